@@ -0,0 +1,407 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, thread};
+
+use crate::digest::Digest;
+use crate::merkle_tree::{self, MerkleProof, ProofGroup};
+use crate::tree_head::TreeHead;
+use crate::utils;
+
+/// Verifies many proofs against one tree head, splitting them across threads.
+///
+/// Each thread remembers the steps of the proofs it has verified up to the
+/// root, and takes the parent of a step it has seen before, with the same node
+/// and siblings, instead of hashing it again. Every step of every proof is
+/// still checked, along with its path against the leaf count, so each result
+/// is the same as verifying the proof against the head alone, as
+/// `utils::verify_file_response` does. Proofs of neighbouring leaves share
+/// most of their path, so keeping them in order saves most of the hashing.
+#[derive(Clone, Debug)]
+pub struct BatchVerifier {
+    pub root: Digest,
+    /// Children per node. List proofs are only accepted for binary trees.
+    pub arity: usize,
+    pub leaf_count: u64,
+    pub threads: usize,
+    /// Key the nodes of keyed trees are hashed with, see `utils::node_key`.
+    /// Proofs against a keyed head never verify without it.
+    pub node_key: Option<Vec<u8>>,
+}
+
+/// Per-proof results of a batch, in input order, with how much hashing the
+/// memoized nodes saved.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchReport {
+    pub results: Vec<bool>,
+    /// Nodes hashed across all proofs.
+    pub hashes: u64,
+    /// Proofs that reused steps verified by an earlier proof.
+    pub memo_hits: u64,
+}
+
+impl BatchReport {
+    pub fn valid_count(&self) -> usize {
+        self.results.iter().filter(|valid| **valid).count()
+    }
+
+    pub fn all_valid(&self) -> bool {
+        self.results.iter().all(|valid| *valid)
+    }
+}
+
+/// One step up a proof path.
+enum Step<'a> {
+    Group(&'a ProofGroup),
+    /// A binary sibling, and whether it is on the left.
+    Sibling(&'a Digest, bool),
+}
+
+/// A step of a proof that reached the root, and the parent it led to.
+enum VerifiedStep {
+    Group(usize, Vec<Digest>),
+    Sibling(Digest, bool),
+}
+
+impl VerifiedStep {
+    fn new(step: &Step) -> Self {
+        match step {
+            Step::Group(group) => VerifiedStep::Group(group.position, group.hashes.clone()),
+            Step::Sibling(sibling, is_left) => VerifiedStep::Sibling(**sibling, *is_left),
+        }
+    }
+
+    fn matches(&self, step: &Step) -> bool {
+        match (self, step) {
+            (VerifiedStep::Group(position, hashes), Step::Group(group)) => {
+                *position == group.position && *hashes == group.hashes
+            }
+            (VerifiedStep::Sibling(sibling, is_left), Step::Sibling(other, other_is_left)) => {
+                sibling == *other && is_left == other_is_left
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Verified steps by the node they start from.
+type Memo = HashMap<Digest, (VerifiedStep, Digest)>;
+
+impl BatchVerifier {
+    /// A verifier for proofs against `tree_head`, using every available core.
+    pub fn new(tree_head: &TreeHead) -> Self {
+        BatchVerifier {
+            root: tree_head.root_hash,
+            arity: tree_head.arity as usize,
+            leaf_count: tree_head.leaf_count,
+            threads: thread::available_parallelism().map_or(1, |threads| threads.get()),
+            node_key: None,
+        }
+    }
+
+    /// Verifies each `(leaf hash, proof)` pair against the root.
+    pub fn verify(&self, items: &[(Digest, MerkleProof)]) -> BatchReport {
+        if items.is_empty() {
+            return BatchReport::default();
+        }
+        let chunk_size = items.len().div_ceil(self.threads.max(1));
+
+        let reports: Vec<BatchReport> = thread::scope(|scope| {
+            let workers: Vec<_> = items
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(|| self.verify_chunk(chunk)))
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("Batch verification thread panicked"))
+                .collect()
+        });
+
+        reports
+            .into_iter()
+            .fold(BatchReport::default(), |mut total, report| {
+                total.results.extend(report.results);
+                total.hashes += report.hashes;
+                total.memo_hits += report.memo_hits;
+                total
+            })
+    }
+
+    fn verify_chunk(&self, items: &[(Digest, MerkleProof)]) -> BatchReport {
+        let mut memo = Memo::new();
+        let mut report = BatchReport::default();
+
+        for (leaf_hash, proof) in items {
+            let leaf_node = Digest::of_leaf(leaf_hash);
+            let valid = match proof_steps(proof, &leaf_node) {
+                Some(steps) if self.fits_tree(proof, &steps) => {
+                    self.verify_path(leaf_node, &steps, &mut memo, &mut report)
+                }
+                _ => false,
+            };
+            report.results.push(valid);
+        }
+        report
+    }
+
+    /// Whether the steps are the path of some leaf of the tree, one group or
+    /// sibling per level the leaf is not promoted through.
+    fn fits_tree(&self, proof: &MerkleProof, steps: &[Step]) -> bool {
+        if matches!(proof, MerkleProof::List(_)) && self.arity != 2 {
+            return false;
+        }
+        let path: Vec<(usize, usize)> = steps
+            .iter()
+            .map(|step| match step {
+                Step::Group(group) => (group.position, group.hashes.len()),
+                Step::Sibling(_, is_left) => (*is_left as usize, 2),
+            })
+            .collect();
+        usize::try_from(self.leaf_count).is_ok_and(|leaf_count| {
+            merkle_tree::path_index(leaf_count, self.arity, &path).is_some()
+        })
+    }
+
+    /// Follows every step up the path, hashing only steps not verified by an
+    /// earlier proof, and remembers the steps if the path reaches the root.
+    fn verify_path(
+        &self,
+        leaf_node: Digest,
+        steps: &[Step],
+        memo: &mut Memo,
+        report: &mut BatchReport,
+    ) -> bool {
+        let mut path = Vec::with_capacity(steps.len());
+        let mut current = leaf_node;
+        let mut reused = false;
+        for step in steps {
+            let parent = match memo.get(&current) {
+                Some((verified, parent)) if verified.matches(step) => {
+                    reused = true;
+                    *parent
+                }
+                _ => {
+                    report.hashes += 1;
+                    match self.parent(step, current) {
+                        Some(parent) => parent,
+                        None => return false,
+                    }
+                }
+            };
+            path.push((current, step, parent));
+            current = parent;
+        }
+
+        if current != self.root {
+            return false;
+        }
+        if reused {
+            report.memo_hits += 1;
+        }
+        for (node, step, parent) in path {
+            memo.entry(node)
+                .or_insert_with(|| (VerifiedStep::new(step), parent));
+        }
+        true
+    }
+
+    fn parent(&self, step: &Step, current: Digest) -> Option<Digest> {
+        let node_key = self.node_key.as_deref();
+        match step {
+            Step::Group(group) => {
+                let size_ok = (2..=self.arity).contains(&group.hashes.len());
+                (size_ok && group.hashes.get(group.position) == Some(&current))
+                    .then(|| Digest::of_keyed_children(node_key, &group.hashes))
+            }
+            Step::Sibling(sibling, true) => {
+                Some(Digest::of_keyed_children(node_key, [*sibling, &current]))
+            }
+            Step::Sibling(sibling, false) => {
+                Some(Digest::of_keyed_children(node_key, [&current, *sibling]))
+            }
+        }
+    }
+}
+
+/// Steps from the leaf up, or `None` if the proof does not start at the leaf.
+fn proof_steps<'a>(proof: &'a MerkleProof, leaf_node: &Digest) -> Option<Vec<Step<'a>>> {
+    match proof {
+        MerkleProof::Group(groups) => Some(groups.iter().map(Step::Group).collect()),
+        MerkleProof::List(items) => {
+            let siblings = utils::list_proof_siblings(items, leaf_node)?;
+            let steps = siblings
+                .into_iter()
+                .map(|(sibling, is_left)| Step::Sibling(sibling, is_left));
+            Some(steps.collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        batch_verify::BatchVerifier,
+        digest::Digest,
+        merkle_tree::{MerkleLeaf, MerkleProof, MerkleTree},
+        utils,
+    };
+
+    fn proofs(merkle_tree: &MerkleTree) -> Vec<(Digest, MerkleProof)> {
+        merkle_tree
+            .iter_leaves()
+            .map(|(index, leaf)| {
+                let proof = if merkle_tree.arity() == 2 {
+                    MerkleProof::List(merkle_tree.generate_merkle_proof_for_index(index).unwrap())
+                } else {
+                    MerkleProof::Group(merkle_tree.generate_group_proof_for_index(index).unwrap())
+                };
+                (leaf.hash, proof)
+            })
+            .collect()
+    }
+
+    fn individually(items: &[(Digest, MerkleProof)], root: Digest, arity: usize) -> Vec<bool> {
+        items
+            .iter()
+            .map(|(leaf_hash, proof)| match proof {
+                MerkleProof::List(list) => {
+                    utils::verify_merkle_proof_for_leaf(list.clone(), root, *leaf_hash)
+                }
+                MerkleProof::Group(groups) => {
+                    utils::verify_group_proof_for_leaf(groups, &root, arity, *leaf_hash)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_match_individual_verification() {
+        for arity in MerkleTree::SUPPORTED_ARITIES {
+            for len in [1, 2, 3, 100] {
+                let items: Vec<String> = (0..len).map(|i| format!("row {}", i)).collect();
+                let leaves = items.iter().map(|i| MerkleLeaf::new(None, i)).collect();
+                let merkle_tree = MerkleTree::from_leaves_with_arity(leaves, arity);
+                let root = merkle_tree.get_root_hash();
+
+                let mut items = proofs(&merkle_tree);
+                let (_, proof) = items[len / 2].clone();
+                items.push((Digest::of(b"forged row"), proof));
+
+                let verifier = BatchVerifier {
+                    threads: 3,
+                    ..BatchVerifier::new(&merkle_tree.tree_head())
+                };
+                let report = verifier.verify(&items);
+
+                assert_eq!(report.results, individually(&items, root, arity));
+                assert_eq!(report.valid_count(), len);
+            }
+        }
+    }
+
+    #[test]
+    fn should_reject_tampered_upper_siblings_with_any_thread_count() {
+        for arity in [2, 4] {
+            let items: Vec<String> = (0..16).map(|i| format!("row {}", i)).collect();
+            let leaves = items.iter().map(|i| MerkleLeaf::new(None, i)).collect();
+            let merkle_tree = MerkleTree::from_leaves_with_arity(leaves, arity);
+            let root = merkle_tree.get_root_hash();
+
+            let mut items = proofs(&merkle_tree);
+            match &mut items[5].1 {
+                MerkleProof::List(list) => list[0].hash = Digest::of(b"forged sibling"),
+                MerkleProof::Group(groups) => {
+                    let top = groups.last_mut().unwrap();
+                    let sibling = (top.position + 1) % top.hashes.len();
+                    top.hashes[sibling] = Digest::of(b"forged sibling");
+                }
+            }
+
+            let expected = individually(&items, root, arity);
+            assert_eq!(expected.iter().filter(|valid| !**valid).count(), 1);
+            for threads in 1..=items.len() {
+                let verifier = BatchVerifier {
+                    threads,
+                    ..BatchVerifier::new(&merkle_tree.tree_head())
+                };
+                assert_eq!(verifier.verify(&items).results, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn should_hash_shared_nodes_once_per_thread() {
+        let items: Vec<String> = (0..1024).map(|i| format!("row {}", i)).collect();
+        let merkle_tree = MerkleTree::from_items(&items);
+        let batch = proofs(&merkle_tree);
+
+        let verifier = BatchVerifier {
+            threads: 1,
+            ..BatchVerifier::new(&merkle_tree.tree_head())
+        };
+        let report = verifier.verify(&batch);
+
+        assert!(report.all_valid());
+        assert_eq!(report.hashes, 2046);
+        assert_eq!(report.memo_hits, 1022);
+    }
+
+    #[test]
+    fn should_reject_proofs_against_another_root() {
+        let merkle_tree = MerkleTree::from_items(["a", "b", "c"]);
+        let other = MerkleTree::from_items(["a", "b", "d"]);
+
+        let report = BatchVerifier::new(&other.tree_head()).verify(&proofs(&merkle_tree));
+
+        assert_eq!(report.results, [false, false, false]);
+        assert!(BatchVerifier::new(&other.tree_head())
+            .verify(&[])
+            .results
+            .is_empty());
+    }
+
+    #[test]
+    fn should_reject_proofs_not_fitting_the_leaf_count() {
+        for arity in [2, 4] {
+            let items: Vec<String> = (0..8).map(|i| format!("row {}", i)).collect();
+            let leaves = items.iter().map(|i| MerkleLeaf::new(None, i)).collect();
+            let merkle_tree = MerkleTree::from_leaves_with_arity(leaves, arity);
+            let batch = proofs(&merkle_tree);
+
+            for leaf_count in [1, 4, 100] {
+                let mut tree_head = merkle_tree.tree_head();
+                tree_head.leaf_count = leaf_count;
+                let report = BatchVerifier::new(&tree_head).verify(&batch);
+                assert_eq!(report.valid_count(), 0);
+            }
+        }
+
+        // A one-leaf proof is the bare leaf, which only fits a one-leaf tree
+        let single = MerkleTree::from_items(["only row"]);
+        let batch = proofs(&single);
+        assert!(BatchVerifier::new(&single.tree_head())
+            .verify(&batch)
+            .all_valid());
+        let mut tree_head = single.tree_head();
+        tree_head.leaf_count = 2;
+        assert!(!BatchVerifier::new(&tree_head).verify(&batch).all_valid());
+    }
+
+    #[test]
+    fn should_verify_keyed_trees_only_with_the_node_key() {
+        for arity in [2, 4] {
+            let items: Vec<String> = (0..10).map(|i| format!("row {}", i)).collect();
+            let leaves = items.iter().map(|i| MerkleLeaf::new(None, i)).collect();
+            let node_key = utils::node_key(b"secret");
+            let merkle_tree = MerkleTree::from_leaves_with_node_key(leaves, arity, Some(&node_key));
+            let batch = proofs(&merkle_tree);
+
+            let unkeyed = BatchVerifier::new(&merkle_tree.tree_head());
+            assert_eq!(unkeyed.verify(&batch).valid_count(), 0);
+
+            let keyed = BatchVerifier {
+                node_key: Some(node_key),
+                ..BatchVerifier::new(&merkle_tree.tree_head())
+            };
+            assert!(keyed.verify(&batch).all_valid());
+        }
+    }
+}
@@ -0,0 +1,280 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::digest::Digest;
+use crate::file_metadata::FileMetadata;
+use crate::merkle_tree::{MerkleLeaf, MerkleTree};
+
+pub const MIN_CHUNK_SIZE: usize = 2 * 1024;
+pub const AVG_CHUNK_SIZE: usize = 8 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+// Normalized chunking masks from the FastCDC paper for 8KiB chunks: a harder
+// mask (15 bits) below the average size and an easier one (11 bits) above it.
+const MASK_S: u64 = 0x0000_d9f0_0353_0000;
+const MASK_L: u64 = 0x0000_d900_0353_0000;
+
+/// Random values for the gear rolling hash, generated with splitmix64 so that
+/// every build splits content at the same points.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6d65_726b_6c65_2d72;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Length of the first chunk of `data`.
+fn cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+
+    let end = data.len().min(MAX_CHUNK_SIZE);
+    let normal = end.min(AVG_CHUNK_SIZE);
+    let mut hash: u64 = 0;
+
+    for (i, byte) in data.iter().enumerate().take(end).skip(MIN_CHUNK_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        let mask = if i < normal { MASK_S } else { MASK_L };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+
+    end
+}
+
+/// Splits `data` at content-defined boundaries, so that an insertion only
+/// changes the chunks around it. Empty data has no chunks.
+pub fn split_chunks(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let (chunk, tail) = rest.split_at(cut_point(rest));
+        chunks.push(chunk);
+        rest = tail;
+    }
+    chunks
+}
+
+/// Root of the binary tree over a file's chunk hashes. This is the file's leaf
+/// hash, and is a node hash (see `Digest::of_leaf`) for files of one chunk.
+pub fn chunk_root(chunk_hashes: &[Digest]) -> Digest {
+    // An empty file has no chunks, but hashes like one with empty contents
+    if chunk_hashes.is_empty() {
+        return Digest::of(b"");
+    }
+    let leaves = chunk_hashes
+        .iter()
+        .map(|hash| MerkleLeaf::from_hash(None, *hash))
+        .collect();
+    MerkleTree::from_leaves(leaves).get_root_hash()
+}
+
+pub fn chunk_hashes(data: &[u8]) -> Vec<Digest> {
+    split_chunks(data).into_iter().map(Digest::of).collect()
+}
+
+/// The chunks a stored file is made of, in order.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    pub size: u64,
+    pub chunks: Vec<Digest>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FileMetadata>,
+}
+
+impl ChunkManifest {
+    pub fn chunk_root(&self) -> Digest {
+        chunk_root(&self.chunks)
+    }
+}
+
+/// Content addressed chunk storage with one manifest per file, kept under
+/// `.chunks` and `.manifests` in a directory.
+#[derive(Clone, Debug)]
+pub struct ChunkStore {
+    directory: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        ChunkStore {
+            directory: directory.as_ref().to_path_buf(),
+        }
+    }
+
+    fn chunk_path(&self, hash: &Digest) -> PathBuf {
+        self.directory.join(".chunks").join(hash.to_hex())
+    }
+
+    fn manifests_dir(&self) -> PathBuf {
+        self.directory.join(".manifests")
+    }
+
+    pub fn contains_chunk(&self, hash: &Digest) -> bool {
+        self.chunk_path(hash).is_file()
+    }
+
+    /// Stores a chunk under its hash and returns the hash.
+    pub fn put_chunk(&self, content: &[u8]) -> io::Result<Digest> {
+        let hash = Digest::of(content);
+        let path = self.chunk_path(&hash);
+        if !path.exists() {
+            fs::create_dir_all(self.directory.join(".chunks"))?;
+            fs::write(path, content)?;
+        }
+        Ok(hash)
+    }
+
+    pub fn get_chunk(&self, hash: &Digest) -> io::Result<Vec<u8>> {
+        fs::read(self.chunk_path(hash))
+    }
+
+    /// Stores a manifest, failing if any of its chunks is missing.
+    pub fn put_manifest(&self, filename: &str, manifest: &ChunkManifest) -> io::Result<()> {
+        if let Some(missing) = manifest.chunks.iter().find(|c| !self.contains_chunk(c)) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Missing chunk {}", missing),
+            ));
+        }
+        fs::create_dir_all(self.manifests_dir())?;
+        let json = serde_json::to_vec(manifest).map_err(|e| invalid_data(e.to_string()))?;
+        fs::write(self.manifests_dir().join(filename), json)
+    }
+
+    pub fn get_manifest(&self, filename: &str) -> io::Result<ChunkManifest> {
+        let json = fs::read(self.manifests_dir().join(filename))?;
+        serde_json::from_slice(&json).map_err(|e| invalid_data(e.to_string()))
+    }
+
+    /// All manifests, ordered by file name like `utils::parse_files`.
+    pub fn manifests(&self) -> io::Result<BTreeMap<String, ChunkManifest>> {
+        let mut manifests = BTreeMap::new();
+        if !self.manifests_dir().exists() {
+            return Ok(manifests);
+        }
+        for entry in fs::read_dir(self.manifests_dir())? {
+            let entry = entry?;
+            if let Some(filename) = entry.file_name().to_str() {
+                manifests.insert(filename.to_owned(), self.get_manifest(filename)?);
+            }
+        }
+        Ok(manifests)
+    }
+
+    /// Reassembles a file from its chunks.
+    pub fn read_file(&self, filename: &str) -> io::Result<Vec<u8>> {
+        let manifest = self.get_manifest(filename)?;
+        let mut content = Vec::with_capacity(manifest.size as usize);
+        for chunk in &manifest.chunks {
+            content.extend_from_slice(&self.get_chunk(chunk)?);
+        }
+        Ok(content)
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::chunking::{
+        chunk_hashes, chunk_root, split_chunks, ChunkManifest, ChunkStore, MAX_CHUNK_SIZE,
+        MIN_CHUNK_SIZE,
+    };
+    use crate::digest::Digest;
+
+    fn pseudo_random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_split_within_chunk_size_bounds() {
+        let data = pseudo_random_bytes(500_000, 42);
+
+        let chunks = split_chunks(&data);
+
+        assert_eq!(chunks.concat(), data);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() > MIN_CHUNK_SIZE && chunk.len() <= MAX_CHUNK_SIZE);
+        }
+    }
+
+    #[test]
+    fn should_keep_chunks_after_an_insertion() {
+        let data = pseudo_random_bytes(300_000, 7);
+        let mut edited = data[..1000].to_vec();
+        edited.extend_from_slice(b"inserted bytes");
+        edited.extend_from_slice(&data[1000..]);
+
+        let original = chunk_hashes(&data);
+        let changed = chunk_hashes(&edited);
+
+        let shared = changed.iter().filter(|c| original.contains(c)).count();
+        assert!(shared >= original.len() - 2);
+    }
+
+    #[test]
+    fn should_use_leaf_node_as_root_of_small_files() {
+        assert_eq!(
+            chunk_root(&chunk_hashes(b"small file")),
+            Digest::of_leaf(&Digest::of(b"small file"))
+        );
+        assert_eq!(chunk_root(&chunk_hashes(b"")), Digest::of(b""));
+    }
+
+    #[test]
+    fn should_store_shared_chunks_once() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = ChunkStore::new(temp_dir.path());
+        let data = pseudo_random_bytes(200_000, 3);
+
+        for filename in ["a.bin", "b.bin"] {
+            let chunks = split_chunks(&data)
+                .into_iter()
+                .map(|chunk| store.put_chunk(chunk).unwrap())
+                .collect();
+            let manifest = ChunkManifest {
+                size: data.len() as u64,
+                chunks,
+                metadata: None,
+            };
+            store.put_manifest(filename, &manifest).unwrap();
+        }
+
+        let stored = std::fs::read_dir(temp_dir.path().join(".chunks"))
+            .unwrap()
+            .count();
+        assert_eq!(stored, split_chunks(&data).len());
+        assert_eq!(store.read_file("b.bin").unwrap(), data);
+        assert_eq!(store.manifests().unwrap().len(), 2);
+        assert_eq!(
+            store.get_manifest("a.bin").unwrap().chunk_root(),
+            chunk_root(&chunk_hashes(&data))
+        );
+    }
+}
@@ -1,200 +1,30 @@
-use crate::{
-    client_args::{Args, Commands},
-    merkle_tree::MerkleTree,
-};
-use base64::{self, engine::general_purpose, Engine};
-use hyper::StatusCode;
+use std::io;
 
-use std::{
-    fs,
-    io::{self, ErrorKind},
+use merkle_rs::{
+    forest::Forest, merkle_client::MerkleClient, signature::PublicKey, tree_head::TreeHead, utils,
 };
 
-mod client_args;
-mod common;
-mod merkle_tree;
-mod utils;
-
-use common::{FileResponse, UploadRequest};
-
-pub struct MerkleClient {
-    pub merkle_root: Option<Vec<u8>>,
-    server_url: String,
-    reqwest_client: reqwest::Client,
-    client_files: Option<String>,
-    merkle_root_path: String,
-}
-
-static NO_DIR_MSG: &str = "Client has no set directory path";
-
-impl MerkleClient {
-    pub fn new(
-        server_url: &str,
-        reqwest_client: reqwest::Client,
-        client_files: Option<String>,
-        merkle_root_path: String,
-    ) -> Self {
-        MerkleClient {
-            merkle_root: None,
-            server_url: server_url.to_owned(),
-            reqwest_client,
-            client_files,
-            merkle_root_path,
-        }
-    }
-    pub async fn request_file(
-        &self,
-        filename: &str,
-    ) -> Result<FileResponse, Box<dyn std::error::Error>> {
-        let url = format!("{}/file/{}", &self.server_url, filename);
-
-        let response = self.reqwest_client.get(&url).send().await?;
-
-        match response.status() {
-            StatusCode::OK => {
-                let response_body = response.text().await?;
-                let file_response: FileResponse = serde_json::from_str(&response_body)?;
-                Ok(file_response)
-            }
-            _ => Err(Box::new(io::Error::new(
-                io::ErrorKind::Other,
-                "Failed to retrieve file from server",
-            ))),
-        }
-    }
-
-    pub async fn upload_all_files_to_server(&self) -> Result<(), Box<dyn std::error::Error>> {
-        match &self.client_files {
-            Some(client_files) => {
-                let base_url = format!("{}/upload", self.server_url);
-
-                let entries: Vec<_> = fs::read_dir(client_files)?.collect();
-                if entries.is_empty() {
-                    eprintln!("The directory is empty!");
-                    return Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "The directory is empty",
-                    )));
-                } else if entries.len() < 2 {
-                    eprintln!("Not enough files to upload, must be > 2");
-                    return Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Not enough files to upload, must be > 2",
-                    )));
-                }
-
-                let entries = fs::read_dir(client_files)?;
+use crate::client_args::{Args, Commands};
 
-                for entry in entries {
-                    let entry = entry?;
-                    let path = entry.path();
-                    if path.is_file() {
-                        MerkleClient::upload_file(&self.reqwest_client, &path, &base_url).await?;
-                    }
-                }
-
-                Ok(())
-            }
-            None => {
-                eprintln!("{}", NO_DIR_MSG);
-                Err(Box::new(io::Error::new(io::ErrorKind::Other, NO_DIR_MSG)))
-            }
-        }
-    }
-
-    pub fn read_merkle_root_from_disk(&self) -> io::Result<Vec<u8>> {
-        fs::read(self.merkle_root_path.clone())
-    }
-
-    pub fn write_merkle_root_to_disk(&self) -> io::Result<()> {
-        match self.merkle_root.clone() {
-            Some(merkle_root) => fs::write(self.merkle_root_path.clone(), merkle_root),
-            None => {
-                eprintln!("Client has no merkle root to store");
-                Err(io::Error::new(
-                    ErrorKind::Other,
-                    "Client has no merkle root to store",
-                ))
-            }
-        }
-    }
-
-    pub fn delete_local_client_files(&self) -> io::Result<()> {
-        match &self.client_files {
-            Some(client_files) => {
-                let entries = fs::read_dir(client_files)?;
-
-                for entry in entries {
-                    let entry = entry?;
-                    let path = entry.path();
-                    if path.is_file() {
-                        fs::remove_file(path)?;
-                    }
-                }
+mod client_args;
 
-                Ok(())
-            }
-            None => {
-                eprintln!("{}", NO_DIR_MSG);
-                Err(io::Error::new(ErrorKind::Other, NO_DIR_MSG))
-            }
-        }
+fn read_leaf_key(leaf_key_file: Option<&str>) -> Option<Vec<u8>> {
+    let key =
+        std::fs::read(leaf_key_file?).unwrap_or_else(|e| panic!("Failed to read leaf key {}", e));
+    if key.is_empty() {
+        panic!("Leaf key file is empty");
     }
+    Some(key)
+}
 
-    pub fn compute_merkle_root_from_files(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        match &self.client_files {
-            Some(client_files) => {
-                let files = utils::parse_files(client_files);
-
-                if files.len() < 2 {
-                    eprintln!("Not enough files to upload, must be > 2");
-                    Err(Box::new(std::io::Error::new(
-                        std::io::ErrorKind::Other,
-                        "Not enough files to upload, must be > 2",
-                    )))
-                } else {
-                    let merkle_tree = MerkleTree::new(&files);
-                    self.merkle_root = Some(merkle_tree.root.hash);
-                    Ok(())
-                }
-            }
-            None => {
-                eprintln!("{}", NO_DIR_MSG);
-                Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    NO_DIR_MSG,
-                )))
-            }
-        }
-    }
-
-    async fn upload_file(
-        client: &reqwest::Client,
-        path: &std::path::Path,
-        base_url: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let content = fs::read(path)?;
-        let base64_content = general_purpose::STANDARD.encode(&content);
-
-        let payload = UploadRequest {
-            filename: path.file_name().unwrap().to_string_lossy().into_owned(),
-            content: base64_content,
-        };
-
-        let response = client.post(base_url).json(&payload).send().await?;
-
-        if response.status().is_success() {
-            println!("Successfully uploaded: {:?}", path);
-        } else {
-            eprintln!(
-                "Failed to upload: {:?}. Status: {}",
-                path,
-                response.status()
-            );
-        }
-
-        Ok(())
-    }
+/// Reads the stored tree head, failing with the reason when proofs can't be
+/// verified against it.
+fn read_valid_tree_head(merkle_client: &MerkleClient) -> io::Result<TreeHead> {
+    let tree_head = merkle_client.read_merkle_root_from_disk()?;
+    tree_head
+        .validate()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(tree_head)
 }
 
 #[tokio::main]
@@ -214,6 +44,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Some(args.files_path),
                 args.merkle_path,
             );
+            merkle_client.arity = args.arity;
+            merkle_client.leaf_metadata = args.leaf_metadata;
+            merkle_client.chunking = args.chunking;
+            merkle_client.leaf_key = read_leaf_key(args.leaf_key_file.as_deref());
+            merkle_client.bucket = args.bucket.clone();
+            if args.salted_leaves {
+                if let Err(e) = merkle_client.generate_leaf_salts() {
+                    panic!("Failed to generate leaf salts {}", e);
+                }
+            }
 
             match merkle_client.upload_all_files_to_server().await {
                 Ok(_) => {
@@ -231,80 +71,231 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             if let Err(e) = merkle_client.write_merkle_root_to_disk() {
                 panic!("Failed to write merkle root to disk {}", e);
             }
+            if let Err(e) = merkle_client.write_leaf_salts_to_disk() {
+                panic!("Failed to write leaf salts to disk {}", e);
+            }
+            if let Some(tree_head) = &merkle_client.merkle_root {
+                println!("Stored merkle {}", tree_head);
+            }
 
             if let Err(e) = merkle_client.delete_local_client_files() {
                 panic!("Failed to delete client files {}", e);
             }
         }
 
-        Some(Commands::Request { file_name }) => {
-            let merkle_client = MerkleClient::new(
+        Some(Commands::NodeKey { path }) => match read_leaf_key(args.leaf_key_file.as_deref()) {
+            Some(leaf_key) => match std::fs::write(path, utils::node_key(&leaf_key)) {
+                Ok(_) => println!("Wrote node key to {}", path),
+                Err(e) => eprintln!("Failed to write node key {}", e),
+            },
+            None => eprintln!("A --leaf-key-file is needed to derive the node key"),
+        },
+
+        Some(Commands::Import { manifest }) => {
+            let mut merkle_client = MerkleClient::new(
+                &args.server_address,
+                reqwest::Client::new(),
+                None,
+                args.merkle_path,
+            );
+            merkle_client.arity = args.arity;
+            merkle_client.leaf_metadata = args.leaf_metadata;
+            merkle_client.chunking = args.chunking;
+            merkle_client.leaf_key = read_leaf_key(args.leaf_key_file.as_deref());
+
+            if let Err(e) = merkle_client.import_checksum_manifest(manifest) {
+                panic!("Failed to import checksum manifest {}", e);
+            }
+
+            if let Err(e) = merkle_client.write_merkle_root_to_disk() {
+                panic!("Failed to write merkle root to disk {}", e);
+            }
+            if let Some(tree_head) = &merkle_client.merkle_root {
+                println!("Stored merkle {}", tree_head);
+            }
+        }
+
+        Some(Commands::Forest { collections }) => {
+            match Forest::read_tree_heads(collections.iter().cloned()) {
+                Ok(forest) => {
+                    for (name, tree_head) in forest.collections() {
+                        println!("{}: {}", name, tree_head);
+                    }
+                    println!(
+                        "Super-root {} over {} collections",
+                        forest.super_root(),
+                        forest.collections().len()
+                    );
+                }
+                Err(e) => eprintln!("Failed to read collection roots {}", e),
+            }
+        }
+
+        Some(Commands::CheckHead { public_key }) => {
+            let public_key: PublicKey = std::fs::read(public_key)
+                .map_err(Box::<dyn std::error::Error>::from)
+                .and_then(|json| Ok(serde_json::from_slice(&json)?))
+                .unwrap_or_else(|e| panic!("Failed to read public key {}", e));
+            let mut merkle_client = MerkleClient::new(
                 &args.server_address,
                 reqwest::Client::new(),
                 None,
                 args.merkle_path,
             );
+            merkle_client.bucket = args.bucket.clone();
 
-            match merkle_client.request_file(file_name).await {
-                Ok(server_response) => match merkle_client.read_merkle_root_from_disk() {
-                    Ok(client_merkle_root) => {
-                        if utils::verify_merkle_proof(
-                            server_response.merkle_proof,
-                            client_merkle_root,
-                            server_response.content,
-                        ) {
-                            println!("Server proof is valid!");
-                        } else {
-                            eprintln!("Server proof is invalid!");
+            match merkle_client.request_signed_tree_head().await {
+                Ok(signed) if signed.bucket != merkle_client.bucket => {
+                    eprintln!("Server signed the tree head of another store!")
+                }
+                Ok(signed) if signed.verify(&public_key) => {
+                    println!("Server signed {}", signed.tree_head);
+                    match merkle_client.read_merkle_root_from_disk() {
+                        Ok(tree_head) if tree_head.root_hash == signed.tree_head.root_hash => {
+                            println!("Signed root matches the stored merkle root!");
+                        }
+                        Ok(tree_head) => {
+                            eprintln!(
+                                "Signed root differs from the stored merkle root {}",
+                                tree_head.root_hash
+                            );
                         }
+                        Err(e) => eprintln!("{}", e),
                     }
-                    Err(e) => {
-                        eprintln!("{}", e);
+                }
+                Ok(_) => eprintln!("Server tree head signature is invalid!"),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+
+        Some(Commands::List { page_size }) => {
+            let mut merkle_client = MerkleClient::new(
+                &args.server_address,
+                reqwest::Client::new(),
+                None,
+                args.merkle_path,
+            );
+            merkle_client.bucket = args.bucket.clone();
+
+            match read_valid_tree_head(&merkle_client) {
+                Ok(tree_head) => match merkle_client.list_files(&tree_head, *page_size).await {
+                    Ok(entries) => {
+                        for entry in &entries {
+                            println!("{} {:>12} {}", entry.leaf_hash, entry.size, entry.filename);
+                        }
+                        println!(
+                            "Listed all {} files of root {}!",
+                            entries.len(),
+                            tree_head.root_hash
+                        );
                     }
+                    Err(e) => eprintln!("Failed to list files {}", e),
                 },
-                Err(e) => {
-                    eprint!("{}", e);
-                }
+                Err(e) => eprintln!("{}", e),
             }
         }
-    }
 
-    Ok(())
-}
+        Some(Commands::CreateBucket { name }) => {
+            let merkle_client = MerkleClient::new(
+                &args.server_address,
+                reqwest::Client::new(),
+                None,
+                args.merkle_path,
+            );
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+            match merkle_client.create_bucket(name).await {
+                Ok(bucket) => println!("Created bucket {}", bucket.name),
+                Err(e) => eprintln!("Failed to create bucket {}", e),
+            }
+        }
 
-    #[test]
-    fn test_initialization() {
-        let client = MerkleClient::new(
-            "http://localhost:8000",
-            reqwest::Client::new(),
-            Some("path/to/files".to_owned()),
-            "path/to/merkle_root".to_owned(),
-        );
-        assert_eq!(client.server_url, "http://localhost:8000");
-        assert_eq!(client.client_files, Some("path/to/files".to_owned()));
-        assert_eq!(client.merkle_root_path, "path/to/merkle_root".to_owned());
-    }
+        Some(Commands::Buckets {}) => {
+            let merkle_client = MerkleClient::new(
+                &args.server_address,
+                reqwest::Client::new(),
+                None,
+                args.merkle_path,
+            );
 
-    #[test]
-    fn test_read_write_merkle_root() {
-        let temp_dir = tempfile::tempdir().unwrap();
-        let merkle_path = temp_dir.path().join("merkle_root");
-        let mut client = MerkleClient::new(
-            "http://localhost:8000",
-            reqwest::Client::new(),
-            Some("path/to/files".to_owned()),
-            merkle_path.to_str().unwrap().to_string(),
-        );
+            match merkle_client.list_buckets().await {
+                Ok(buckets) => {
+                    for bucket in &buckets {
+                        println!("{} {:>8} {}", bucket.root_hash, bucket.files, bucket.name);
+                    }
+                    println!("Listed {} buckets", buckets.len());
+                }
+                Err(e) => eprintln!("Failed to list buckets {}", e),
+            }
+        }
 
-        client.merkle_root = Some(vec![1, 2, 3, 4]);
-        let write_result = client.write_merkle_root_to_disk();
-        assert!(write_result.is_ok());
+        Some(Commands::Request {
+            file_name,
+            restore,
+            stream,
+        }) => {
+            let leaf_key = read_leaf_key(args.leaf_key_file.as_deref());
+            let mut merkle_client = MerkleClient::new(
+                &args.server_address,
+                reqwest::Client::new(),
+                Some(args.files_path),
+                args.merkle_path,
+            );
+            merkle_client.bucket = args.bucket.clone();
+
+            if *stream {
+                match read_valid_tree_head(&merkle_client) {
+                    Ok(client_merkle_root) => match merkle_client
+                        .stream_file(file_name, &client_merkle_root, leaf_key.as_deref())
+                        .await
+                    {
+                        Ok(size) => println!(
+                            "Streamed {} ({} bytes), valid for root {}!",
+                            file_name, size, client_merkle_root.root_hash
+                        ),
+                        Err(e) => eprintln!("Failed to stream file {}", e),
+                    },
+                    Err(e) => eprintln!("{}", e),
+                }
+                return Ok(());
+            }
 
-        let read_result = client.read_merkle_root_from_disk().unwrap();
-        assert_eq!(read_result, vec![1, 2, 3, 4]);
+            let client_merkle_root = match read_valid_tree_head(&merkle_client) {
+                Ok(client_merkle_root) => client_merkle_root,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return Ok(());
+                }
+            };
+            match merkle_client
+                .request_file(file_name, Some(&client_merkle_root.root_hash))
+                .await
+            {
+                Ok(server_response) => {
+                    if utils::verify_file_response_with_key(
+                        &server_response,
+                        &client_merkle_root,
+                        leaf_key.as_deref(),
+                    ) {
+                        println!(
+                            "Server proof is valid for root {}!",
+                            client_merkle_root.root_hash
+                        );
+                        if *restore {
+                            match merkle_client.restore_file(file_name, &server_response) {
+                                Ok(_) => println!("Restored {}", server_response.filename),
+                                Err(e) => eprintln!("Failed to restore file {}", e),
+                            }
+                        }
+                    } else {
+                        eprintln!("Server proof is invalid!");
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                }
+            }
+        }
     }
+
+    Ok(())
 }
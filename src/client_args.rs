@@ -1,4 +1,8 @@
 use clap::{Parser, Subcommand};
+use merkle_rs::{
+    file_metadata::MetadataFields,
+    utils::{parse_arity, parse_metadata_fields},
+};
 
 /// A Merkle Tree implementation for proving file integrity.
 #[derive(Parser)]
@@ -16,6 +20,30 @@ pub struct Args {
     #[arg(short, long, default_value_t = String::from("http://127.0.0.1:3000"))]
     pub server_address: String,
 
+    /// Number of children per tree node (2, 4, 8 or 16), must match the server
+    #[arg(long, default_value_t = 2, value_parser = parse_arity)]
+    pub arity: usize,
+
+    /// Comma separated metadata fields (size, mode, mtime) committed to in each leaf, must match the server
+    #[arg(long, default_value = "", value_parser = parse_metadata_fields)]
+    pub leaf_metadata: MetadataFields,
+
+    /// Upload files as content-defined chunks, sending chunks shared between files once
+    #[arg(long)]
+    pub chunking: bool,
+
+    /// File holding the secret that leaves are keyed with, needed again to verify requests
+    #[arg(long)]
+    pub leaf_key_file: Option<String>,
+
+    /// Commit a random salt per file in its leaf, so proofs do not expose other files to guessing
+    #[arg(long)]
+    pub salted_leaves: bool,
+
+    /// Bucket on the server to upload to, list, request from and check the signed head of, instead of the default store
+    #[arg(long)]
+    pub bucket: Option<String>,
+
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
@@ -25,8 +53,64 @@ pub enum Commands {
     /// Uploads all files to the server
     Upload {},
 
+    /// Writes the key a server building keyed trees hashes nodes with, derived
+    /// from the leaf key, for its --node-key-file
+    NodeKey { path: String },
+
+    /// Computes the merkle root from a sha256sum or BSD style checksum manifest
+    /// without reading the files it lists
+    Import { manifest: String },
+
+    /// Computes the super-root over the stored merkle roots of several
+    /// collections, given as NAME=PATH pairs
+    Forest {
+        #[arg(required = true, value_parser = parse_collection)]
+        collections: Vec<(String, String)>,
+    },
+
+    /// Fetches the server's signed tree head, checks its signature and compares
+    /// it with the stored merkle root
+    CheckHead {
+        /// Public key JSON the server wrote next to its signing key
+        #[arg(long)]
+        public_key: String,
+    },
+
+    /// Lists the files the server holds, verifying each page against the stored merkle root
+    List {
+        /// Files requested per page, at most 1000
+        #[arg(long, default_value_t = 100)]
+        page_size: u64,
+    },
+
+    /// Creates a bucket on the server, holding its own tree of files
+    CreateBucket { name: String },
+
+    /// Lists the server's buckets with the current root of each
+    Buckets {},
+
     /// Request a file by name
-    Request { file_name: String },
+    Request {
+        file_name: String,
+
+        /// Write the verified file and its committed metadata into the files path
+        #[arg(long)]
+        restore: bool,
+
+        /// Stream a chunked file into the files path, verifying each chunk as it arrives
+        #[arg(long, conflicts_with = "restore")]
+        stream: bool,
+    },
+}
+
+/// Parses a `NAME=PATH` collection argument.
+fn parse_collection(collection: &str) -> Result<(String, String), String> {
+    match collection.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+            Ok((name.to_owned(), path.to_owned()))
+        }
+        _ => Err(format!("Expected NAME=PATH, got {}", collection)),
+    }
 }
 
 impl Args {
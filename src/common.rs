@@ -1,12 +1,32 @@
-use merkle_tree::ProofListItem;
 use serde::{Deserialize, Serialize};
 
-use crate::merkle_tree;
+use crate::chunking::ChunkManifest;
+use crate::digest::Digest;
+use crate::file_metadata::FileMetadata;
+use crate::merkle_tree::{ProofGroup, ProofListItem, RangeProof};
+use crate::signature::{PublicKey, Signature};
+use crate::tree_head::TreeHead;
+use crate::utils::LeafSalt;
 
 #[derive(Serialize, Deserialize)]
 pub struct UploadRequest {
     pub filename: String,
     pub content: String,
+    /// Metadata the server keeps beside the stored file, so that leaves
+    /// committing to it match the client's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FileMetadata>,
+    /// Leaf hash keyed by the client, which servers building keyed trees
+    /// cannot compute themselves.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leaf_hash: Option<Digest>,
+    /// Tag over the file name and keyed leaf hash, see `utils::keyed_leaf_tag`,
+    /// which the server checks with its node key before storing the leaf.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leaf_tag: Option<Digest>,
+    /// Salt the server mixes into the file's leaf and returns with its proofs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<LeafSalt>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -19,6 +39,15 @@ pub struct FileResponse {
     pub filename: String,
     pub content: Vec<u8>,
     pub merkle_proof: Vec<ProofListItem>,
+    /// Proof for trees with an arity other than 2, which have no list proof.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_proof: Option<Vec<ProofGroup>>,
+    /// Metadata committed to in the file's leaf, if the tree commits to any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<FileMetadata>,
+    /// Salt committed in the file's leaf. Only the proven file's salt is disclosed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<LeafSalt>,
 }
 
 impl FileResponse {
@@ -27,6 +56,150 @@ impl FileResponse {
             filename,
             content,
             merkle_proof,
+            group_proof: None,
+            metadata: None,
+            salt: None,
         }
     }
+
+    pub fn with_group_proof(
+        filename: String,
+        content: Vec<u8>,
+        group_proof: Vec<ProofGroup>,
+    ) -> Self {
+        FileResponse {
+            filename,
+            content,
+            merkle_proof: Vec::new(),
+            group_proof: Some(group_proof),
+            metadata: None,
+            salt: None,
+        }
+    }
+}
+
+/// Hashes of the chunks a client is about to upload.
+#[derive(Serialize, Deserialize)]
+pub struct MissingChunksRequest {
+    pub chunks: Vec<Digest>,
+}
+
+/// The requested chunks the server does not store yet.
+#[derive(Serialize, Deserialize)]
+pub struct MissingChunksResponse {
+    pub missing: Vec<Digest>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChunkUploadRequest {
+    /// Base64 encoded chunk contents, stored under their hash.
+    pub content: String,
+}
+
+/// Describes a file as a list of chunks the server already stores.
+#[derive(Serialize, Deserialize)]
+pub struct ManifestUploadRequest {
+    pub filename: String,
+    pub manifest: ChunkManifest,
+    /// See `UploadRequest::leaf_hash`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leaf_hash: Option<Digest>,
+    /// See `UploadRequest::leaf_tag`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leaf_tag: Option<Digest>,
+    /// See `UploadRequest::salt`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub salt: Option<LeafSalt>,
+}
+
+/// Prefix of the messages signed for bucket tree heads.
+const BUCKET_HEAD_DOMAIN: &[u8] = b"merkle-rs:bucket-tree-head:v1";
+
+/// A store's current tree head, signed with the server's Merkle signature key.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignedTreeHead {
+    pub tree_head: TreeHead,
+    /// Bucket the head is of, none for the default store.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    pub signature: Signature,
+}
+
+impl SignedTreeHead {
+    /// Bytes signed for a head of `bucket`, or of the default store: the
+    /// serialized tree head, preceded for buckets by the bucket name so that
+    /// the head of one store cannot pass for another's.
+    pub fn message(tree_head: &TreeHead, bucket: Option<&str>) -> Vec<u8> {
+        let bucket = match bucket {
+            Some(bucket) => bucket,
+            None => return tree_head.to_bytes(),
+        };
+        let mut message = BUCKET_HEAD_DOMAIN.to_vec();
+        message.extend_from_slice(&(bucket.len() as u64).to_be_bytes());
+        message.extend_from_slice(bucket.as_bytes());
+        message.extend_from_slice(&tree_head.to_bytes());
+        message
+    }
+
+    /// Checks the signature over the serialized tree head and bucket name.
+    pub fn verify(&self, public_key: &PublicKey) -> bool {
+        let message = SignedTreeHead::message(&self.tree_head, self.bucket.as_deref());
+        public_key.verify(&message, &self.signature)
+    }
+}
+
+/// Query of `GET /files`: up to `limit` files from the `offset`-th, in leaf order.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListingQuery {
+    #[serde(default)]
+    pub offset: u64,
+    pub limit: u64,
+}
+
+/// Query of `GET /file/:filename` and `GET /stream/:filename`: a root the
+/// server kept to prove the file against, instead of its current root.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ProofQuery {
+    pub root: Option<Digest>,
+}
+
+/// A stored file as listed by the server.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ListingEntry {
+    pub filename: String,
+    /// Length of the stored contents.
+    pub size: u64,
+    pub leaf_hash: Digest,
+}
+
+/// A page of the files the server holds, with a range proof over their
+/// entries in the listing tree, see `utils::listing_tree`. Pages past the
+/// last file are empty and carry no proof.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListingPage {
+    pub entries: Vec<ListingEntry>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof: Option<RangeProof>,
+}
+
+/// Result of comparing the server's cached index with the files on disk.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IndexCheck {
+    /// Files indexed after the check.
+    pub files: u64,
+    /// Files whose cached entry differed from disk, which was reloaded.
+    pub mismatched: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreateBucketRequest {
+    pub name: String,
+}
+
+/// A bucket with the current root of its tree.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BucketSummary {
+    pub name: String,
+    pub files: u64,
+    pub root_hash: Digest,
 }
@@ -0,0 +1,312 @@
+use base64::{engine::general_purpose, Engine};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Sha256;
+use std::{
+    cmp::Ordering,
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+use subtle::ConstantTimeEq;
+
+/// First byte hashed for tree leaves and internal nodes, see `Digest::of_leaf`.
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// A SHA-256 hash of a leaf, node or chunk.
+///
+/// Displayed as lowercase hex, or as base64 with the alternate flag (`{:#}`).
+/// Parsing accepts either form and serde uses hex strings. Comparisons take
+/// the same time wherever the digests differ, so verifying a proof does not
+/// leak how much of a forged hash matched.
+#[derive(Clone, Copy, Eq)]
+pub struct Digest([u8; Digest::LEN]);
+
+impl Digest {
+    pub const LEN: usize = 32;
+
+    pub const fn new(bytes: [u8; Digest::LEN]) -> Self {
+        Digest(bytes)
+    }
+
+    /// SHA-256 hash of `data`.
+    pub fn of<T: AsRef<[u8]>>(data: T) -> Self {
+        Digest(<Sha256 as sha2::Digest>::digest(data.as_ref()).into())
+    }
+
+    /// SHA-256 hash of the concatenated `digests`.
+    pub fn of_concatenated<'a, I: IntoIterator<Item = &'a Digest>>(digests: I) -> Self {
+        Digest::of_prefixed(&[], digests)
+    }
+
+    /// Hash of a tree's bottom node, `SHA-256(0x00 || leaf_hash)`.
+    pub fn of_leaf(leaf_hash: &Digest) -> Self {
+        Digest::of_prefixed(&[LEAF_PREFIX], [leaf_hash])
+    }
+
+    /// Hash of an internal node, `SHA-256(0x01 || children)`. The prefixes
+    /// keep leaves and internal nodes apart, so that no contents can be passed
+    /// off as the children of a node.
+    pub fn of_children<'a, I: IntoIterator<Item = &'a Digest>>(children: I) -> Self {
+        Digest::of_prefixed(&[NODE_PREFIX], children)
+    }
+
+    /// Hash of an internal node of a keyed tree, `HMAC-SHA256(node_key, 0x01 ||
+    /// children)`, or `of_children` for trees without a node key.
+    pub fn of_keyed_children<'a, I: IntoIterator<Item = &'a Digest>>(
+        node_key: Option<&[u8]>,
+        children: I,
+    ) -> Self {
+        let Some(node_key) = node_key else {
+            return Digest::of_children(children);
+        };
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(node_key).expect("HMAC takes keys of any length");
+        mac.update(&[NODE_PREFIX]);
+        for child in children {
+            mac.update(&child.0);
+        }
+        Digest(mac.finalize().into_bytes().into())
+    }
+
+    fn of_prefixed<'a, I: IntoIterator<Item = &'a Digest>>(prefix: &[u8], digests: I) -> Self {
+        let mut hasher = <Sha256 as sha2::Digest>::new();
+        sha2::Digest::update(&mut hasher, prefix);
+        for digest in digests {
+            sha2::Digest::update(&mut hasher, digest.0);
+        }
+        Digest(sha2::Digest::finalize(hasher).into())
+    }
+
+    /// HMAC-SHA256 of `data` under `key`.
+    pub fn hmac<T: AsRef<[u8]>>(key: &[u8], data: T) -> Self {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+        mac.update(data.as_ref());
+        Digest(mac.finalize().into_bytes().into())
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, ParseDigestError> {
+        bytes.try_into().map(Digest).map_err(|_| {
+            ParseDigestError(format!(
+                "expected {} bytes, got {}",
+                Digest::LEN,
+                bytes.len()
+            ))
+        })
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, ParseDigestError> {
+        let bytes = hex::decode(hex).map_err(|e| ParseDigestError(e.to_string()))?;
+        Digest::from_slice(&bytes)
+    }
+
+    pub fn from_base64(base64: &str) -> Result<Self, ParseDigestError> {
+        let bytes = general_purpose::STANDARD
+            .decode(base64)
+            .map_err(|e| ParseDigestError(e.to_string()))?;
+        Digest::from_slice(&bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; Digest::LEN] {
+        &self.0
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.0)
+    }
+
+    pub fn to_base64(&self) -> String {
+        general_purpose::STANDARD.encode(self.0)
+    }
+
+    /// First bytes in hex, for printing trees and proofs compactly.
+    pub fn short_hex(&self) -> String {
+        hex::encode(&self.0[..3])
+    }
+}
+
+impl PartialEq for Digest {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
+}
+
+impl Hash for Digest {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl Ord for Digest {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl PartialOrd for Digest {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl AsRef<[u8]> for Digest {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<[u8; Digest::LEN]> for Digest {
+    fn from(bytes: [u8; Digest::LEN]) -> Self {
+        Digest(bytes)
+    }
+}
+
+impl TryFrom<&[u8]> for Digest {
+    type Error = ParseDigestError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Digest::from_slice(bytes)
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            write!(f, "{}", self.to_base64())
+        } else {
+            write!(f, "{}", self.to_hex())
+        }
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Digest({})", self.to_hex())
+    }
+}
+
+impl FromStr for Digest {
+    type Err = ParseDigestError;
+
+    /// Parses a digest from hex (64 characters) or base64 (44 characters).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() == Digest::LEN * 2 {
+            Digest::from_hex(s)
+        } else {
+            Digest::from_base64(s)
+        }
+    }
+}
+
+impl Serialize for Digest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_hex())
+    }
+}
+
+impl<'de> Deserialize<'de> for Digest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        Digest::from_hex(&hex).map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseDigestError(String);
+
+impl fmt::Display for ParseDigestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid digest: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseDigestError {}
+
+#[cfg(test)]
+mod test {
+    use crate::digest::Digest;
+
+    #[test]
+    fn should_round_trip_hex_and_base64() {
+        let digest = Digest::of(b"");
+
+        assert_eq!(
+            digest.to_string(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            format!("{:#}", digest),
+            "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="
+        );
+        assert_eq!(digest.to_string().parse::<Digest>().unwrap(), digest);
+        assert_eq!(format!("{:#}", digest).parse::<Digest>().unwrap(), digest);
+        assert!("e3b0c4".parse::<Digest>().is_err());
+        assert!(Digest::from_slice(&[0; 31]).is_err());
+    }
+
+    #[test]
+    fn should_serialize_as_hex_string() {
+        let digest = Digest::of(b"some contents");
+
+        let json = serde_json::to_string(&digest).unwrap();
+
+        assert_eq!(json, format!("\"{}\"", digest));
+        assert_eq!(serde_json::from_str::<Digest>(&json).unwrap(), digest);
+        assert!(serde_json::from_str::<Digest>("[1, 2, 3]").is_err());
+    }
+
+    #[test]
+    fn should_compare_every_byte() {
+        let digest = Digest::new([7; 32]);
+        let mut last_differs = [7; 32];
+        last_differs[31] = 8;
+
+        assert_eq!(digest, Digest::new([7; 32]));
+        assert_ne!(digest, Digest::new(last_differs));
+        assert_ne!(digest, Digest::new([0; 32]));
+    }
+
+    #[test]
+    fn should_separate_leaves_from_internal_nodes() {
+        let (a, b) = (Digest::of(b"a"), Digest::of(b"b"));
+        let joined = [a.as_bytes().as_slice(), b.as_bytes()].concat();
+
+        assert_eq!(Digest::of_concatenated([&a, &b]), Digest::of(&joined));
+        assert_eq!(
+            Digest::of_children([&a, &b]),
+            Digest::of([&[0x01], joined.as_slice()].concat())
+        );
+        assert_eq!(
+            Digest::of_leaf(&a),
+            Digest::of([&[0x00], a.as_bytes().as_slice()].concat())
+        );
+        assert_ne!(
+            Digest::of_leaf(&Digest::of(&joined)),
+            Digest::of_children([&a, &b])
+        );
+    }
+
+    #[test]
+    fn should_key_internal_nodes_only_with_a_node_key() {
+        let (a, b) = (Digest::of(b"a"), Digest::of(b"b"));
+        let joined = [a.as_bytes().as_slice(), b.as_bytes()].concat();
+
+        assert_eq!(
+            Digest::of_keyed_children(None, [&a, &b]),
+            Digest::of_children([&a, &b])
+        );
+        assert_eq!(
+            Digest::of_keyed_children(Some(b"key"), [&a, &b]),
+            Digest::hmac(b"key", [&[0x01], joined.as_slice()].concat())
+        );
+        assert_ne!(
+            Digest::of_keyed_children(Some(b"key"), [&a, &b]),
+            Digest::of_keyed_children(Some(b"other key"), [&a, &b])
+        );
+    }
+}
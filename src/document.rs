@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+use crate::digest::Digest;
+use crate::merkle_tree::{MerkleLeaf, MerkleTree, ProofListItem};
+use crate::utils::{self, LeafSalt};
+
+/// Prefix of field leaves, so they cannot collide with file leaves.
+const FIELD_LEAF_PREFIX: &[u8] = b"merkle-rs:document-field:v1";
+
+/// A binary tree over the fields of a JSON document, whose root can stand in
+/// for the document as a leaf of a file tree.
+///
+/// Every scalar (and empty object or array) is a field, addressed by its JSON
+/// pointer (`/customer/name`, `/items/0`). Each field's leaf commits to a
+/// random salt, the pointer and the canonical JSON of its value, so proofs for
+/// some fields reveal nothing about the others.
+#[derive(Clone, Debug)]
+pub struct DocumentTree {
+    fields: BTreeMap<String, Value>,
+    salts: BTreeMap<String, LeafSalt>,
+    merkle_tree: MerkleTree,
+}
+
+/// A field of a document with what is needed to check it against the
+/// document root: its salt and the proof of its leaf.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FieldDisclosure {
+    pub path: String,
+    pub value: Value,
+    pub salt: LeafSalt,
+    pub proof: Vec<ProofListItem>,
+}
+
+impl DocumentTree {
+    /// Builds the tree with a fresh random salt per field.
+    pub fn new(document: &Value) -> Self {
+        let fields = flatten(document);
+        let salts = fields
+            .keys()
+            .map(|path| (path.clone(), utils::random_salt()))
+            .collect();
+        DocumentTree::build(fields, salts)
+    }
+
+    /// Rebuilds the tree of a document with the salts it was first built with.
+    /// Returns `None` if a field has no salt.
+    pub fn with_salts(document: &Value, salts: &BTreeMap<String, LeafSalt>) -> Option<Self> {
+        let fields = flatten(document);
+        let salts = fields
+            .keys()
+            .map(|path| Some((path.clone(), *salts.get(path)?)))
+            .collect::<Option<_>>()?;
+        Some(DocumentTree::build(fields, salts))
+    }
+
+    fn build(fields: BTreeMap<String, Value>, salts: BTreeMap<String, LeafSalt>) -> Self {
+        let leaves = fields
+            .iter()
+            .map(|(path, value)| {
+                MerkleLeaf::from_hash(
+                    Some(path.clone()),
+                    field_leaf_hash(&salts[path], path, value),
+                )
+            })
+            .collect();
+        DocumentTree {
+            fields,
+            salts,
+            merkle_tree: MerkleTree::from_leaves(leaves),
+        }
+    }
+
+    /// Hash committing to the whole document, its leaf in a file tree.
+    pub fn root(&self) -> Digest {
+        self.merkle_tree.get_root_hash()
+    }
+
+    /// Salts of every field, which the document's owner keeps to disclose
+    /// fields later.
+    pub fn salts(&self) -> &BTreeMap<String, LeafSalt> {
+        &self.salts
+    }
+
+    /// JSON pointers of all fields, in leaf order.
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.fields.keys().map(String::as_str)
+    }
+
+    /// Discloses the field at `path` and nothing else about the document.
+    pub fn disclose(&self, path: &str) -> Option<FieldDisclosure> {
+        Some(FieldDisclosure {
+            path: path.to_owned(),
+            value: self.fields.get(path)?.clone(),
+            salt: *self.salts.get(path)?,
+            proof: self.merkle_tree.generate_merkle_proof(path)?,
+        })
+    }
+}
+
+impl FieldDisclosure {
+    /// Checks that the document with the given root has this value at `path`.
+    pub fn verify(&self, document_root: &Digest) -> bool {
+        let leaf_hash = field_leaf_hash(&self.salt, &self.path, &self.value);
+        utils::verify_merkle_proof_for_leaf(self.proof.clone(), *document_root, leaf_hash)
+    }
+}
+
+/// Hash of a prefix, the salt, the length-prefixed path and the canonical JSON
+/// of the value. Object keys serialize sorted and without whitespace.
+fn field_leaf_hash(salt: &LeafSalt, path: &str, value: &Value) -> Digest {
+    let mut data = FIELD_LEAF_PREFIX.to_vec();
+    data.extend_from_slice(salt.as_bytes());
+    data.extend_from_slice(&(path.len() as u64).to_be_bytes());
+    data.extend_from_slice(path.as_bytes());
+    data.extend_from_slice(value.to_string().as_bytes());
+    Digest::of(data)
+}
+
+/// Fields of a document by JSON pointer.
+fn flatten(document: &Value) -> BTreeMap<String, Value> {
+    fn visit(value: &Value, path: String, fields: &mut BTreeMap<String, Value>) {
+        match value {
+            Value::Object(map) if !map.is_empty() => {
+                for (key, child) in map {
+                    let key = key.replace('~', "~0").replace('/', "~1");
+                    visit(child, format!("{}/{}", path, key), fields);
+                }
+            }
+            Value::Array(items) if !items.is_empty() => {
+                for (index, child) in items.iter().enumerate() {
+                    visit(child, format!("{}/{}", path, index), fields);
+                }
+            }
+            _ => {
+                fields.insert(path, value.clone());
+            }
+        }
+    }
+
+    let mut fields = BTreeMap::new();
+    visit(document, String::new(), &mut fields);
+    fields
+}
+
+#[cfg(test)]
+mod test {
+    use crate::document::DocumentTree;
+    use serde_json::json;
+
+    fn invoice() -> serde_json::Value {
+        json!({
+            "id": "INV-1042",
+            "status": "paid",
+            "amount": 1250,
+            "customer": { "name": "Ada", "email": "ada@example.com" },
+            "lines": [{ "sku": "A/1", "qty": 2 }, { "sku": "B~2", "qty": 1 }],
+        })
+    }
+
+    #[test]
+    fn should_verify_disclosed_fields_only() {
+        let tree = DocumentTree::new(&invoice());
+
+        let status = tree.disclose("/status").unwrap();
+        assert!(status.verify(&tree.root()));
+        assert!(tree
+            .disclose("/customer/email")
+            .unwrap()
+            .verify(&tree.root()));
+
+        let mut forged = status.clone();
+        forged.value = json!("refunded");
+        assert!(!forged.verify(&tree.root()));
+
+        let mut moved = status;
+        moved.path = "/id".to_owned();
+        assert!(!moved.verify(&tree.root()));
+
+        let mut malformed = tree.disclose("/amount").unwrap();
+        for item in &mut malformed.proof {
+            item.order = None;
+        }
+        assert!(!malformed.verify(&tree.root()));
+
+        let serialized = serde_json::to_string(&tree.disclose("/amount").unwrap()).unwrap();
+        assert!(!serialized.contains("Ada"));
+        assert!(tree.disclose("/customer").is_none());
+    }
+
+    #[test]
+    fn should_address_fields_by_json_pointer() {
+        let tree = DocumentTree::new(&invoice());
+
+        let paths: Vec<&str> = tree.paths().collect();
+        assert_eq!(
+            paths,
+            [
+                "/amount",
+                "/customer/email",
+                "/customer/name",
+                "/id",
+                "/lines/0/qty",
+                "/lines/0/sku",
+                "/lines/1/qty",
+                "/lines/1/sku",
+                "/status",
+            ]
+        );
+        assert_eq!(tree.disclose("/lines/0/sku").unwrap().value, json!("A/1"));
+    }
+
+    #[test]
+    fn should_rebuild_same_root_from_salts() {
+        let tree = DocumentTree::new(&invoice());
+
+        let rebuilt = DocumentTree::with_salts(&invoice(), tree.salts()).unwrap();
+        let fresh = DocumentTree::new(&invoice());
+
+        assert_eq!(rebuilt.root(), tree.root());
+        assert_ne!(fresh.root(), tree.root());
+
+        let mut changed = invoice();
+        changed["status"] = json!("overdue");
+        let changed = DocumentTree::with_salts(&changed, tree.salts()).unwrap();
+        assert_ne!(changed.root(), tree.root());
+        assert!(DocumentTree::with_salts(&json!({ "other": 1 }), tree.salts()).is_none());
+    }
+}
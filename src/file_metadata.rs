@@ -0,0 +1,259 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::digest::Digest;
+
+/// Prefix of leaves that commit to metadata, so they cannot collide with plain content.
+const LEAF_PREFIX: &[u8] = b"merkle-rs:file-metadata:v1";
+
+/// Which metadata fields a tree's leaves commit to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataFields {
+    pub size: bool,
+    pub mode: bool,
+    pub mtime: bool,
+}
+
+impl MetadataFields {
+    pub const ALL: MetadataFields = MetadataFields {
+        size: true,
+        mode: true,
+        mtime: true,
+    };
+
+    pub fn is_empty(&self) -> bool {
+        !(self.size || self.mode || self.mtime)
+    }
+
+    pub fn bits(&self) -> u8 {
+        (self.size as u8) | (self.mode as u8) << 1 | (self.mtime as u8) << 2
+    }
+
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        if bits & !0b111 != 0 {
+            return None;
+        }
+        Some(MetadataFields {
+            size: bits & 1 != 0,
+            mode: bits & 0b10 != 0,
+            mtime: bits & 0b100 != 0,
+        })
+    }
+}
+
+/// File metadata committed to in a leaf. Fields that are not selected are `None`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    pub size: Option<u64>,
+    /// Permission bits, without the file type.
+    pub mode: Option<u32>,
+    /// Modification time in seconds since the unix epoch.
+    pub mtime: Option<u64>,
+}
+
+impl FileMetadata {
+    /// Largest mode a file is committed or restored with: permission bits
+    /// only, without setuid, setgid or sticky bits.
+    pub const MAX_MODE: u32 = 0o777;
+
+    /// Reads the selected fields of the file at `path`.
+    pub fn read(path: &Path, fields: MetadataFields) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        Ok(FileMetadata {
+            size: fields.size.then_some(metadata.len()),
+            mode: fields.mode.then_some(file_mode(&metadata)),
+            mtime: fields.mtime.then_some(mtime),
+        })
+    }
+
+    pub fn fields(&self) -> MetadataFields {
+        MetadataFields {
+            size: self.size.is_some(),
+            mode: self.mode.is_some(),
+            mtime: self.mtime.is_some(),
+        }
+    }
+
+    /// Checks that the metadata can be restored, rejecting modes with bits
+    /// beyond `MAX_MODE`.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.mode {
+            Some(mode) if mode > FileMetadata::MAX_MODE => {
+                Err(format!("Unsupported file mode {:o}", mode))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Keeps only the selected fields.
+    pub fn select(&self, fields: MetadataFields) -> Self {
+        FileMetadata {
+            size: self.size.filter(|_| fields.size),
+            mode: self.mode.filter(|_| fields.mode),
+            mtime: self.mtime.filter(|_| fields.mtime),
+        }
+    }
+
+    /// Sets the mode and modification time of the file at `path`. The size is
+    /// a property of the content and is only checked, never applied.
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        // Set the time first, the mode may make the file read-only
+        if let Some(mtime) = self.mtime {
+            let file = fs::OpenOptions::new().write(true).open(path)?;
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime))?;
+        }
+        if let Some(mode) = self.mode {
+            set_file_mode(path, mode)?;
+        }
+        Ok(())
+    }
+
+    /// Bytes hashed into the leaf: a prefix, the content hash and each selected
+    /// field as a tag followed by a big-endian `u64`.
+    pub fn leaf_data(&self, content: &[u8]) -> Vec<u8> {
+        self.leaf_data_for_hash(&Digest::of(content))
+    }
+
+    /// Like `leaf_data`, for contents already hashed (or chunked into a root).
+    pub fn leaf_data_for_hash(&self, content_hash: &Digest) -> Vec<u8> {
+        let mut data = LEAF_PREFIX.to_vec();
+        data.extend_from_slice(content_hash.as_bytes());
+        for (tag, value) in [
+            (1u8, self.size),
+            (2u8, self.mode.map(u64::from)),
+            (3u8, self.mtime),
+        ] {
+            if let Some(value) = value {
+                data.push(tag);
+                data.extend_from_slice(&value.to_be_bytes());
+            }
+        }
+        data
+    }
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & FileMetadata::MAX_MODE
+}
+
+#[cfg(not(unix))]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(unix)]
+fn set_file_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(
+        path,
+        fs::Permissions::from_mode(mode & FileMetadata::MAX_MODE),
+    )
+}
+
+#[cfg(not(unix))]
+fn set_file_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::file_metadata::{FileMetadata, MetadataFields};
+    use std::fs;
+
+    #[test]
+    fn should_read_and_apply_selected_metadata() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let source = temp_dir.path().join("source.txt");
+        let copy = temp_dir.path().join("copy.txt");
+        fs::write(&source, b"some contents").unwrap();
+        fs::write(&copy, b"some contents").unwrap();
+
+        let metadata = FileMetadata {
+            size: Some(13),
+            mode: Some(0o640),
+            mtime: Some(1_600_000_000),
+        };
+        metadata.apply(&source).unwrap();
+        metadata.apply(&copy).unwrap();
+
+        let read = FileMetadata::read(&copy, MetadataFields::ALL).unwrap();
+        assert_eq!(read, metadata);
+
+        let size_only = MetadataFields {
+            size: true,
+            ..Default::default()
+        };
+        let read = FileMetadata::read(&source, size_only).unwrap();
+        assert_eq!(read.fields(), size_only);
+        assert_eq!(read, metadata.select(size_only));
+    }
+
+    #[test]
+    fn should_commit_to_metadata_in_leaf_data() {
+        let metadata = FileMetadata {
+            size: Some(13),
+            mode: Some(0o640),
+            mtime: Some(1_600_000_000),
+        };
+        let changed = FileMetadata {
+            mode: Some(0o644),
+            ..metadata.clone()
+        };
+
+        assert_ne!(
+            metadata.leaf_data(b"some contents"),
+            changed.leaf_data(b"some contents")
+        );
+        assert_ne!(
+            metadata.leaf_data(b"some contents"),
+            metadata
+                .select(MetadataFields::default())
+                .leaf_data(b"some contents")
+        );
+    }
+
+    #[test]
+    fn should_reject_modes_beyond_permission_bits() {
+        for mode in [0o000, 0o644, 0o755, 0o777] {
+            let metadata = FileMetadata {
+                mode: Some(mode),
+                ..Default::default()
+            };
+            assert!(metadata.validate().is_ok());
+        }
+        for mode in [0o4755, 0o2755, 0o1777, 0o100644] {
+            let metadata = FileMetadata {
+                mode: Some(mode),
+                ..Default::default()
+            };
+            assert!(metadata.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn should_round_trip_field_bits() {
+        for bits in 0..8 {
+            assert_eq!(MetadataFields::from_bits(bits).unwrap().bits(), bits);
+        }
+        assert!(MetadataFields::from_bits(8).is_none());
+    }
+}
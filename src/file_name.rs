@@ -0,0 +1,161 @@
+use std::{fmt, str::FromStr};
+use unicode_normalization::UnicodeNormalization;
+
+/// A file name that is safe to join onto a storage directory: a single path
+/// component in Unicode NFC form, so names that look the same are the same.
+///
+/// Rejects separators, `.` and `..`, control characters (including NUL),
+/// names starting with a dot, which are kept for the store's own directories,
+/// names Windows reserves for devices or would silently alter, and names
+/// longer than `MAX_LEN` bytes.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileName(String);
+
+impl FileName {
+    /// Longest name most file systems accept, in bytes once normalized.
+    pub const MAX_LEN: usize = 255;
+
+    const RESERVED: [&'static str; 4] = ["CON", "PRN", "AUX", "NUL"];
+
+    pub fn new(name: &str) -> Result<Self, InvalidFileName> {
+        let name: String = name.nfc().collect();
+        let invalid = |reason: &str| Err(InvalidFileName(format!("{:?} {}", name, reason)));
+
+        if name.is_empty() {
+            return invalid("is empty");
+        }
+        if name.len() > FileName::MAX_LEN {
+            return invalid(&format!("is longer than {} bytes", FileName::MAX_LEN));
+        }
+        if name.contains(['/', '\\']) {
+            return invalid("contains a path separator");
+        }
+        if name.chars().any(char::is_control) {
+            return invalid("contains a control character");
+        }
+        if name.starts_with('.') {
+            return invalid("starts with a dot");
+        }
+        if name.ends_with(['.', ' ']) {
+            return invalid("ends with a dot or space");
+        }
+        if FileName::is_reserved(&name) {
+            return invalid("is reserved for a device");
+        }
+        Ok(FileName(name))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Windows device names, which are reserved with any extension.
+    fn is_reserved(name: &str) -> bool {
+        let stem = name.split('.').next().unwrap_or(name).trim_end();
+        let numbered = |prefix: &str| {
+            stem.len() == 4
+                && stem
+                    .get(..3)
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+                && matches!(stem.as_bytes()[3], b'1'..=b'9')
+        };
+        FileName::RESERVED
+            .iter()
+            .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+            || numbered("COM")
+            || numbered("LPT")
+    }
+}
+
+impl fmt::Display for FileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for FileName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<std::path::Path> for FileName {
+    fn as_ref(&self) -> &std::path::Path {
+        std::path::Path::new(&self.0)
+    }
+}
+
+impl From<FileName> for String {
+    fn from(name: FileName) -> Self {
+        name.0
+    }
+}
+
+impl FromStr for FileName {
+    type Err = InvalidFileName;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        FileName::new(name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidFileName(String);
+
+impl fmt::Display for InvalidFileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid file name: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidFileName {}
+
+#[cfg(test)]
+mod test {
+    use crate::file_name::FileName;
+
+    #[test]
+    fn should_reject_names_escaping_the_directory() {
+        let long = "a".repeat(FileName::MAX_LEN + 1);
+        for name in [
+            "",
+            ".",
+            "..",
+            "../../etc/cron.d/x",
+            "/etc/passwd",
+            "dir/file.txt",
+            "..\\windows",
+            "nul\0byte",
+            "line\nbreak",
+            ".chunks",
+            "trailing.",
+            "trailing ",
+            "CON",
+            "con.txt",
+            "Lpt1.log",
+            &long,
+        ] {
+            assert!(FileName::new(name).is_err(), "accepted {:?}", name);
+        }
+
+        for name in [
+            "file.txt",
+            "a..b",
+            "CONSOLE.txt",
+            "COM0",
+            "résumé.pdf",
+            "x y",
+        ] {
+            assert_eq!(FileName::new(name).unwrap().as_str(), name);
+        }
+    }
+
+    #[test]
+    fn should_normalize_to_composed_form() {
+        let decomposed = FileName::new("re\u{301}sume\u{301}.pdf").unwrap();
+        let composed = FileName::new("r\u{e9}sum\u{e9}.pdf").unwrap();
+
+        assert_eq!(decomposed, composed);
+        assert_eq!(decomposed.as_str(), "r\u{e9}sum\u{e9}.pdf");
+    }
+}
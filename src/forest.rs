@@ -0,0 +1,205 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use crate::common::FileResponse;
+use crate::digest::Digest;
+use crate::merkle_tree::{MerkleLeaf, MerkleTree, ProofListItem};
+use crate::tree_head::TreeHead;
+use crate::utils;
+
+/// Prefix of collection leaves, so they cannot collide with file leaves.
+const COLLECTION_LEAF_PREFIX: &[u8] = b"merkle-rs:forest-collection:v1";
+
+/// A binary tree over the tree heads of named collections, whose root is a
+/// super-root committing to every collection at once.
+///
+/// Each leaf commits to the collection's name and whole tree head, so a file
+/// proof is checked with the layout the collection was committed with.
+#[derive(Clone, Debug)]
+pub struct Forest {
+    collections: BTreeMap<String, TreeHead>,
+    merkle_tree: MerkleTree,
+}
+
+/// Proof that a collection's tree head is part of a forest.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CollectionProof {
+    pub collection: String,
+    pub tree_head: TreeHead,
+    pub proof: Vec<ProofListItem>,
+}
+
+/// A file with its proof up to its collection root, chained to the super-root.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ForestFileProof {
+    pub file: FileResponse,
+    pub collection: CollectionProof,
+}
+
+impl Forest {
+    pub fn new(collections: BTreeMap<String, TreeHead>) -> Self {
+        let leaves = collections
+            .iter()
+            .map(|(name, tree_head)| {
+                MerkleLeaf::from_hash(Some(name.clone()), collection_leaf_hash(name, tree_head))
+            })
+            .collect();
+        Forest {
+            collections,
+            merkle_tree: MerkleTree::from_leaves(leaves),
+        }
+    }
+
+    /// Builds a forest from `(name, path)` pairs of stored tree heads, such as
+    /// each collection's `merkle.bin`.
+    pub fn read_tree_heads<I, N, P>(paths: I) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = (N, P)>,
+        N: Into<String>,
+        P: AsRef<Path>,
+    {
+        let mut collections = BTreeMap::new();
+        for (name, path) in paths {
+            let tree_head = TreeHead::from_bytes(&fs::read(path)?)?;
+            if collections.insert(name.into(), tree_head).is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Collection names must be unique",
+                ));
+            }
+        }
+        Ok(Forest::new(collections))
+    }
+
+    pub fn super_root(&self) -> Digest {
+        self.merkle_tree.get_root_hash()
+    }
+
+    /// Head of the top-level tree, with one leaf per collection.
+    pub fn tree_head(&self) -> TreeHead {
+        self.merkle_tree.tree_head()
+    }
+
+    pub fn collections(&self) -> &BTreeMap<String, TreeHead> {
+        &self.collections
+    }
+
+    pub fn prove_collection(&self, collection: &str) -> Option<CollectionProof> {
+        Some(CollectionProof {
+            collection: collection.to_owned(),
+            tree_head: self.collections.get(collection)?.clone(),
+            proof: self.merkle_tree.generate_merkle_proof(collection)?,
+        })
+    }
+
+    /// Chains a file's proof from its collection to the super-root.
+    pub fn prove_file(&self, collection: &str, file: FileResponse) -> Option<ForestFileProof> {
+        Some(ForestFileProof {
+            file,
+            collection: self.prove_collection(collection)?,
+        })
+    }
+}
+
+impl CollectionProof {
+    pub fn verify(&self, super_root: &Digest) -> bool {
+        let leaf_hash = collection_leaf_hash(&self.collection, &self.tree_head);
+        utils::verify_merkle_proof_for_leaf(self.proof.clone(), *super_root, leaf_hash)
+    }
+}
+
+impl ForestFileProof {
+    /// Checks the collection against the super-root, then the file against
+    /// the collection's tree head.
+    pub fn verify(&self, super_root: &Digest) -> bool {
+        self.collection.verify(super_root)
+            && utils::verify_file_response(&self.file, &self.collection.tree_head)
+    }
+}
+
+fn collection_leaf_hash(name: &str, tree_head: &TreeHead) -> Digest {
+    let mut data = COLLECTION_LEAF_PREFIX.to_vec();
+    data.extend_from_slice(&(name.len() as u64).to_be_bytes());
+    data.extend_from_slice(name.as_bytes());
+    data.extend_from_slice(&tree_head.to_bytes());
+    Digest::of(data)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{common::FileResponse, forest::Forest, merkle_tree::MerkleTree};
+    use std::{collections::BTreeMap, fs};
+
+    fn collection(files: &[(&str, &str)]) -> MerkleTree {
+        MerkleTree::new(files.iter().copied())
+    }
+
+    #[test]
+    fn should_chain_file_proofs_to_super_root() {
+        let photos = collection(&[("a.jpg", "photo a"), ("b.jpg", "photo b")]);
+        let docs = collection(&[("x.txt", "doc x"), ("y.txt", "doc y"), ("z.txt", "doc z")]);
+        let forest = Forest::new(BTreeMap::from([
+            ("docs".to_owned(), docs.tree_head()),
+            ("photos".to_owned(), photos.tree_head()),
+        ]));
+
+        let file = FileResponse::new(
+            "y.txt".to_owned(),
+            b"doc y".to_vec(),
+            docs.generate_merkle_proof("y.txt").unwrap(),
+        );
+        let chained = forest.prove_file("docs", file).unwrap();
+        assert!(chained.verify(&forest.super_root()));
+
+        let mut moved = chained.collection.clone();
+        moved.collection = "photos".to_owned();
+        assert!(!moved.verify(&forest.super_root()));
+
+        let mut malformed = chained.collection.clone();
+        for item in &mut malformed.proof {
+            item.order = None;
+        }
+        assert!(!malformed.verify(&forest.super_root()));
+
+        let photo = FileResponse::new(
+            "a.jpg".to_owned(),
+            b"photo a".to_vec(),
+            photos.generate_merkle_proof("a.jpg").unwrap(),
+        );
+        let wrong_collection = forest.prove_file("docs", photo).unwrap();
+        assert!(!wrong_collection.verify(&forest.super_root()));
+        assert!(forest.prove_collection("music").is_none());
+    }
+
+    #[test]
+    fn should_change_super_root_with_any_collection() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let heads = [
+            ("logs", collection(&[("1.log", "one")]).tree_head()),
+            ("docs", collection(&[("x.txt", "doc x")]).tree_head()),
+        ];
+        let paths: Vec<(&str, std::path::PathBuf)> = heads
+            .iter()
+            .map(|(name, tree_head)| {
+                let path = temp_dir.path().join(format!("{}.bin", name));
+                fs::write(&path, tree_head.to_bytes()).unwrap();
+                (*name, path)
+            })
+            .collect();
+
+        let forest = Forest::read_tree_heads(paths.clone()).unwrap();
+        assert_eq!(forest.tree_head().leaf_count, 2);
+        assert!(forest
+            .prove_collection("logs")
+            .unwrap()
+            .verify(&forest.super_root()));
+
+        let mut collections = forest.collections().clone();
+        collections.get_mut("logs").unwrap().root_hash =
+            collection(&[("1.log", "changed")]).tree_head().root_hash;
+        assert_ne!(Forest::new(collections).super_root(), forest.super_root());
+
+        let duplicate = [paths[0].clone(), paths[0].clone()];
+        assert!(Forest::read_tree_heads(duplicate).is_err());
+    }
+}
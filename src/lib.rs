@@ -0,0 +1,33 @@
+//! Merkle tree construction, proof generation and verification, together with
+//! the wire types and client used by the `client` and `server` binaries.
+//!
+//! ```
+//! use merkle_rs::{merkle_tree::MerkleTree, utils};
+//!
+//! let rows = vec!["row a", "row b", "row c"];
+//! let tree = MerkleTree::from_items(&rows);
+//! let proof = tree.generate_merkle_proof_for_index(1).unwrap();
+//!
+//! assert!(utils::verify_merkle_proof(
+//!     proof,
+//!     tree.get_root_hash(),
+//!     b"row b".to_vec(),
+//! ));
+//! ```
+
+pub mod batch_verify;
+pub mod chunking;
+pub mod common;
+pub mod digest;
+pub mod document;
+pub mod file_metadata;
+pub mod file_name;
+pub mod forest;
+pub mod merkle_client;
+pub mod merkle_tree;
+pub mod proof_cache;
+pub mod signature;
+pub mod streaming;
+pub mod tree_head;
+pub mod utils;
+pub mod versioned_tree;
@@ -0,0 +1,996 @@
+use base64::{self, engine::general_purpose, Engine};
+use hyper::StatusCode;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+};
+
+use crate::chunking::{self, ChunkManifest};
+use crate::common::{
+    BucketSummary, ChunkUploadRequest, CreateBucketRequest, FileResponse, ListingEntry,
+    ListingPage, ListingQuery, ManifestUploadRequest, MissingChunksRequest, MissingChunksResponse,
+    SignedTreeHead, UploadRequest,
+};
+use crate::digest::Digest;
+use crate::file_metadata::{FileMetadata, MetadataFields};
+use crate::file_name::FileName;
+use crate::merkle_tree::{MerkleLeaf, MerkleTree};
+use crate::streaming::{self, StreamDecoder};
+use crate::tree_head::TreeHead;
+use crate::utils::{self, LeafSalt};
+
+pub struct MerkleClient {
+    pub merkle_root: Option<TreeHead>,
+    /// Arity of the tree computed over the client files, must match the server
+    pub arity: usize,
+    /// Metadata fields committed to in each leaf, must match the server
+    pub leaf_metadata: MetadataFields,
+    /// Upload files as deduplicated chunks and use chunk tree roots as leaves
+    pub chunking: bool,
+    /// Secret the leaves and nodes are keyed with. The server is sent the
+    /// keyed leaf hashes and needs the node key derived from it, never the
+    /// secret itself, see `utils::node_key`.
+    pub leaf_key: Option<Vec<u8>>,
+    /// Random salt of each file's leaf, see `generate_leaf_salts`.
+    pub leaf_salts: Option<BTreeMap<String, LeafSalt>>,
+    /// Bucket the files are stored in, the server's default store if unset
+    pub bucket: Option<String>,
+    server_url: String,
+    reqwest_client: reqwest::Client,
+    client_files: Option<String>,
+    merkle_root_path: String,
+}
+
+static NO_DIR_MSG: &str = "Client has no set directory path";
+
+/// Name a local file is stored under on the server, see `FileName`.
+fn stored_name(filename: &str) -> io::Result<String> {
+    FileName::new(filename)
+        .map(String::from)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Names local files are stored under, by local name. Fails on names the
+/// server would reject and on files that would be stored under the same name,
+/// such as one name in two Unicode normalization forms.
+fn stored_names<'a, I>(filenames: I) -> io::Result<BTreeMap<String, String>>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut stored_names = BTreeMap::new();
+    let mut local_names: BTreeMap<String, &str> = BTreeMap::new();
+    for filename in filenames {
+        let stored = stored_name(filename)?;
+        if let Some(other) = local_names.insert(stored.clone(), filename) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} and {} would both be stored as {}",
+                    other, filename, stored
+                ),
+            ));
+        }
+        stored_names.insert(filename.to_owned(), stored);
+    }
+    Ok(stored_names)
+}
+
+impl MerkleClient {
+    pub fn new(
+        server_url: &str,
+        reqwest_client: reqwest::Client,
+        client_files: Option<String>,
+        merkle_root_path: String,
+    ) -> Self {
+        MerkleClient {
+            merkle_root: None,
+            arity: 2,
+            leaf_metadata: MetadataFields::default(),
+            chunking: false,
+            leaf_key: None,
+            leaf_salts: None,
+            bucket: None,
+            server_url: server_url.to_owned(),
+            reqwest_client,
+            client_files,
+            merkle_root_path,
+        }
+    }
+    /// Requests a file with its proof against `root`, a root the server kept,
+    /// or against the server's current root.
+    pub async fn request_file(
+        &self,
+        filename: &str,
+        root: Option<&Digest>,
+    ) -> Result<FileResponse, Box<dyn std::error::Error>> {
+        let url = self.file_url("file", &stored_name(filename)?, root)?;
+
+        let response = self.reqwest_client.get(url).send().await?;
+
+        match response.status() {
+            StatusCode::OK => {
+                let response_body = response.text().await?;
+                let file_response: FileResponse = serde_json::from_str(&response_body)?;
+                Ok(file_response)
+            }
+            StatusCode::CONFLICT => Err(Box::new(io::Error::other(
+                "File changed on the server since the stored root",
+            ))),
+            status => Err(Box::new(io::Error::other(format!(
+                "Failed to retrieve file from server: {}",
+                status
+            )))),
+        }
+    }
+
+    pub async fn request_signed_tree_head(
+        &self,
+    ) -> Result<SignedTreeHead, Box<dyn std::error::Error>> {
+        let url = format!("{}/tree-head", self.store_url());
+
+        let response = self.reqwest_client.get(&url).send().await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(serde_json::from_str(&response.text().await?)?),
+            _ => Err(Box::new(io::Error::other(
+                "Failed to retrieve signed tree head from server",
+            ))),
+        }
+    }
+
+    /// Lists every file the server holds, `page_size` at a time, checking
+    /// each page against `tree_head` so a file missing from the listing, added
+    /// to it or changed in it is detected.
+    pub async fn list_files(
+        &self,
+        tree_head: &TreeHead,
+        page_size: u64,
+    ) -> Result<Vec<ListingEntry>, Box<dyn std::error::Error>> {
+        if tree_head.listing_root.is_none() {
+            return Err(Box::new(io::Error::other(
+                "The stored tree head has no listing root to verify listings against",
+            )));
+        }
+        let url = format!("{}/files", self.store_url());
+        let mut entries: Vec<ListingEntry> = Vec::new();
+
+        loop {
+            let offset = entries.len() as u64;
+            let page: ListingPage = self
+                .reqwest_client
+                .get(&url)
+                .query(&ListingQuery {
+                    offset,
+                    limit: page_size,
+                })
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            let follows_previous = match (entries.last(), page.entries.first()) {
+                (Some(last), Some(first)) => last.filename < first.filename,
+                _ => true,
+            };
+            if !follows_previous || !utils::verify_listing_page(&page, tree_head, offset) {
+                return Err(Box::new(io::Error::other(format!(
+                    "Listing page at {} does not match the merkle root",
+                    offset
+                ))));
+            }
+            if page.entries.is_empty() {
+                return Ok(entries);
+            }
+            entries.extend(page.entries);
+        }
+    }
+
+    pub async fn upload_all_files_to_server(&self) -> Result<(), Box<dyn std::error::Error>> {
+        match &self.client_files {
+            Some(client_files) if self.chunking => {
+                self.upload_all_files_as_chunks(client_files).await
+            }
+            Some(client_files) => {
+                let base_url = format!("{}/upload", self.store_url());
+                let keyed_leaves = self.keyed_leaf_hashes(client_files)?;
+
+                let mut files = Vec::new();
+                for entry in fs::read_dir(client_files)? {
+                    let entry = entry?;
+                    if entry.path().is_file() {
+                        let filename = entry.file_name().to_string_lossy().into_owned();
+                        files.push((entry.path(), filename));
+                    }
+                }
+                let mut stored_names = stored_names(files.iter().map(|(_, name)| name.as_str()))?;
+
+                for (path, filename) in files {
+                    let stored = stored_names.remove(&filename).unwrap_or_default();
+                    let keyed_leaf = self.keyed_leaf(keyed_leaves.as_ref(), &stored);
+                    let salt = self.leaf_salt(&filename);
+                    let metadata = self.committed_metadata(&path)?;
+                    MerkleClient::upload_file(
+                        &self.reqwest_client,
+                        &path,
+                        stored,
+                        &base_url,
+                        metadata,
+                        keyed_leaf,
+                        salt,
+                    )
+                    .await?;
+                }
+
+                Ok(())
+            }
+            None => {
+                eprintln!("{}", NO_DIR_MSG);
+                Err(Box::new(io::Error::other(NO_DIR_MSG)))
+            }
+        }
+    }
+
+    /// Uploads the chunks of every file that the server does not store yet,
+    /// each once, followed by one manifest per file.
+    async fn upload_all_files_as_chunks(
+        &self,
+        client_files: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let files = utils::parse_files(client_files);
+        let keyed_leaves = self.keyed_leaf_hashes(client_files)?;
+        let mut stored_names = stored_names(files.keys().map(String::as_str))?;
+
+        let mut chunks: BTreeMap<Digest, &[u8]> = BTreeMap::new();
+        let mut manifests = Vec::new();
+        for (filename, content) in &files {
+            let mut hashes = Vec::new();
+            for chunk in chunking::split_chunks(content) {
+                let hash = Digest::of(chunk);
+                chunks.entry(hash).or_insert(chunk);
+                hashes.push(hash);
+            }
+            let path = std::path::Path::new(client_files).join(filename);
+            let manifest = ChunkManifest {
+                size: content.len() as u64,
+                chunks: hashes,
+                metadata: self.committed_metadata(&path)?,
+            };
+            let stored = stored_names.remove(filename).unwrap_or_default();
+            manifests.push((filename, stored, manifest));
+        }
+
+        let missing: MissingChunksResponse = self
+            .reqwest_client
+            .post(format!("{}/chunks/missing", self.store_url()))
+            .json(&MissingChunksRequest {
+                chunks: chunks.keys().cloned().collect(),
+            })
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        println!(
+            "Uploading {} of {} distinct chunks",
+            missing.missing.len(),
+            chunks.len()
+        );
+        for hash in &missing.missing {
+            let content = chunks.get(hash).ok_or_else(|| {
+                io::Error::other(format!("Server asked for unknown chunk {}", hash))
+            })?;
+            self.reqwest_client
+                .post(format!("{}/chunks", self.store_url()))
+                .json(&ChunkUploadRequest {
+                    content: general_purpose::STANDARD.encode(content),
+                })
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+
+        for (filename, stored, manifest) in manifests {
+            let keyed_leaf = self.keyed_leaf(keyed_leaves.as_ref(), &stored);
+            self.reqwest_client
+                .post(format!("{}/manifest", self.store_url()))
+                .json(&ManifestUploadRequest {
+                    leaf_hash: keyed_leaf.map(|(leaf_hash, _)| leaf_hash),
+                    leaf_tag: keyed_leaf.map(|(_, leaf_tag)| leaf_tag),
+                    filename: stored,
+                    manifest,
+                    salt: self.leaf_salt(filename),
+                })
+                .send()
+                .await?
+                .error_for_status()?;
+            println!("Successfully uploaded: {:?}", filename);
+        }
+
+        Ok(())
+    }
+
+    pub fn read_merkle_root_from_disk(&self) -> io::Result<TreeHead> {
+        TreeHead::from_bytes(&fs::read(self.merkle_root_path.clone())?)
+    }
+
+    pub fn write_merkle_root_to_disk(&self) -> io::Result<()> {
+        match self.merkle_root.clone() {
+            Some(merkle_root) => fs::write(self.merkle_root_path.clone(), merkle_root.to_bytes()),
+            None => {
+                eprintln!("Client has no merkle root to store");
+                Err(io::Error::other("Client has no merkle root to store"))
+            }
+        }
+    }
+
+    /// Salts are kept next to the merkle root, as `<merkle path>.salts`.
+    fn leaf_salts_path(&self) -> String {
+        format!("{}.salts", self.merkle_root_path)
+    }
+
+    /// Draws a random salt for every client file. The salts are sent with the
+    /// uploads and committed in the leaves, so that a proof for one file does
+    /// not expose the others' contents to brute force.
+    pub fn generate_leaf_salts(&mut self) -> io::Result<()> {
+        match &self.client_files {
+            Some(client_files) => {
+                let salts = utils::parse_files(client_files)
+                    .into_keys()
+                    .map(|filename| (filename, utils::random_salt()))
+                    .collect();
+                self.leaf_salts = Some(salts);
+                Ok(())
+            }
+            None => {
+                eprintln!("{}", NO_DIR_MSG);
+                Err(io::Error::other(NO_DIR_MSG))
+            }
+        }
+    }
+
+    pub fn read_leaf_salts_from_disk(&self) -> io::Result<BTreeMap<String, LeafSalt>> {
+        let json = fs::read(self.leaf_salts_path())?;
+        serde_json::from_slice(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Stores the salts, if any, so that proofs can be disclosed later.
+    pub fn write_leaf_salts_to_disk(&self) -> io::Result<()> {
+        match &self.leaf_salts {
+            Some(salts) => fs::write(self.leaf_salts_path(), serde_json::to_vec_pretty(salts)?),
+            None => Ok(()),
+        }
+    }
+
+    pub fn delete_local_client_files(&self) -> io::Result<()> {
+        match &self.client_files {
+            Some(client_files) => {
+                let entries = fs::read_dir(client_files)?;
+
+                for entry in entries {
+                    let entry = entry?;
+                    let path = entry.path();
+                    if path.is_file() {
+                        fs::remove_file(path)?;
+                    }
+                }
+
+                Ok(())
+            }
+            None => {
+                eprintln!("{}", NO_DIR_MSG);
+                Err(io::Error::other(NO_DIR_MSG))
+            }
+        }
+    }
+
+    pub fn compute_merkle_root_from_files(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match &self.client_files {
+            Some(client_files) => {
+                let entries = self.listing_entries(client_files)?;
+                let leaf_hashes = entries
+                    .iter()
+                    .map(|entry| (&entry.filename, entry.leaf_hash));
+                let merkle_tree = self.merkle_tree(leaf_hashes);
+                let mut tree_head = merkle_tree.tree_head();
+                let listing_tree = utils::listing_tree(&entries, self.arity);
+                tree_head.listing_root = Some(listing_tree.get_root_hash());
+                tree_head.leaf_metadata = self.leaf_metadata;
+                tree_head.chunking = self.chunking;
+                tree_head.keyed = self.leaf_key.is_some();
+                tree_head.salted = self.leaf_salts.is_some();
+                self.merkle_root = Some(tree_head);
+                Ok(())
+            }
+            None => {
+                eprintln!("{}", NO_DIR_MSG);
+                Err(Box::new(io::Error::other(NO_DIR_MSG)))
+            }
+        }
+    }
+
+    /// Computes the tree head from a `sha256sum` style manifest instead of
+    /// the files themselves, see `utils::parse_checksum_manifest`. The leaves
+    /// are plain content hashes, so chunking and leaf metadata are not supported,
+    /// and manifests carry no sizes, so the head has no listing root.
+    pub fn import_checksum_manifest(
+        &mut self,
+        manifest_path: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if self.chunking || !self.leaf_metadata.is_empty() {
+            return Err(Box::new(io::Error::other(
+                "Checksum manifests cannot be imported with chunking or leaf metadata",
+            )));
+        }
+
+        let hashes = utils::parse_checksum_manifest(&fs::read_to_string(manifest_path)?)?
+            .into_iter()
+            .map(|(filename, hash)| (filename, self.key_leaf(hash)));
+        let merkle_tree = self.merkle_tree(hashes);
+        let mut tree_head = merkle_tree.tree_head();
+        tree_head.keyed = self.leaf_key.is_some();
+        self.merkle_root = Some(tree_head);
+        Ok(())
+    }
+
+    /// Leaf hash of every client file under the name it is stored as, salted
+    /// if the client has drawn salts and keyed if it has a leaf key.
+    fn leaf_hashes(&self, client_files: &str) -> io::Result<BTreeMap<String, Digest>> {
+        let entries = self.listing_entries(client_files)?;
+        Ok(entries
+            .into_iter()
+            .map(|entry| (entry.filename, entry.leaf_hash))
+            .collect())
+    }
+
+    /// Every client file as the server will list it: under the name it is
+    /// stored as, with its size and its leaf hash as in `leaf_hashes`, in the
+    /// order of the stored names. Fails if a name cannot be stored, see
+    /// `stored_names`.
+    fn listing_entries(&self, client_files: &str) -> io::Result<Vec<ListingEntry>> {
+        let parsed = utils::parse_listing_entries(client_files, self.leaf_metadata, self.chunking);
+        let stored_names = stored_names(parsed.iter().map(|entry| entry.filename.as_str()))?;
+
+        let entries: BTreeMap<&String, ListingEntry> = parsed
+            .iter()
+            .map(|entry| {
+                let leaf_hash = match self.leaf_salt(&entry.filename) {
+                    Some(salt) => utils::salted_leaf_hash(&salt, &entry.leaf_hash),
+                    None => entry.leaf_hash,
+                };
+                let stored = &stored_names[&entry.filename];
+                let entry = ListingEntry {
+                    filename: stored.clone(),
+                    size: entry.size,
+                    leaf_hash: self.key_leaf(leaf_hash),
+                };
+                (stored, entry)
+            })
+            .collect();
+        Ok(entries.into_values().collect())
+    }
+
+    /// Creates a bucket on the server, failing if it already exists.
+    pub async fn create_bucket(
+        &self,
+        name: &str,
+    ) -> Result<BucketSummary, Box<dyn std::error::Error>> {
+        let response = self
+            .reqwest_client
+            .post(format!("{}/buckets", self.server_url))
+            .json(&CreateBucketRequest {
+                name: name.to_owned(),
+            })
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::OK => Ok(response.json().await?),
+            StatusCode::CONFLICT => Err(Box::new(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Bucket {} already exists", name),
+            ))),
+            StatusCode::BAD_REQUEST => Err(Box::new(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid bucket name {:?}", name),
+            ))),
+            _ => Err(Box::new(io::Error::other("Failed to create bucket"))),
+        }
+    }
+
+    pub async fn list_buckets(&self) -> Result<Vec<BucketSummary>, Box<dyn std::error::Error>> {
+        Ok(self
+            .reqwest_client
+            .get(format!("{}/buckets", self.server_url))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    }
+
+    /// Base URL of the store the client uploads to and requests from.
+    fn store_url(&self) -> String {
+        match &self.bucket {
+            Some(bucket) => format!("{}/buckets/{}", self.server_url, bucket),
+            None => self.server_url.clone(),
+        }
+    }
+
+    /// URL of `filename` under `route`, with the name percent-encoded, proving
+    /// against `root` if given, see `ProofQuery`.
+    fn file_url(
+        &self,
+        route: &str,
+        filename: &str,
+        root: Option<&Digest>,
+    ) -> Result<reqwest::Url, Box<dyn std::error::Error>> {
+        let mut url = reqwest::Url::parse(&self.store_url())?;
+        url.path_segments_mut()
+            .map_err(|_| io::Error::other("Server address cannot take a path"))?
+            .pop_if_empty()
+            .extend([route, filename]);
+        if let Some(root) = root {
+            url.query_pairs_mut().append_pair("root", &root.to_hex());
+        }
+        Ok(url)
+    }
+
+    /// Metadata to send along with an upload, only the fields the leaves
+    /// commit to and none if they commit to no metadata.
+    fn committed_metadata(&self, path: &std::path::Path) -> io::Result<Option<FileMetadata>> {
+        if self.leaf_metadata.is_empty() {
+            return Ok(None);
+        }
+        FileMetadata::read(path, self.leaf_metadata).map(Some)
+    }
+
+    fn leaf_salt(&self, filename: &str) -> Option<LeafSalt> {
+        self.leaf_salts.as_ref()?.get(filename).copied()
+    }
+
+    /// Leaf hashes to send along with uploads, only needed for keyed trees.
+    fn keyed_leaf_hashes(
+        &self,
+        client_files: &str,
+    ) -> io::Result<Option<BTreeMap<String, Digest>>> {
+        match &self.leaf_key {
+            Some(_) => self.leaf_hashes(client_files).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Keyed leaf hash to send along with the upload of `stored`, with its
+    /// tag, see `utils::keyed_leaf_tag`. Only keyed trees have them.
+    fn keyed_leaf(
+        &self,
+        keyed_leaves: Option<&BTreeMap<String, Digest>>,
+        stored: &str,
+    ) -> Option<(Digest, Digest)> {
+        let leaf_hash = *keyed_leaves?.get(stored)?;
+        let node_key = utils::node_key(self.leaf_key.as_ref()?);
+        Some((
+            leaf_hash,
+            utils::keyed_leaf_tag(&node_key, stored, &leaf_hash),
+        ))
+    }
+
+    /// Tree over leaf hashes, with its nodes keyed if the client has a leaf key.
+    fn merkle_tree<I, K>(&self, leaf_hashes: I) -> MerkleTree
+    where
+        I: IntoIterator<Item = (K, Digest)>,
+        K: AsRef<str>,
+    {
+        let leaves = leaf_hashes
+            .into_iter()
+            .map(|(key, hash)| MerkleLeaf::from_hash(Some(key.as_ref().to_owned()), hash))
+            .collect();
+        let node_key = self.leaf_key.as_deref().map(utils::node_key);
+        MerkleTree::from_leaves_with_node_key(leaves, self.arity, node_key.as_deref())
+    }
+
+    fn key_leaf(&self, leaf_hash: Digest) -> Digest {
+        match &self.leaf_key {
+            Some(key) => utils::keyed_leaf_hash(key, &leaf_hash),
+            None => leaf_hash,
+        }
+    }
+
+    /// Writes a verified file, requested as `filename`, into the client
+    /// directory and applies its committed metadata, then checks the restored
+    /// file matches it. Fails if the server answered with another file.
+    pub fn restore_file(&self, filename: &str, file_response: &FileResponse) -> io::Result<()> {
+        let client_files = match &self.client_files {
+            Some(client_files) => client_files,
+            None => {
+                eprintln!("{}", NO_DIR_MSG);
+                return Err(io::Error::other(NO_DIR_MSG));
+            }
+        };
+
+        let filename = stored_name(filename)?;
+        if file_response.filename != filename {
+            return Err(io::Error::other(format!(
+                "Server returned {} for {}",
+                file_response.filename, filename
+            )));
+        }
+        fs::create_dir_all(client_files)?;
+        let path = std::path::Path::new(client_files).join(filename);
+        fs::write(&path, &file_response.content)?;
+
+        match &file_response.metadata {
+            Some(metadata) => MerkleClient::apply_metadata(&path, metadata),
+            None => Ok(()),
+        }
+    }
+
+    /// Streams a chunked file into the client directory, verifying its proof
+    /// before any data is written and each chunk as it arrives. Nothing is
+    /// left behind if the stream fails verification. Returns the file length.
+    pub async fn stream_file(
+        &self,
+        filename: &str,
+        tree_head: &TreeHead,
+        leaf_key: Option<&[u8]>,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let client_files = match &self.client_files {
+            Some(client_files) => client_files,
+            None => {
+                eprintln!("{}", NO_DIR_MSG);
+                return Err(Box::new(io::Error::other(NO_DIR_MSG)));
+            }
+        };
+        let filename = &stored_name(filename)?;
+        let url = self.file_url("stream", filename, Some(&tree_head.root_hash))?;
+        let mut response = self.reqwest_client.get(url).send().await?;
+        if response.status() != StatusCode::OK {
+            return Err(Box::new(io::Error::other(
+                "Failed to stream file from server",
+            )));
+        }
+
+        let mut buffer = Vec::new();
+        let (header, header_len) = loop {
+            if let Some(header) = streaming::decode_header(&buffer)? {
+                break header;
+            }
+            match response.chunk().await? {
+                Some(data) => buffer.extend_from_slice(&data),
+                None => return Err(Box::new(io::Error::from(io::ErrorKind::UnexpectedEof))),
+            }
+        };
+        if &header.file.filename != filename
+            || !utils::verify_file_proof_for_content_hash(
+                &header.file,
+                header.chunk_root,
+                tree_head,
+                leaf_key,
+            )
+        {
+            return Err(Box::new(io::Error::other("Server proof is invalid")));
+        }
+
+        fs::create_dir_all(client_files)?;
+        let path = std::path::Path::new(client_files).join(filename);
+        let partial_path = std::path::Path::new(client_files).join(format!("{}.partial", filename));
+        let result = async {
+            let mut out = io::BufWriter::new(fs::File::create(&partial_path)?);
+            let mut decoder = StreamDecoder::new(header.chunk_root, header.chunk_count)?;
+            decoder.update(&buffer[header_len..], &mut out)?;
+            while let Some(data) = response.chunk().await? {
+                decoder.update(&data, &mut out)?;
+            }
+            out.flush()?;
+            let size = decoder.finish()?;
+            if let Some(committed) = header.file.metadata.as_ref().and_then(|m| m.size) {
+                if committed != size {
+                    return Err(io::Error::other("Streamed file does not match its size").into());
+                }
+            }
+            Ok::<u64, Box<dyn std::error::Error>>(size)
+        }
+        .await;
+
+        match result {
+            Ok(size) => {
+                fs::rename(&partial_path, &path)?;
+                if let Some(metadata) = &header.file.metadata {
+                    MerkleClient::apply_metadata(&path, metadata)?;
+                }
+                Ok(size)
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&partial_path);
+                Err(e)
+            }
+        }
+    }
+
+    fn apply_metadata(path: &std::path::Path, metadata: &FileMetadata) -> io::Result<()> {
+        metadata.apply(path)?;
+        if &FileMetadata::read(path, metadata.fields())? != metadata {
+            return Err(io::Error::other(format!(
+                "Restored file {:?} does not match its committed metadata",
+                path
+            )));
+        }
+        Ok(())
+    }
+
+    async fn upload_file(
+        client: &reqwest::Client,
+        path: &std::path::Path,
+        filename: String,
+        base_url: &str,
+        metadata: Option<FileMetadata>,
+        keyed_leaf: Option<(Digest, Digest)>,
+        salt: Option<LeafSalt>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let content = fs::read(path)?;
+        let base64_content = general_purpose::STANDARD.encode(&content);
+
+        let payload = UploadRequest {
+            filename,
+            content: base64_content,
+            metadata,
+            leaf_hash: keyed_leaf.map(|(leaf_hash, _)| leaf_hash),
+            leaf_tag: keyed_leaf.map(|(_, leaf_tag)| leaf_tag),
+            salt,
+        };
+
+        client
+            .post(base_url)
+            .json(&payload)
+            .send()
+            .await?
+            .error_for_status()?;
+        println!("Successfully uploaded: {:?}", path);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_initialization() {
+        let client = MerkleClient::new(
+            "http://localhost:8000",
+            reqwest::Client::new(),
+            Some("path/to/files".to_owned()),
+            "path/to/merkle_root".to_owned(),
+        );
+        assert_eq!(client.server_url, "http://localhost:8000");
+        assert_eq!(client.client_files, Some("path/to/files".to_owned()));
+        assert_eq!(client.merkle_root_path, "path/to/merkle_root".to_owned());
+    }
+
+    #[test]
+    fn test_read_write_merkle_root() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let merkle_path = temp_dir.path().join("merkle_root");
+        let mut client = MerkleClient::new(
+            "http://localhost:8000",
+            reqwest::Client::new(),
+            Some("path/to/files".to_owned()),
+            merkle_path.to_str().unwrap().to_string(),
+        );
+
+        let tree_head = TreeHead::new(Digest::new([1; 32]), 2, 2);
+        client.merkle_root = Some(tree_head.clone());
+        let write_result = client.write_merkle_root_to_disk();
+        assert!(write_result.is_ok());
+
+        let read_result = client.read_merkle_root_from_disk().unwrap();
+        assert_eq!(read_result, tree_head);
+    }
+
+    #[test]
+    fn test_compute_merkle_root_for_small_collections() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let files_path = temp_dir.path().to_str().unwrap().to_string();
+        let mut client = MerkleClient::new(
+            "http://localhost:8000",
+            reqwest::Client::new(),
+            Some(files_path),
+            "path/to/merkle_root".to_owned(),
+        );
+
+        client.compute_merkle_root_from_files().unwrap();
+        let tree_head = client.merkle_root.clone().unwrap();
+        assert_eq!(tree_head.root_hash, MerkleTree::EMPTY_ROOT);
+        assert_eq!(tree_head.leaf_count, 0);
+
+        fs::write(temp_dir.path().join("only.txt"), b"only file").unwrap();
+        client.compute_merkle_root_from_files().unwrap();
+        let tree_head = client.merkle_root.clone().unwrap();
+        assert_eq!(
+            tree_head.root_hash,
+            MerkleTree::from_items([b"only file"]).get_root_hash()
+        );
+        assert_eq!(tree_head.leaf_count, 1);
+    }
+
+    #[test]
+    fn test_reject_names_that_cannot_be_stored() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut client = MerkleClient::new(
+            "http://localhost:8000",
+            reqwest::Client::new(),
+            Some(temp_dir.path().to_str().unwrap().to_string()),
+            "path/to/merkle_root".to_owned(),
+        );
+
+        fs::write(temp_dir.path().join("caf\u{e9}.txt"), b"composed").unwrap();
+        client.compute_merkle_root_from_files().unwrap();
+
+        fs::write(temp_dir.path().join("cafe\u{301}.txt"), b"decomposed").unwrap();
+        assert!(client.compute_merkle_root_from_files().is_err());
+
+        fs::remove_file(temp_dir.path().join("cafe\u{301}.txt")).unwrap();
+        fs::write(temp_dir.path().join("trailing space "), b"invalid").unwrap();
+        assert!(client.compute_merkle_root_from_files().is_err());
+    }
+
+    #[test]
+    fn test_store_leaf_salts_with_merkle_root() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let files_path = temp_dir.path().join("files");
+        fs::create_dir(&files_path).unwrap();
+        fs::write(files_path.join("a.txt"), b"yes").unwrap();
+        fs::write(files_path.join("b.txt"), b"no").unwrap();
+        let mut client = MerkleClient::new(
+            "http://localhost:8000",
+            reqwest::Client::new(),
+            Some(files_path.to_str().unwrap().to_owned()),
+            temp_dir
+                .path()
+                .join("merkle.bin")
+                .to_str()
+                .unwrap()
+                .to_owned(),
+        );
+        client.compute_merkle_root_from_files().unwrap();
+        let unsalted = client.merkle_root.take().unwrap();
+
+        client.generate_leaf_salts().unwrap();
+        client.compute_merkle_root_from_files().unwrap();
+        client.write_leaf_salts_to_disk().unwrap();
+
+        let salted = client.merkle_root.clone().unwrap();
+        assert!(salted.salted);
+        assert_ne!(salted.root_hash, unsalted.root_hash);
+        let salts = client.read_leaf_salts_from_disk().unwrap();
+        assert_eq!(Some(&salts), client.leaf_salts.as_ref());
+        assert_eq!(salts.len(), 2);
+    }
+
+    #[test]
+    fn test_import_checksum_manifest() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let files_path = temp_dir.path().join("files");
+        fs::create_dir(&files_path).unwrap();
+        let mut manifest = String::new();
+        for i in 0..5 {
+            let content = format!("File {} contents", i);
+            fs::write(files_path.join(format!("file{}.txt", i)), &content).unwrap();
+            manifest.push_str(&format!("{}  file{}.txt\n", Digest::of(content), i));
+        }
+        let manifest_path = temp_dir.path().join("SHA256SUMS");
+        fs::write(&manifest_path, manifest).unwrap();
+
+        let mut client = MerkleClient::new(
+            "http://localhost:8000",
+            reqwest::Client::new(),
+            Some(files_path.to_str().unwrap().to_owned()),
+            "path/to/merkle_root".to_owned(),
+        );
+        client.arity = 4;
+        client.compute_merkle_root_from_files().unwrap();
+        let from_files = client.merkle_root.take().unwrap();
+
+        client
+            .import_checksum_manifest(manifest_path.to_str().unwrap())
+            .unwrap();
+        let imported = client.merkle_root.clone().unwrap();
+        assert_eq!(imported.root_hash, from_files.root_hash);
+        assert_eq!((imported.leaf_count, imported.arity), (5, 4));
+
+        client.chunking = true;
+        assert!(client
+            .import_checksum_manifest(manifest_path.to_str().unwrap())
+            .is_err());
+    }
+
+    #[test]
+    fn test_restore_file_with_metadata() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let files_path = temp_dir.path().join("restored");
+        let client = MerkleClient::new(
+            "http://localhost:8000",
+            reqwest::Client::new(),
+            Some(files_path.to_str().unwrap().to_string()),
+            "path/to/merkle_root".to_owned(),
+        );
+
+        let metadata = FileMetadata {
+            size: Some(11),
+            mode: Some(0o600),
+            mtime: Some(1_600_000_000),
+        };
+        let mut file_response =
+            FileResponse::new("hello.txt".to_owned(), b"Hello World".to_vec(), Vec::new());
+        file_response.metadata = Some(metadata.clone());
+
+        client.restore_file("hello.txt", &file_response).unwrap();
+
+        let restored = files_path.join("hello.txt");
+        assert_eq!(fs::read(&restored).unwrap(), b"Hello World");
+        assert_eq!(
+            FileMetadata::read(&restored, MetadataFields::ALL).unwrap(),
+            metadata
+        );
+
+        assert!(client.restore_file("other.txt", &file_response).is_err());
+        assert!(!files_path.join("other.txt").exists());
+
+        file_response.metadata.as_mut().unwrap().size = Some(12);
+        assert!(client.restore_file("hello.txt", &file_response).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_keep_files_after_failed_upload() {
+        use std::io::{BufRead, BufReader};
+
+        // Answers every request with 404, like the server for an unknown bucket
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut request_line = String::new();
+            BufReader::new(&stream)
+                .read_line(&mut request_line)
+                .unwrap();
+            let mut stream = stream;
+            stream
+                .write_all(
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+            request_line
+        });
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let files_path = temp_dir.path().join("files");
+        fs::create_dir(&files_path).unwrap();
+        fs::write(files_path.join("hello.txt"), "Hello World").unwrap();
+        let mut client = MerkleClient::new(
+            &format!("http://{}", address),
+            reqwest::Client::new(),
+            Some(files_path.to_str().unwrap().to_string()),
+            temp_dir
+                .path()
+                .join("merkle_root")
+                .to_str()
+                .unwrap()
+                .to_owned(),
+        );
+        client.bucket = Some("unknown".to_owned());
+
+        assert!(client.upload_all_files_to_server().await.is_err());
+        assert!(requests
+            .join()
+            .unwrap()
+            .starts_with("POST /buckets/unknown/upload "));
+        assert_eq!(
+            fs::read(files_path.join("hello.txt")).unwrap(),
+            b"Hello World"
+        );
+        assert!(!temp_dir.path().join("merkle_root").exists());
+    }
+}
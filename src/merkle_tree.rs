@@ -1,12 +1,18 @@
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
-use std::{collections::BTreeMap, rc::Rc};
+use std::{collections::HashMap, ops::Range, sync::Arc};
 
+use crate::digest::Digest;
+use crate::tree_head::TreeHead;
+
+/// A node of the tree. Leaves hash as `Digest::of_leaf` of their leaf hash
+/// and internal nodes as `Digest::of_children`, so that the two never collide.
 #[derive(Clone, Debug)]
 pub struct MerkleNode {
-    pub hash: Vec<u8>,
-    pub left: Option<Rc<MerkleNode>>,
-    pub right: Option<Rc<MerkleNode>>,
+    pub hash: Digest,
+    /// Empty for leaves, otherwise between 2 and the tree's arity children.
+    pub children: Vec<Arc<MerkleNode>>,
+    /// The leaf hash a leaf node was built from, `None` for internal nodes.
+    pub leaf_hash: Option<Digest>,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -15,21 +21,206 @@ pub enum NodeOrder {
     Left,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct ProofListItem {
-    pub hash: Vec<u8>,
+    pub hash: Digest,
     pub order: Option<NodeOrder>,
 }
 
 impl ProofListItem {
-    pub fn new(hash: Vec<u8>, order: Option<NodeOrder>) -> Self {
+    pub fn new(hash: Digest, order: Option<NodeOrder>) -> Self {
         ProofListItem { hash, order }
     }
 }
 
+/// The children of one node on the path from a leaf to the root, with the
+/// position of the path node among them. Used for trees of any arity.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProofGroup {
+    pub position: usize,
+    pub hashes: Vec<Digest>,
+}
+
+/// A proof in the format matching the tree's arity, see
+/// `MerkleTree::generate_proof`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MerkleProof {
+    List(Vec<ProofListItem>),
+    Group(Vec<ProofGroup>),
+}
+
+/// A proof that binds the leaf's position: the siblings on the path from the
+/// leaf up, without the leaf itself. Which siblings form each group, and where
+/// the path node sits among them, follows from `leaf_index` and `tree_size`,
+/// so the proof only verifies for the position it claims.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct IndexedProof {
+    pub leaf_index: u64,
+    pub tree_size: u64,
+    pub siblings: Vec<Digest>,
+}
+
+/// A proof for the leaves `start..start + n` of a tree of `tree_size` leaves,
+/// given the `n` leaf hashes. It carries only the hashes of nodes outside the
+/// span that share a group with a node inside it, so a span costs about as
+/// much as proving its two ends.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RangeProof {
+    pub start: u64,
+    pub tree_size: u64,
+    pub siblings: Vec<Digest>,
+}
+
+/// Hashes the nodes `start..start + span.len()` of a level with `level_size`
+/// nodes up to the root. For the leaf level, `span` holds the leaves' node
+/// hashes, see `Digest::of_leaf`. `sibling` is asked for every node outside the span
+/// that shares a group with one inside it, by level and index, level by level
+/// and in the order the groups are built. Returns `None` for an empty span, a
+/// span past the end of the level, or when `sibling` does.
+pub(crate) fn fold_range<F>(
+    level_size: usize,
+    arity: usize,
+    start: usize,
+    span: Vec<Digest>,
+    mut sibling: F,
+) -> Option<Digest>
+where
+    F: FnMut(usize, usize) -> Option<Digest>,
+{
+    if span.is_empty() || start.checked_add(span.len())? > level_size {
+        return None;
+    }
+
+    let (mut level, mut level_size, mut start, mut span) = (0, level_size, start, span);
+    while level_size > 1 {
+        let end = start + span.len();
+        // Groups are counted from the end of the level, so the last node of
+        // the span falls in the first group
+        let first_group = (level_size - end) / arity;
+        let last_group = (level_size - 1 - start) / arity;
+
+        let mut parents = Vec::with_capacity(last_group - first_group + 1);
+        for group in first_group..=last_group {
+            let group_size = (level_size - group * arity).min(arity);
+            let mut hashes = Vec::with_capacity(group_size);
+            for position in 0..group_size {
+                let index = level_size - 1 - (group * arity + position);
+                if (start..end).contains(&index) {
+                    hashes.push(span[index - start]);
+                } else {
+                    hashes.push(sibling(level, index)?);
+                }
+            }
+            parents.push(match hashes[..] {
+                [promoted] => promoted,
+                _ => Digest::of_children(&hashes),
+            });
+        }
+
+        level += 1;
+        level_size = level_size.div_ceil(arity);
+        start = first_group;
+        span = parents;
+    }
+    span.pop()
+}
+
+/// Builds the levels above `nodes` up to the root, grouping up to `arity`
+/// nodes per parent from the end of each level. `existing` is asked for a
+/// parent already built over the same children before a group is hashed.
+fn build_root<F>(
+    mut nodes: Vec<Arc<MerkleNode>>,
+    arity: usize,
+    node_key: Option<&[u8]>,
+    mut existing: F,
+) -> MerkleNode
+where
+    F: FnMut(&[Arc<MerkleNode>]) -> Option<Arc<MerkleNode>>,
+{
+    while nodes.len() > 1 {
+        let mut next_level = Vec::new();
+        while !nodes.is_empty() {
+            let group: Vec<Arc<MerkleNode>> = nodes
+                .drain(nodes.len().saturating_sub(arity)..)
+                .rev()
+                .collect();
+            if group.len() == 1 {
+                next_level.extend(group);
+            } else {
+                let parent = existing(&group)
+                    .unwrap_or_else(|| Arc::new(MerkleNode::parent(group, node_key)));
+                next_level.push(parent);
+            }
+        }
+        nodes = next_level;
+    }
+    nodes
+        .pop()
+        .map(|root| Arc::try_unwrap(root).unwrap_or_else(|root| (*root).clone()))
+        .unwrap_or_else(|| MerkleNode {
+            hash: MerkleTree::EMPTY_ROOT,
+            children: Vec::new(),
+            leaf_hash: None,
+        })
+}
+
+/// Position within its group and group size of each node on the path from the
+/// node at `index` of a level with `level_size` nodes up to the root. Levels
+/// where the node is promoted without a parent of its own are skipped.
+pub(crate) fn path_groups(level_size: usize, arity: usize, index: usize) -> Vec<(usize, usize)> {
+    let mut groups = Vec::new();
+    let mut index = index;
+    let mut level_size = level_size;
+    while level_size > 1 {
+        let reversed = level_size - 1 - index;
+        let group = reversed / arity;
+        let group_size = (level_size - group * arity).min(arity);
+        if group_size > 1 {
+            groups.push((reversed % arity, group_size));
+        }
+        index = group;
+        level_size = level_size.div_ceil(arity);
+    }
+    groups
+}
+
+/// Index of the node of a level with `level_size` nodes whose path to the
+/// root has exactly `groups`, bottom up as returned by `path_groups`. `None`
+/// if no node has that path, e.g. for a proof too short or too long for a
+/// tree of that size.
+pub(crate) fn path_index(
+    level_size: usize,
+    arity: usize,
+    groups: &[(usize, usize)],
+) -> Option<usize> {
+    if level_size == 0 {
+        return None;
+    }
+    let mut sizes = vec![level_size];
+    while let Some(&size) = sizes.last().filter(|&&size| size > 1) {
+        sizes.push(size.div_ceil(arity));
+    }
+
+    let mut groups = groups.iter().rev();
+    let mut index = 0;
+    for &size in sizes.iter().rev().skip(1) {
+        // The parent's index counts its group from the end of this level
+        let group_size = (size - index * arity).min(arity);
+        let position = match group_size {
+            1 => 0,
+            _ => match groups.next()? {
+                &(position, size) if size == group_size && position < size => position,
+                _ => return None,
+            },
+        };
+        index = size - 1 - (index * arity + position);
+    }
+    groups.next().is_none().then_some(index)
+}
+
 impl fmt::Debug for ProofListItem {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let hash_str = hex::encode(&self.hash[0..3]);
+        let hash_str = self.hash.short_hex();
         match &self.order {
             Some(NodeOrder::Right) => write!(f, "h: {} Right", hash_str),
             Some(NodeOrder::Left) => write!(f, "h: {} Left", hash_str),
@@ -38,30 +229,72 @@ impl fmt::Debug for ProofListItem {
     }
 }
 
+/// A leaf of the tree, optionally addressed by a key (e.g. a file name).
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerkleLeaf {
+    pub key: Option<String>,
+    pub hash: Digest,
+}
+
+impl MerkleLeaf {
+    pub fn new<T: AsRef<[u8]>>(key: Option<String>, data: T) -> Self {
+        MerkleLeaf {
+            key,
+            hash: Digest::of(data),
+        }
+    }
+
+    /// A leaf whose hash was computed elsewhere, e.g. the root of a chunk tree.
+    pub fn from_hash(key: Option<String>, hash: Digest) -> Self {
+        MerkleLeaf { key, hash }
+    }
+}
+
 impl MerkleNode {
-    fn new(data: &Vec<u8>) -> Self {
-        let hash = Sha256::digest(data).to_vec();
+    fn leaf(leaf_hash: Digest) -> Self {
         MerkleNode {
-            hash,
-            left: None,
-            right: None,
+            hash: Digest::of_leaf(&leaf_hash),
+            children: Vec::new(),
+            leaf_hash: Some(leaf_hash),
         }
     }
 
     pub fn combine(left: &MerkleNode, right: &MerkleNode) -> Self {
-        let combined = [&left.hash[..], &right.hash[..]].concat();
-        let hash = Sha256::digest(combined).to_vec();
+        MerkleNode::combine_group(vec![Arc::new(left.clone()), Arc::new(right.clone())])
+    }
+
+    /// Creates the parent of `children`, hashing their hashes in order.
+    pub fn combine_group(children: Vec<Arc<MerkleNode>>) -> Self {
+        MerkleNode::parent(children, None)
+    }
+
+    /// Like `combine_group`, hashing under `node_key` if there is one.
+    fn parent(children: Vec<Arc<MerkleNode>>, node_key: Option<&[u8]>) -> Self {
         MerkleNode {
-            hash,
-            left: Some(Rc::new(left.clone())),
-            right: Some(Rc::new(right.clone())),
+            hash: Digest::of_keyed_children(node_key, children.iter().map(|child| &child.hash)),
+            children,
+            leaf_hash: None,
         }
     }
+
+    pub fn left(&self) -> Option<&Arc<MerkleNode>> {
+        self.children.first()
+    }
+
+    pub fn right(&self) -> Option<&Arc<MerkleNode>> {
+        self.children.get(1)
+    }
 }
 
+/// Leaf hashes live in the tree's bottom nodes and keys in a list shared by
+/// every tree derived with `with_leaf_hash`, so a new version copies neither.
 #[derive(Clone, Debug)]
 pub struct MerkleTree {
     pub root: MerkleNode,
+    keys: Arc<[Option<String>]>,
+    arity: usize,
+    /// Secret internal nodes are hashed under, see `utils::node_key`.
+    node_key: Option<Arc<[u8]>>,
 }
 
 use std::fmt;
@@ -71,19 +304,20 @@ impl fmt::Display for MerkleNode {
         // Helper function to recursively print nodes with indentation
         fn print_node(node: &MerkleNode, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
             // Convert the first few bytes of the hash to a hex string for brevity
-            let short_hash = hex::encode(&node.hash[0..3]);
+            let short_hash = node.hash.short_hex();
 
-            // Recursively print left child first
-            if let Some(left) = &node.left {
-                print_node(left, f, depth + 1)?;
+            // Recursively print the first half of the children (left) first
+            let (before, after) = node.children.split_at(node.children.len().div_ceil(2));
+            for child in before {
+                print_node(child, f, depth + 1)?;
             }
 
             // Print the current node's hash with proper indentation
             writeln!(f, "{}{}", "        ".repeat(depth), short_hash)?;
 
-            // Recursively print right child
-            if let Some(right) = &node.right {
-                print_node(right, f, depth + 1)?;
+            // Recursively print the remaining (right) children
+            for child in after {
+                print_node(child, f, depth + 1)?;
             }
             Ok(())
         }
@@ -99,116 +333,496 @@ impl fmt::Display for MerkleTree {
 }
 
 impl MerkleTree {
-    pub fn new(files: &BTreeMap<String, Vec<u8>>) -> Self {
-        let mut nodes = Vec::new();
-        for file in files {
-            nodes.push(MerkleNode::new(file.1));
+    /// Root of a tree without leaves, `SHA-256(0x02 || "empty")`. Domain
+    /// separated so that it differs from the root of a tree holding one empty file.
+    pub const EMPTY_ROOT: Digest = Digest::new([
+        0x84, 0x08, 0x50, 0x73, 0x48, 0x21, 0xdb, 0x93, 0xd0, 0x57, 0x8d, 0x22, 0x40, 0x07, 0xed,
+        0x37, 0x0d, 0x3f, 0x7e, 0x14, 0x9f, 0x18, 0x84, 0x17, 0x9c, 0xda, 0xa8, 0x71, 0xe1, 0x54,
+        0x09, 0x6a,
+    ]);
+
+    /// Number of children per node that trees can be built with.
+    pub const SUPPORTED_ARITIES: [usize; 4] = [2, 4, 8, 16];
+
+    /// Builds a tree over keyed items, e.g. a `BTreeMap` of file names to contents.
+    /// Items are used in iteration order, so the collection should be ordered.
+    pub fn new<I, K, V>(items: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<[u8]>,
+    {
+        let leaves = items
+            .into_iter()
+            .map(|(key, value)| MerkleLeaf::new(Some(key.as_ref().to_owned()), value))
+            .collect();
+        MerkleTree::from_leaves(leaves)
+    }
+
+    /// Builds a tree over unkeyed items such as database rows or log entries.
+    /// Proofs for these are generated by index.
+    pub fn from_items<I, V>(items: I) -> Self
+    where
+        I: IntoIterator<Item = V>,
+        V: AsRef<[u8]>,
+    {
+        let leaves = items
+            .into_iter()
+            .map(|value| MerkleLeaf::new(None, value))
+            .collect();
+        MerkleTree::from_leaves(leaves)
+    }
+
+    /// Builds a keyed tree whose nodes have up to `arity` children.
+    pub fn new_with_arity<I, K, V>(items: I, arity: usize) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<[u8]>,
+    {
+        let leaves = items
+            .into_iter()
+            .map(|(key, value)| MerkleLeaf::new(Some(key.as_ref().to_owned()), value))
+            .collect();
+        MerkleTree::from_leaves_with_arity(leaves, arity)
+    }
+
+    /// Builds a keyed tree from leaf hashes computed elsewhere, e.g. the
+    /// entries of a checksum manifest, without reading any content.
+    pub fn from_leaf_hashes<I, K>(items: I) -> Self
+    where
+        I: IntoIterator<Item = (K, Digest)>,
+        K: AsRef<str>,
+    {
+        MerkleTree::from_leaf_hashes_with_arity(items, 2)
+    }
+
+    pub fn from_leaf_hashes_with_arity<I, K>(items: I, arity: usize) -> Self
+    where
+        I: IntoIterator<Item = (K, Digest)>,
+        K: AsRef<str>,
+    {
+        let leaves = items
+            .into_iter()
+            .map(|(key, hash)| MerkleLeaf::from_hash(Some(key.as_ref().to_owned()), hash))
+            .collect();
+        MerkleTree::from_leaves_with_arity(leaves, arity)
+    }
+
+    pub fn from_leaves(leaves: Vec<MerkleLeaf>) -> Self {
+        MerkleTree::from_leaves_with_arity(leaves, 2)
+    }
+
+    /// Groups up to `arity` nodes per parent, taking nodes from the end of each
+    /// level. A node left alone at the end of a level is promoted unchanged.
+    ///
+    /// Panics if `arity` is not one of `SUPPORTED_ARITIES`.
+    pub fn from_leaves_with_arity(leaves: Vec<MerkleLeaf>, arity: usize) -> Self {
+        MerkleTree::from_leaves_with_node_key(leaves, arity, None)
+    }
+
+    /// Like `from_leaves_with_arity`, hashing internal nodes under `node_key`,
+    /// see `utils::node_key`. Versions derived from the tree keep the key.
+    pub fn from_leaves_with_node_key(
+        leaves: Vec<MerkleLeaf>,
+        arity: usize,
+        node_key: Option<&[u8]>,
+    ) -> Self {
+        assert!(
+            MerkleTree::SUPPORTED_ARITIES.contains(&arity),
+            "Unsupported tree arity {}",
+            arity
+        );
+
+        let nodes = leaves
+            .iter()
+            .map(|leaf| Arc::new(MerkleNode::leaf(leaf.hash)))
+            .collect();
+        MerkleTree {
+            root: build_root(nodes, arity, node_key, |_| None),
+            keys: leaves.into_iter().map(|leaf| leaf.key).collect(),
+            arity,
+            node_key: node_key.map(Arc::from),
         }
+    }
+
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    /// Leaves in insertion order, collected from the tree's bottom level.
+    pub fn leaves(&self) -> Vec<MerkleLeaf> {
+        self.iter_leaves().map(|(_, leaf)| leaf).collect()
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Hash of the leaf at `index`, in insertion order.
+    pub fn leaf_hash(&self, index: usize) -> Option<Digest> {
+        self.node_at(0, index)?.leaf_hash
+    }
+
+    /// Keys of the leaves in insertion order, `None` for unkeyed leaves.
+    pub fn keys(&self) -> impl Iterator<Item = Option<&str>> {
+        self.keys.iter().map(|key| key.as_deref())
+    }
+
+    /// Number of levels above the leaves, 0 for trees of up to one leaf.
+    pub fn depth(&self) -> usize {
+        self.level_sizes().len() - 1
+    }
+
+    /// Index of the leaf stored under `key`, in insertion order.
+    pub fn leaf_index(&self, key: &str) -> Option<usize> {
+        self.keys().position(|leaf_key| leaf_key == Some(key))
+    }
+
+    /// Leaves with their indices, in insertion order.
+    pub fn iter_leaves(&self) -> impl Iterator<Item = (usize, MerkleLeaf)> + '_ {
+        let nodes = self.levels().next().unwrap_or_default();
+        self.keys
+            .iter()
+            .zip(nodes)
+            .filter_map(|(key, node)| Some(MerkleLeaf::from_hash(key.clone(), node.leaf_hash?)))
+            .enumerate()
+    }
+
+    /// Number of nodes per level, from the leaves up to the root.
+    fn level_sizes(&self) -> Vec<usize> {
+        let mut sizes = vec![self.leaf_count()];
+        while let Some(&size) = sizes.last().filter(|&&size| size > 1) {
+            sizes.push(size.div_ceil(self.arity));
+        }
+        sizes
+    }
 
-        while nodes.len() > 1 {
-            let mut next_level = Vec::new();
-            while let Some(left) = nodes.pop() {
-                if let Some(right) = nodes.pop() {
-                    next_level.push(MerkleNode::combine(&left, &right));
+    /// Node at `index` of `level`, where level 0 holds the leaves in insertion
+    /// order and each level above holds the groups of the one below, counted
+    /// from the end as the tree is built. Promoted nodes appear on every level
+    /// they are carried through.
+    pub fn node_at(&self, level: usize, index: usize) -> Option<&MerkleNode> {
+        let mut node = &self.root;
+        for position in self.path_positions(level, index)? {
+            node = node.children.get(position)?;
+        }
+        Some(node)
+    }
+
+    /// Levels from the leaves up to the root, indexed like `node_at`.
+    pub fn levels(&self) -> impl Iterator<Item = Vec<&MerkleNode>> {
+        let sizes = self.level_sizes();
+        let mut levels = Vec::new();
+        if self.leaf_count() > 0 {
+            levels.push(vec![&self.root]);
+        }
+        for &size in sizes.iter().rev().skip(1) {
+            let parents = levels.last().unwrap();
+            let mut nodes: Vec<&MerkleNode> = Vec::with_capacity(size);
+            for (group, parent) in parents.iter().enumerate() {
+                if size - group * self.arity == 1 {
+                    nodes.push(parent);
                 } else {
-                    next_level.push(left);
+                    nodes.extend(parent.children.iter().map(|child| child.as_ref()));
                 }
             }
-            nodes = next_level;
+            // Groups are taken from the end of the level, last node first
+            nodes.reverse();
+            levels.push(nodes);
         }
-        MerkleTree {
-            root: nodes.pop().unwrap(),
+        levels.into_iter().rev()
+    }
+
+    /// Every node once, parents before their children.
+    pub fn nodes(&self) -> impl Iterator<Item = &MerkleNode> {
+        let mut stack = Vec::new();
+        if self.leaf_count() > 0 {
+            stack.push(&self.root);
         }
+        std::iter::from_fn(move || {
+            let node = stack.pop()?;
+            stack.extend(node.children.iter().rev().map(|child| child.as_ref()));
+            Some(node)
+        })
     }
 
-    #[allow(dead_code)]
-    pub fn get_root_hash(&self) -> Vec<u8> {
-        self.root.hash.clone()
+    pub fn get_root_hash(&self) -> Digest {
+        self.root.hash
     }
 
-    #[allow(dead_code)]
-    pub fn generate_merkle_proof(
-        &self,
-        file_name: &str,
-        files: &BTreeMap<String, Vec<u8>>,
-    ) -> Option<Vec<ProofListItem>> {
-        let mut current_node = Some(Rc::new(self.root.clone()));
-
-        let file_contents = files.get(file_name)?;
-        let target_hash: Vec<u8> = Sha256::digest(file_contents).to_vec();
-        let mut proof_list: Vec<ProofListItem> = Vec::new();
-
-        while let Some(ref node) = current_node {
-            match self.find_target_relative_to_node(node.as_ref(), &target_hash) {
-                Some(NodeOrder::Right) => {
-                    if let Some(left) = &node.left {
-                        if let Some(right) = &node.right {
-                            // If we are at the end add both leaves (one is the target)
-                            if left.hash == target_hash || right.hash == target_hash {
-                                proof_list.push(ProofListItem::new(
-                                    right.hash.clone(),
-                                    Some(NodeOrder::Right),
-                                ));
-                            }
-
-                            proof_list
-                                .push(ProofListItem::new(left.hash.clone(), Some(NodeOrder::Left)));
-                            current_node = Some(Rc::clone(right));
-                        }
-                    }
-                }
-                Some(NodeOrder::Left) => {
-                    if let Some(right) = &node.right {
-                        if let Some(left) = &node.left {
-                            // If we are at the end add both leaves (one is the target)
-                            if left.hash == target_hash || right.hash == target_hash {
-                                proof_list.push(ProofListItem::new(
-                                    left.hash.clone(),
-                                    Some(NodeOrder::Left),
-                                ));
-                            }
-
-                            proof_list.push(ProofListItem::new(
-                                right.hash.clone(),
-                                Some(NodeOrder::Right),
-                            ));
-                            current_node = Some(Rc::clone(left));
-                        }
-                    }
-                }
-                None => break,
+    /// Root hash together with the leaf count and layout it was computed with.
+    pub fn tree_head(&self) -> TreeHead {
+        TreeHead::new(self.get_root_hash(), self.leaf_count() as u64, self.arity)
+    }
+
+    /// Generates the proof for the leaf stored under `key`, a list proof for
+    /// binary trees and a group proof otherwise.
+    pub fn generate_proof(&self, key: &str) -> Option<MerkleProof> {
+        self.generate_proof_for_index(self.leaf_index(key)?)
+    }
+
+    /// Like `generate_proof`, for the leaf at `index`. Proofs are built along
+    /// the leaf's path by position, so each costs only the tree's depth.
+    pub fn generate_proof_for_index(&self, index: usize) -> Option<MerkleProof> {
+        if self.arity == 2 {
+            self.generate_merkle_proof_for_index(index)
+                .map(MerkleProof::List)
+        } else {
+            self.generate_group_proof_for_index(index)
+                .map(MerkleProof::Group)
+        }
+    }
+
+    /// Generates a proof for the leaf stored under `key`.
+    pub fn generate_merkle_proof(&self, key: &str) -> Option<Vec<ProofListItem>> {
+        let index = self.leaf_index(key)?;
+        self.generate_merkle_proof_for_index(index)
+    }
+
+    /// Generates a proof for the leaf at `index`, in insertion order.
+    /// Only binary trees have list proofs, see `generate_group_proof_for_index`.
+    pub fn generate_merkle_proof_for_index(&self, index: usize) -> Option<Vec<ProofListItem>> {
+        if self.arity != 2 {
+            return None;
+        }
+
+        let positions = self.path_positions(0, index)?;
+        let order = |position| match position {
+            0 => NodeOrder::Left,
+            _ => NodeOrder::Right,
+        };
+
+        let mut proof_list = Vec::with_capacity(positions.len() + 1);
+        let mut node = &self.root;
+        for (depth, &position) in positions.iter().enumerate() {
+            let child = node.children.get(position)?;
+            let sibling = node.children.get(1 - position)?;
+            // The proof ends with the leaf's own pair, the leaf first
+            if depth + 1 == positions.len() {
+                proof_list.push(ProofListItem::new(child.hash, Some(order(position))));
             }
+            proof_list.push(ProofListItem::new(sibling.hash, Some(order(1 - position))));
+            node = child;
         }
 
+        // A single leaf is the root, so the proof is just the leaf's node
         if proof_list.is_empty() {
-            return None;
+            proof_list.push(ProofListItem::new(node.hash, None));
         }
 
         Some(proof_list)
     }
 
+    /// Generates a proof of the sibling groups on the path from the leaf stored
+    /// under `key` to the root.
+    pub fn generate_group_proof(&self, key: &str) -> Option<Vec<ProofGroup>> {
+        let index = self.leaf_index(key)?;
+        self.generate_group_proof_for_index(index)
+    }
+
+    /// Generates a group proof for the leaf at `index`, ordered from the leaf
+    /// level up to the root. A single-leaf tree has an empty proof.
+    pub fn generate_group_proof_for_index(&self, index: usize) -> Option<Vec<ProofGroup>> {
+        let mut proof = Vec::new();
+        let mut node = &self.root;
+        for position in self.path_positions(0, index)? {
+            proof.push(ProofGroup {
+                position,
+                hashes: node.children.iter().map(|c| c.hash).collect(),
+            });
+            node = node.children.get(position)?;
+        }
+        proof.reverse();
+
+        Some(proof)
+    }
+
+    /// Generates a position-binding proof for the leaf stored under `key`.
+    pub fn generate_indexed_proof(&self, key: &str) -> Option<IndexedProof> {
+        self.generate_indexed_proof_for_index(self.leaf_index(key)?)
+    }
+
+    pub fn generate_indexed_proof_for_index(&self, index: usize) -> Option<IndexedProof> {
+        let siblings = self
+            .generate_group_proof_for_index(index)?
+            .into_iter()
+            .flat_map(|group| {
+                let position = group.position;
+                group
+                    .hashes
+                    .into_iter()
+                    .enumerate()
+                    .filter(move |(i, _)| *i != position)
+                    .map(|(_, hash)| hash)
+            })
+            .collect();
+        Some(IndexedProof {
+            leaf_index: index as u64,
+            tree_size: self.leaf_count() as u64,
+            siblings,
+        })
+    }
+
+    /// Generates a proof for the contiguous leaves in `range`, in insertion
+    /// order, verified with `utils::verify_range_proof`.
+    pub fn generate_range_proof(&self, range: Range<usize>) -> Option<RangeProof> {
+        let levels: Vec<Vec<&MerkleNode>> = self.levels().collect();
+        let span = levels
+            .first()?
+            .get(range.clone())?
+            .iter()
+            .map(|node| node.hash);
+        let mut siblings = Vec::new();
+        fold_range(
+            self.leaf_count(),
+            self.arity,
+            range.start,
+            span.collect(),
+            |level, index| {
+                let hash = levels.get(level)?.get(index)?.hash;
+                siblings.push(hash);
+                Some(hash)
+            },
+        )?;
+        Some(RangeProof {
+            start: range.start as u64,
+            tree_size: self.leaf_count() as u64,
+            siblings,
+        })
+    }
+
+    /// Returns a new version of the tree with the hash of the leaf at `index`
+    /// replaced. Only the nodes on the path to the root are rehashed; every
+    /// other subtree is shared with `self`.
+    pub fn with_leaf_hash(&self, index: usize, hash: Digest) -> Option<MerkleTree> {
+        fn replace(
+            node: &MerkleNode,
+            positions: &[usize],
+            hash: Digest,
+            node_key: Option<&[u8]>,
+        ) -> MerkleNode {
+            match positions.split_first() {
+                None => MerkleNode::leaf(hash),
+                Some((position, rest)) => {
+                    let mut children = node.children.clone();
+                    children[*position] =
+                        Arc::new(replace(&children[*position], rest, hash, node_key));
+                    MerkleNode::parent(children, node_key)
+                }
+            }
+        }
+
+        let positions = self.path_positions(0, index)?;
+        Some(MerkleTree {
+            root: replace(&self.root, &positions, hash, self.node_key.as_deref()),
+            keys: Arc::clone(&self.keys),
+            arity: self.arity,
+            node_key: self.node_key.clone(),
+        })
+    }
+
+    /// Returns a new version of the tree with a leaf inserted before the leaf
+    /// at `index`, or appended at `leaf_count`. Groups are counted from the
+    /// end of each level, so the groups of the leaves after the new one are
+    /// unchanged and their subtrees are shared with `self`; only the groups
+    /// the insertion shifts are hashed again.
+    pub fn with_inserted_leaf(
+        &self,
+        index: usize,
+        key: Option<String>,
+        hash: Digest,
+    ) -> Option<MerkleTree> {
+        if index > self.leaf_count() {
+            return None;
+        }
+
+        // Parents below the root by their first child, to reuse a parent
+        // whose children are all unchanged
+        let mut parents: HashMap<Digest, Arc<MerkleNode>> = HashMap::new();
+        let mut stack: Vec<&Arc<MerkleNode>> = self.root.children.iter().collect();
+        while let Some(node) = stack.pop() {
+            if let Some(first) = node.children.first() {
+                parents.insert(first.hash, Arc::clone(node));
+                stack.extend(&node.children);
+            }
+        }
+
+        let mut nodes: Vec<Arc<MerkleNode>> = self
+            .levels()
+            .next()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|node| Arc::new(node.clone()))
+            .collect();
+        nodes.insert(index, Arc::new(MerkleNode::leaf(hash)));
+        let root = build_root(nodes, self.arity, self.node_key.as_deref(), |group| {
+            let parent = parents.get(&group[0].hash)?;
+            let unchanged = parent.children.len() == group.len()
+                && parent
+                    .children
+                    .iter()
+                    .zip(group)
+                    .all(|(child, node)| child.hash == node.hash);
+            unchanged.then(|| Arc::clone(parent))
+        });
+
+        let mut keys = self.keys.to_vec();
+        keys.insert(index, key);
+        Some(MerkleTree {
+            root,
+            keys: keys.into(),
+            arity: self.arity,
+            node_key: self.node_key.clone(),
+        })
+    }
+
+    /// Like `with_leaf_hash`, for the leaf stored under `key`.
+    pub fn with_leaf_hash_for_key(&self, key: &str, hash: Digest) -> Option<MerkleTree> {
+        let index = self.leaf_index(key)?;
+        self.with_leaf_hash(index, hash)
+    }
+
+    /// Child positions on the path from the root down to the node at `index`
+    /// of `level`, skipping levels where the node is promoted without a parent
+    /// of its own.
+    fn path_positions(&self, level: usize, index: usize) -> Option<Vec<usize>> {
+        let level_size = *self.level_sizes().get(level)?;
+        if index >= level_size {
+            return None;
+        }
+
+        let positions = path_groups(level_size, self.arity, index)
+            .into_iter()
+            .rev()
+            .map(|(position, _)| position)
+            .collect();
+        Some(positions)
+    }
+
     pub fn find_target_relative_to_node(
         &self,
         node: &MerkleNode,
-        target_hash: &Vec<u8>,
+        target_hash: &Digest,
     ) -> Option<NodeOrder> {
-        if MerkleTree::is_node_in_subtree(&node.left, target_hash) {
+        if MerkleTree::is_node_in_subtree(node.left(), target_hash) {
             Some(NodeOrder::Left)
-        } else if MerkleTree::is_node_in_subtree(&node.right, target_hash) {
+        } else if MerkleTree::is_node_in_subtree(node.right(), target_hash) {
             Some(NodeOrder::Right)
         } else {
             None
         }
     }
 
-    fn is_node_in_subtree(node: &Option<Rc<MerkleNode>>, target_hash: &Vec<u8>) -> bool {
+    fn is_node_in_subtree(node: Option<&Arc<MerkleNode>>, target_hash: &Digest) -> bool {
         match node {
             Some(n) => {
                 if &n.hash == target_hash {
                     return true;
                 }
-                MerkleTree::is_node_in_subtree(&n.left, target_hash)
-                    || MerkleTree::is_node_in_subtree(&n.right, target_hash)
+                n.children
+                    .iter()
+                    .any(|child| MerkleTree::is_node_in_subtree(Some(child), target_hash))
             }
             None => false,
         }
@@ -218,11 +832,14 @@ impl MerkleTree {
 #[cfg(test)]
 mod test {
     use crate::{
-        merkle_tree::{MerkleTree, NodeOrder},
+        common::FileResponse,
+        digest::Digest,
+        merkle_tree::{
+            self, MerkleLeaf, MerkleNode, MerkleTree, NodeOrder, ProofListItem, RangeProof,
+        },
         utils,
     };
-    use sha2::{Digest, Sha256};
-    use std::collections::BTreeMap;
+    use std::{collections::BTreeMap, sync::Arc};
 
     fn setup_test() -> (MerkleTree, BTreeMap<String, Vec<u8>>) {
         let files = (1..=8)
@@ -242,7 +859,7 @@ mod test {
     fn should_find_left_target_relative_to_node() {
         let (merkle_tree, files) = setup_test();
 
-        let target_hash = Sha256::digest(&files["file8.txt"]).to_vec();
+        let target_hash = Digest::of_leaf(&Digest::of(&files["file8.txt"]));
 
         let result =
             merkle_tree.find_target_relative_to_node(&merkle_tree.root.clone(), &target_hash);
@@ -254,7 +871,7 @@ mod test {
     fn should_find_right_target_relative_to_node() {
         let (merkle_tree, files) = setup_test();
 
-        let target_hash = Sha256::digest(&files["file3.txt"]).to_vec();
+        let target_hash = Digest::of_leaf(&Digest::of(&files["file3.txt"]));
 
         let result =
             merkle_tree.find_target_relative_to_node(&merkle_tree.root.clone(), &target_hash);
@@ -266,10 +883,10 @@ mod test {
     fn should_not_find_target_if_not_exist() {
         let (merkle_tree, files) = setup_test();
 
-        let target_hash = Sha256::digest(&files["file8.txt"]).to_vec();
+        let target_hash = Digest::of_leaf(&Digest::of(&files["file8.txt"]));
 
         let result = merkle_tree
-            .find_target_relative_to_node(&merkle_tree.root.clone().right.unwrap(), &target_hash);
+            .find_target_relative_to_node(merkle_tree.root.right().unwrap(), &target_hash);
 
         assert_eq!(result, None);
     }
@@ -278,7 +895,7 @@ mod test {
     fn should_accept_correct_proof() {
         let (merkle_tree, files) = setup_test();
 
-        match merkle_tree.generate_merkle_proof("file1.txt", &files) {
+        match merkle_tree.generate_merkle_proof("file1.txt") {
             Some(proof_list) => {
                 assert!(utils::verify_merkle_proof(
                     proof_list,
@@ -286,9 +903,7 @@ mod test {
                     files["file1.txt"].clone(),
                 ));
             }
-            None => {
-                assert!(false);
-            }
+            None => panic!("Expected a proof for file1.txt"),
         }
     }
 
@@ -335,7 +950,7 @@ mod test {
 
         let modified_merkle_tree = MerkleTree::new(&modified_files);
 
-        match modified_merkle_tree.generate_merkle_proof("file1.txt", &modified_files) {
+        match modified_merkle_tree.generate_merkle_proof("file1.txt") {
             Some(proof_list) => {
                 assert!(!utils::verify_merkle_proof(
                     proof_list,
@@ -343,9 +958,526 @@ mod test {
                     files["file1.txt"].clone(),
                 ));
             }
-            None => {
-                assert!(false);
+            None => panic!("Expected a proof for file1.txt"),
+        }
+    }
+
+    #[test]
+    fn should_build_same_root_from_any_ordered_collection() {
+        let (merkle_tree, files) = setup_test();
+
+        let pairs: Vec<(String, Vec<u8>)> = files.clone().into_iter().collect();
+        let from_pairs = MerkleTree::new(pairs);
+        let from_items = MerkleTree::from_items(files.values());
+
+        assert_eq!(from_pairs.get_root_hash(), merkle_tree.get_root_hash());
+        assert_eq!(from_items.get_root_hash(), merkle_tree.get_root_hash());
+        assert_eq!(from_items.leaves()[0].key, None);
+    }
+
+    #[test]
+    fn should_build_same_tree_from_leaf_hashes() {
+        let (merkle_tree, files) = setup_test();
+
+        let hashes = files
+            .iter()
+            .map(|(file_name, content)| (file_name, Digest::of(content)));
+        let from_hashes = MerkleTree::from_leaf_hashes(hashes);
+
+        assert_eq!(from_hashes.get_root_hash(), merkle_tree.get_root_hash());
+        let proof_list = from_hashes.generate_merkle_proof("file3.txt").unwrap();
+        assert!(utils::verify_merkle_proof(
+            proof_list,
+            merkle_tree.get_root_hash(),
+            files["file3.txt"].clone(),
+        ));
+    }
+
+    #[test]
+    fn should_prove_leaves_with_identical_contents_by_position() {
+        let rows = ["same", "other", "same", "same", "other"];
+        let merkle_tree = MerkleTree::from_items(rows);
+        let tree_head = merkle_tree.tree_head();
+
+        let proofs: Vec<Vec<ProofListItem>> = (0..rows.len())
+            .map(|index| merkle_tree.generate_merkle_proof_for_index(index).unwrap())
+            .collect();
+        for (index, proof_list) in proofs.iter().enumerate() {
+            assert!(utils::verify_merkle_proof_against_head(
+                proof_list.clone(),
+                &tree_head,
+                rows[index].as_bytes().to_vec(),
+            ));
+            assert_eq!(
+                proofs.iter().filter(|other| *other == proof_list).count(),
+                1
+            );
+        }
+    }
+
+    #[test]
+    fn should_accept_proof_for_unkeyed_item() {
+        let rows: Vec<&str> = vec!["row a", "row b", "row c", "row d", "row e"];
+        let merkle_tree = MerkleTree::from_items(&rows);
+
+        for (index, row) in rows.iter().enumerate() {
+            let proof_list = merkle_tree
+                .generate_merkle_proof_for_index(index)
+                .expect("Expected a proof for every row");
+            assert!(utils::verify_merkle_proof(
+                proof_list,
+                merkle_tree.get_root_hash(),
+                row.as_bytes().to_vec(),
+            ));
+        }
+        assert!(merkle_tree
+            .generate_merkle_proof_for_index(rows.len())
+            .is_none());
+    }
+
+    #[test]
+    fn should_use_empty_root_for_empty_tree() {
+        let files: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+        let merkle_tree = MerkleTree::new(&files);
+
+        assert_eq!(merkle_tree.get_root_hash(), MerkleTree::EMPTY_ROOT);
+        assert_eq!(
+            MerkleTree::EMPTY_ROOT,
+            Digest::of([&[0x02][..], b"empty"].concat())
+        );
+        assert_ne!(
+            MerkleTree::EMPTY_ROOT,
+            MerkleTree::new([("a", "")]).get_root_hash()
+        );
+        assert!(merkle_tree.generate_merkle_proof("file1.txt").is_none());
+        assert!(merkle_tree.generate_merkle_proof_for_index(0).is_none());
+    }
+
+    #[test]
+    fn should_accept_proof_for_single_file_tree() {
+        let files: BTreeMap<String, Vec<u8>> =
+            vec![("file1.txt".to_string(), b"File 1 contents".to_vec())]
+                .into_iter()
+                .collect();
+        let merkle_tree = MerkleTree::new(&files);
+
+        assert_eq!(
+            merkle_tree.get_root_hash(),
+            Digest::of_leaf(&Digest::of(b"File 1 contents"))
+        );
+
+        let proof_list = merkle_tree.generate_merkle_proof("file1.txt").unwrap();
+        assert_eq!(proof_list.len(), 1);
+        assert!(utils::verify_merkle_proof(
+            proof_list.clone(),
+            merkle_tree.get_root_hash(),
+            files["file1.txt"].clone(),
+        ));
+        assert!(!utils::verify_merkle_proof(
+            proof_list,
+            merkle_tree.get_root_hash(),
+            b"Other contents".to_vec(),
+        ));
+    }
+
+    #[test]
+    fn should_reject_node_children_passed_off_as_contents() {
+        let (merkle_tree, files) = setup_test();
+        let tree_head = merkle_tree.tree_head();
+        let root = &merkle_tree.root;
+        let forged_contents = [
+            root.children[0].hash.to_vec(),
+            root.children[1].hash.to_vec(),
+        ]
+        .concat();
+        let forged_proof = vec![ProofListItem::new(root.hash, None)];
+
+        assert!(!utils::verify_merkle_proof_against_head(
+            forged_proof.clone(),
+            &tree_head,
+            forged_contents.clone(),
+        ));
+        let forged = FileResponse::new("file1.txt".to_owned(), forged_contents, forged_proof);
+        assert!(!utils::verify_file_response(&forged, &tree_head));
+
+        // A one-item proof only fits a one-leaf tree
+        let single = MerkleTree::new([("file1.txt", &files["file1.txt"])]);
+        let proof_list = single.generate_merkle_proof("file1.txt").unwrap();
+        let mut wrong_count = single.tree_head();
+        wrong_count.leaf_count = 8;
+        assert!(!utils::verify_merkle_proof_against_head(
+            proof_list.clone(),
+            &wrong_count,
+            files["file1.txt"].clone(),
+        ));
+        assert!(utils::verify_merkle_proof_against_head(
+            proof_list,
+            &single.tree_head(),
+            files["file1.txt"].clone(),
+        ));
+    }
+
+    #[test]
+    fn should_reject_group_proofs_not_fitting_the_leaf_count() {
+        let items: Vec<String> = (0..10).map(|i| format!("item {}", i)).collect();
+        let merkle_tree = MerkleTree::from_leaves_with_arity(
+            items.iter().map(|i| MerkleLeaf::new(None, i)).collect(),
+            4,
+        );
+        let tree_head = merkle_tree.tree_head();
+        let forged_contents: Vec<u8> = merkle_tree
+            .root
+            .children
+            .iter()
+            .flat_map(|child| child.hash.to_vec())
+            .collect();
+        let forged = FileResponse::with_group_proof("item".to_owned(), forged_contents, Vec::new());
+        assert!(!utils::verify_file_response(&forged, &tree_head));
+
+        // A genuine proof checked against a head claiming another leaf count
+        let proof = merkle_tree.generate_group_proof_for_index(3).unwrap();
+        let response =
+            FileResponse::with_group_proof("item".to_owned(), items[3].clone().into_bytes(), proof);
+        assert!(utils::verify_file_response(&response, &tree_head));
+        for leaf_count in [1, 3, 100] {
+            let mut wrong_count = tree_head.clone();
+            wrong_count.leaf_count = leaf_count;
+            assert!(!utils::verify_file_response(&response, &wrong_count));
+        }
+    }
+
+    #[test]
+    fn should_find_leaf_index_from_its_path() {
+        for arity in MerkleTree::SUPPORTED_ARITIES {
+            for len in [1, 2, 5, 17, 40] {
+                for index in 0..len {
+                    let path = merkle_tree::path_groups(len, arity, index);
+                    assert_eq!(merkle_tree::path_index(len, arity, &path), Some(index));
+                    // Other lengths only fit leaves promoted past fewer or more levels
+                    let longer = [&[(0, 2)], path.as_slice()].concat();
+                    let shorter = path.get(1..).unwrap_or_default();
+                    for other_path in [longer.as_slice(), shorter] {
+                        if let Some(other) = merkle_tree::path_index(len, arity, other_path) {
+                            assert_eq!(merkle_tree::path_groups(len, arity, other), other_path);
+                        }
+                    }
+                    let past_group = [&[(2, 2)], path.as_slice()].concat();
+                    assert_eq!(merkle_tree::path_index(len, arity, &past_group), None);
+                }
             }
         }
     }
+
+    #[test]
+    fn should_describe_tree_in_tree_head() {
+        let (merkle_tree, files) = setup_test();
+
+        let tree_head = merkle_tree.tree_head();
+
+        assert_eq!(tree_head.root_hash, merkle_tree.get_root_hash());
+        assert_eq!(tree_head.leaf_count, 8);
+
+        let proof_list = merkle_tree.generate_merkle_proof("file1.txt").unwrap();
+        assert!(utils::verify_merkle_proof_against_head(
+            proof_list,
+            &tree_head,
+            files["file1.txt"].clone(),
+        ));
+    }
+
+    #[test]
+    fn should_accept_group_proofs_for_every_arity() {
+        for arity in MerkleTree::SUPPORTED_ARITIES {
+            for leaf_count in [1, 2, 3, 7, 16, 17, 40] {
+                let items: Vec<String> = (0..leaf_count).map(|i| format!("item {}", i)).collect();
+                let merkle_tree = MerkleTree::from_leaves_with_arity(
+                    items.iter().map(|i| MerkleLeaf::new(None, i)).collect(),
+                    arity,
+                );
+                let tree_head = merkle_tree.tree_head();
+
+                for (index, item) in items.iter().enumerate() {
+                    let proof = merkle_tree.generate_group_proof_for_index(index).unwrap();
+                    assert!(proof.len() <= tree_head.max_depth());
+                    assert!(utils::verify_group_proof(
+                        &proof,
+                        &tree_head.root_hash,
+                        arity,
+                        item.as_bytes(),
+                    ));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn should_reject_group_proof_with_wrong_position() {
+        let items: Vec<String> = (0..10).map(|i| format!("item {}", i)).collect();
+        let merkle_tree = MerkleTree::from_leaves_with_arity(
+            items.iter().map(|i| MerkleLeaf::new(None, i)).collect(),
+            4,
+        );
+
+        let mut proof = merkle_tree.generate_group_proof_for_index(4).unwrap();
+        assert!(merkle_tree.generate_merkle_proof_for_index(4).is_none());
+        proof[0].position = (proof[0].position + 1) % proof[0].hashes.len();
+
+        assert!(!utils::verify_group_proof(
+            &proof,
+            &merkle_tree.get_root_hash(),
+            4,
+            items[4].as_bytes(),
+        ));
+    }
+
+    #[test]
+    fn should_build_shallower_trees_with_higher_arity() {
+        let items: Vec<String> = (0..64).map(|i| format!("item {}", i)).collect();
+
+        let binary = MerkleTree::from_items(&items);
+        let wide = MerkleTree::from_leaves_with_arity(
+            items.iter().map(|i| MerkleLeaf::new(None, i)).collect(),
+            8,
+        );
+
+        assert_eq!(binary.generate_group_proof_for_index(0).unwrap().len(), 6);
+        assert_eq!(wide.generate_group_proof_for_index(0).unwrap().len(), 2);
+        assert_eq!(wide.root.children.len(), 8);
+        assert_ne!(binary.get_root_hash(), wide.get_root_hash());
+    }
+
+    #[test]
+    fn should_match_rebuilt_tree_after_leaf_update() {
+        for arity in MerkleTree::SUPPORTED_ARITIES {
+            for len in [1, 2, 7, 33] {
+                let mut items: Vec<String> = (0..len).map(|i| format!("item {}", i)).collect();
+                let leaves =
+                    |items: &[String]| items.iter().map(|i| MerkleLeaf::new(None, i)).collect();
+                let merkle_tree = MerkleTree::from_leaves_with_arity(leaves(&items), arity);
+
+                let index = len / 2;
+                items[index] = "updated".to_owned();
+                let updated = merkle_tree
+                    .with_leaf_hash(index, Digest::of(b"updated"))
+                    .unwrap();
+                let rebuilt = MerkleTree::from_leaves_with_arity(leaves(&items), arity);
+
+                assert_eq!(updated.get_root_hash(), rebuilt.get_root_hash());
+                assert_eq!(updated.leaves(), rebuilt.leaves());
+                assert_eq!(updated.leaf_hash(index), Some(Digest::of(b"updated")));
+                assert!(Arc::ptr_eq(&updated.keys, &merkle_tree.keys));
+                assert!(merkle_tree.with_leaf_hash(len, Digest::of(b"")).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn should_match_rebuilt_tree_after_leaf_insertion() {
+        for arity in MerkleTree::SUPPORTED_ARITIES {
+            for len in [0, 1, 2, 7, 33] {
+                let items: Vec<String> = (0..len).map(|i| format!("item {}", i)).collect();
+                let merkle_tree = MerkleTree::new_with_arity(items.iter().map(|i| (i, i)), arity);
+
+                for index in 0..=len {
+                    let inserted = merkle_tree
+                        .with_inserted_leaf(index, Some("new".to_owned()), Digest::of(b"new"))
+                        .unwrap();
+                    let mut items: Vec<(&str, &str)> =
+                        items.iter().map(|i| (i.as_str(), i.as_str())).collect();
+                    items.insert(index, ("new", "new"));
+                    let rebuilt = MerkleTree::new_with_arity(items, arity);
+
+                    assert_eq!(inserted.get_root_hash(), rebuilt.get_root_hash());
+                    assert_eq!(inserted.leaves(), rebuilt.leaves());
+                    assert_eq!(inserted.leaf_index("new"), Some(index));
+                }
+                assert!(merkle_tree
+                    .with_inserted_leaf(len + 1, None, Digest::of(b""))
+                    .is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn should_share_subtrees_after_an_inserted_leaf() {
+        let items: Vec<String> = (0..32).map(|i| format!("item {}", i)).collect();
+        let merkle_tree = MerkleTree::from_items(&items);
+        let inserted = merkle_tree
+            .with_inserted_leaf(0, None, Digest::of(b"first"))
+            .unwrap();
+
+        // Every pair of leaves after the new one keeps its parent
+        let old_nodes: Vec<*const MerkleNode> = merkle_tree
+            .nodes()
+            .map(|node| node as *const MerkleNode)
+            .collect();
+        let shared = inserted
+            .nodes()
+            .filter(|node| old_nodes.contains(&(*node as *const MerkleNode)))
+            .filter(|node| !node.children.is_empty())
+            .count();
+        assert!(shared >= 16);
+    }
+
+    #[test]
+    fn should_keep_the_node_key_in_updated_versions() {
+        let items: Vec<String> = (0..7).map(|i| format!("item {}", i)).collect();
+        let leaves = |items: &[String]| items.iter().map(|i| MerkleLeaf::new(None, i)).collect();
+        let keyed = MerkleTree::from_leaves_with_node_key(leaves(&items), 2, Some(b"node key"));
+
+        let plain = MerkleTree::from_leaves_with_arity(leaves(&items), 2);
+        assert_ne!(keyed.get_root_hash(), plain.get_root_hash());
+        assert_eq!(keyed.leaves(), plain.leaves());
+
+        let updated = keyed.with_leaf_hash(3, Digest::of(b"updated")).unwrap();
+        let mut items = items;
+        items[3] = "updated".to_owned();
+        let rebuilt = MerkleTree::from_leaves_with_node_key(leaves(&items), 2, Some(b"node key"));
+        assert_eq!(updated.get_root_hash(), rebuilt.get_root_hash());
+    }
+
+    #[test]
+    fn should_traverse_leaves_levels_and_nodes() {
+        let (merkle_tree, files) = setup_test();
+        let items: Vec<String> = (0..5).map(|i| format!("item {}", i)).collect();
+        let odd_tree = MerkleTree::from_items(&items);
+
+        assert_eq!(merkle_tree.leaf_count(), 8);
+        assert_eq!(merkle_tree.depth(), 3);
+        assert_eq!(merkle_tree.leaf_index("file3.txt"), Some(2));
+        assert_eq!(merkle_tree.leaf_index("missing.txt"), None);
+        for (index, leaf) in merkle_tree.iter_leaves() {
+            let key = leaf.key.as_deref().unwrap();
+            assert_eq!(leaf.hash, Digest::of(&files[key]));
+            let node = merkle_tree.node_at(0, index).unwrap();
+            assert_eq!(node.leaf_hash, Some(leaf.hash));
+            assert_eq!(node.hash, Digest::of_leaf(&leaf.hash));
+        }
+
+        for tree in [&merkle_tree, &odd_tree] {
+            let levels: Vec<Vec<Digest>> = tree
+                .levels()
+                .map(|level| level.iter().map(|node| node.hash).collect())
+                .collect();
+            assert_eq!(levels.len(), tree.depth() + 1);
+            assert_eq!(levels[tree.depth()], [tree.get_root_hash()]);
+            for (level, hashes) in levels.iter().enumerate() {
+                for (index, hash) in hashes.iter().enumerate() {
+                    assert_eq!(&tree.node_at(level, index).unwrap().hash, hash);
+                }
+                assert!(tree.node_at(level, hashes.len()).is_none());
+            }
+        }
+
+        assert_eq!(merkle_tree.nodes().count(), 15);
+        assert_eq!(odd_tree.nodes().count(), 9);
+        assert_eq!(
+            odd_tree
+                .nodes()
+                .filter(|node| node.children.is_empty())
+                .count(),
+            5
+        );
+        assert_eq!(
+            MerkleTree::from_items(Vec::<&str>::new()).nodes().count(),
+            0
+        );
+        assert_eq!(MerkleTree::from_items(["only"]).depth(), 0);
+    }
+
+    #[test]
+    fn should_bind_leaf_position_in_indexed_proofs() {
+        for arity in MerkleTree::SUPPORTED_ARITIES {
+            for len in [1, 2, 5, 17, 40] {
+                let items: Vec<String> = (0..len).map(|i| format!("item {}", i)).collect();
+                let merkle_tree = MerkleTree::from_leaves_with_arity(
+                    items.iter().map(|i| MerkleLeaf::new(None, i)).collect(),
+                    arity,
+                );
+                let root = merkle_tree.get_root_hash();
+
+                for (index, item) in items.iter().enumerate() {
+                    let leaf_hash = Digest::of(item);
+                    let proof = merkle_tree.generate_indexed_proof_for_index(index).unwrap();
+                    assert!(!proof.siblings.contains(&leaf_hash));
+                    assert!(utils::verify_indexed_proof_for_leaf(
+                        &proof, &root, arity, leaf_hash
+                    ));
+
+                    let mut moved = proof.clone();
+                    moved.leaf_index = ((index + 1) % len) as u64;
+                    assert_eq!(
+                        utils::verify_indexed_proof_for_leaf(&moved, &root, arity, leaf_hash),
+                        len == 1
+                    );
+
+                    let mut resized = proof;
+                    resized.tree_size += 1;
+                    assert!(!utils::verify_indexed_proof_for_leaf(
+                        &resized, &root, arity, leaf_hash
+                    ));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn should_prove_every_contiguous_span() {
+        for arity in MerkleTree::SUPPORTED_ARITIES {
+            for len in [1, 2, 5, 17, 40] {
+                let hashes: Vec<Digest> = (0..len)
+                    .map(|i| Digest::of(format!("item {}", i)))
+                    .collect();
+                let merkle_tree = MerkleTree::from_leaves_with_arity(
+                    hashes
+                        .iter()
+                        .map(|hash| MerkleLeaf::from_hash(None, *hash))
+                        .collect(),
+                    arity,
+                );
+                let tree_head = merkle_tree.tree_head();
+
+                for start in 0..len {
+                    for end in start + 1..=len {
+                        let span = &hashes[start..end];
+                        let proof = merkle_tree.generate_range_proof(start..end).unwrap();
+                        assert!(utils::verify_range_proof_against_head(
+                            &proof, &tree_head, span
+                        ));
+
+                        let shifted = RangeProof {
+                            start: proof.start + 1,
+                            ..proof.clone()
+                        };
+                        assert!(!utils::verify_range_proof_against_head(
+                            &shifted, &tree_head, span
+                        ));
+                        if end - start > 1 {
+                            let shorter = &span[..span.len() - 1];
+                            assert!(!utils::verify_range_proof_against_head(
+                                &proof, &tree_head, shorter
+                            ));
+                        }
+                    }
+                }
+                assert!(merkle_tree.generate_range_proof(0..len + 1).is_none());
+                assert!(merkle_tree.generate_range_proof(1..1).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn should_send_only_boundary_siblings_for_a_span() {
+        let items: Vec<String> = (0..4096).map(|i| format!("file {}", i)).collect();
+        let merkle_tree = MerkleTree::from_items(&items);
+        let root = merkle_tree.get_root_hash();
+        let mut span: Vec<Digest> = items[1000..2000].iter().map(Digest::of).collect();
+
+        let proof = merkle_tree.generate_range_proof(1000..2000).unwrap();
+        assert!(proof.siblings.len() <= 2 * merkle_tree.depth());
+        assert!(utils::verify_range_proof(&proof, &root, 2, &span));
+
+        span.swap(10, 11);
+        assert!(!utils::verify_range_proof(&proof, &root, 2, &span));
+    }
 }
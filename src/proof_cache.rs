@@ -0,0 +1,245 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::digest::Digest;
+use crate::merkle_tree::{MerkleProof, MerkleTree};
+
+/// Generates the proofs of the first `limit` keyed leaves by index, e.g. to
+/// warm a cache without holding its lock.
+pub fn generate_proofs(merkle_tree: &MerkleTree, limit: usize) -> Vec<(String, MerkleProof)> {
+    merkle_tree
+        .keys()
+        .enumerate()
+        .filter_map(|(index, key)| Some((key?, index)))
+        .take(limit)
+        .filter_map(|(key, index)| {
+            let proof = merkle_tree.generate_proof_for_index(index)?;
+            Some((key.to_owned(), proof))
+        })
+        .collect()
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofCacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub invalidations: u64,
+}
+
+/// Proofs keyed by root hash and then leaf key, evicting the least recently
+/// used entry once `capacity` proofs are stored. A capacity of 0 disables
+/// caching.
+#[derive(Debug)]
+pub struct ProofCache {
+    entries: HashMap<Digest, HashMap<String, (MerkleProof, u64)>>,
+    len: usize,
+    /// Entries by the tick they were last used at, oldest first.
+    recency: BTreeMap<u64, (Digest, String)>,
+    /// Roots whose proofs were all generated at once by `warm`, as opposed to
+    /// roots with only some proofs cached on demand.
+    warmed: HashSet<Digest>,
+    tick: u64,
+    stats: ProofCacheStats,
+}
+
+impl ProofCache {
+    pub fn new(capacity: usize) -> Self {
+        ProofCache {
+            entries: HashMap::new(),
+            len: 0,
+            recency: BTreeMap::new(),
+            warmed: HashSet::new(),
+            tick: 0,
+            stats: ProofCacheStats {
+                capacity,
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.stats.capacity > 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.stats.capacity
+    }
+
+    pub fn get(&mut self, root: &Digest, key: &str) -> Option<MerkleProof> {
+        self.tick += 1;
+        match self
+            .entries
+            .get_mut(root)
+            .and_then(|proofs| proofs.get_mut(key))
+        {
+            Some((proof, last_used)) => {
+                self.recency.remove(last_used);
+                *last_used = self.tick;
+                self.recency.insert(self.tick, (*root, key.to_owned()));
+                self.stats.hits += 1;
+                Some(proof.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, root: &Digest, key: &str, proof: MerkleProof) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        self.tick += 1;
+        let proofs = self.entries.entry(*root).or_default();
+        match proofs.insert(key.to_owned(), (proof, self.tick)) {
+            Some((_, last_used)) => {
+                self.recency.remove(&last_used);
+            }
+            None => self.len += 1,
+        }
+        self.recency.insert(self.tick, (*root, key.to_owned()));
+
+        while self.len > self.stats.capacity {
+            let Some((_, (oldest_root, oldest_key))) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(proofs) = self.entries.get_mut(&oldest_root) {
+                proofs.remove(&oldest_key);
+                if proofs.is_empty() {
+                    self.entries.remove(&oldest_root);
+                }
+            }
+            self.len -= 1;
+            self.stats.evictions += 1;
+        }
+    }
+
+    /// Looks up the proof for `key` under the tree's root, generating and
+    /// caching it on a miss.
+    pub fn get_or_generate(&mut self, merkle_tree: &MerkleTree, key: &str) -> Option<MerkleProof> {
+        let root = merkle_tree.get_root_hash();
+        if let Some(proof) = self.get(&root, key) {
+            return Some(proof);
+        }
+        let proof = merkle_tree.generate_proof(key)?;
+        self.insert(&root, key, proof.clone());
+        Some(proof)
+    }
+
+    /// Caches proofs generated for `root`, e.g. by `generate_proofs`,
+    /// keeping any already cached, and marks the root as warmed.
+    pub fn warm(&mut self, root: &Digest, proofs: Vec<(String, MerkleProof)>) {
+        self.warmed.insert(*root);
+        for (key, proof) in proofs {
+            let cached = self
+                .entries
+                .get(root)
+                .is_some_and(|proofs| proofs.contains_key(&key));
+            if !cached {
+                self.insert(root, &key, proof);
+            }
+        }
+    }
+
+    /// Whether `warm` was called for `root` since the last invalidation.
+    pub fn is_warmed(&self, root: &Digest) -> bool {
+        self.warmed.contains(root)
+    }
+
+    /// Drops every cached proof, e.g. after the stored files changed.
+    pub fn invalidate(&mut self) {
+        if !self.entries.is_empty() {
+            self.stats.invalidations += 1;
+        }
+        self.entries.clear();
+        self.len = 0;
+        self.recency.clear();
+        self.warmed.clear();
+    }
+
+    pub fn stats(&self) -> ProofCacheStats {
+        ProofCacheStats {
+            entries: self.len,
+            ..self.stats.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::merkle_tree::MerkleTree;
+    use crate::proof_cache::{generate_proofs, ProofCache};
+
+    fn setup_tree(count: usize) -> MerkleTree {
+        MerkleTree::new((0..count).map(|i| (format!("file{}.txt", i), format!("File {}", i))))
+    }
+
+    #[test]
+    fn should_count_hits_and_misses() {
+        let merkle_tree = setup_tree(4);
+        let mut cache = ProofCache::new(10);
+
+        let first = cache.get_or_generate(&merkle_tree, "file1.txt").unwrap();
+        let second = cache.get_or_generate(&merkle_tree, "file1.txt").unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(Some(first), merkle_tree.generate_proof("file1.txt"));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+    }
+
+    #[test]
+    fn should_evict_least_recently_used() {
+        let merkle_tree = setup_tree(4);
+        let root = merkle_tree.get_root_hash();
+        let mut cache = ProofCache::new(2);
+
+        cache.get_or_generate(&merkle_tree, "file0.txt");
+        cache.get_or_generate(&merkle_tree, "file1.txt");
+        cache.get_or_generate(&merkle_tree, "file0.txt");
+        cache.get_or_generate(&merkle_tree, "file2.txt");
+
+        assert!(cache.get(&root, "file0.txt").is_some());
+        assert!(cache.get(&root, "file1.txt").is_none());
+        assert_eq!(cache.stats().evictions, 1);
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn should_warm_and_invalidate() {
+        let merkle_tree = setup_tree(5);
+        let mut cache = ProofCache::new(3);
+
+        let root = merkle_tree.get_root_hash();
+        cache.get_or_generate(&merkle_tree, "file4.txt");
+        assert!(!cache.is_warmed(&root));
+        let proofs = generate_proofs(&merkle_tree, cache.capacity());
+        assert_eq!(proofs.len(), 3);
+        for (key, proof) in &proofs {
+            assert_eq!(merkle_tree.generate_proof(key).as_ref(), Some(proof));
+        }
+        cache.warm(&root, proofs);
+        assert_eq!(cache.stats().entries, 3);
+        assert!(cache.is_warmed(&root));
+        assert!(!cache.is_warmed(&setup_tree(4).get_root_hash()));
+
+        cache.invalidate();
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(cache.stats().invalidations, 1);
+        assert!(!cache.is_warmed(&merkle_tree.get_root_hash()));
+    }
+
+    #[test]
+    fn should_not_store_when_disabled() {
+        let merkle_tree = setup_tree(3);
+        let mut cache = ProofCache::new(0);
+
+        assert!(cache.get_or_generate(&merkle_tree, "file1.txt").is_some());
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
@@ -1,33 +1,202 @@
 use axum::{
-    extract::{Json, Path},
+    extract::{Json, Path, Query},
     handler::{get, post},
     Router,
 };
 use base64::{self, engine::general_purpose, Engine};
 use clap::Parser;
 use hyper::StatusCode;
+use percent_encoding::percent_decode_str;
 use std::{
+    collections::{BTreeMap, BTreeSet},
     fs::{self, create_dir_all, File},
-    io::Write,
+    io::{self, Write},
+    ops::Bound,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
+use tokio::task;
+
+use merkle_rs::{
+    chunking::{ChunkManifest, ChunkStore},
+    common::{
+        BucketSummary, ChunkUploadRequest, CreateBucketRequest, FileResponse, IndexCheck,
+        ListingEntry, ListingPage, ListingQuery, ManifestUploadRequest, MissingChunksRequest,
+        MissingChunksResponse, ProofQuery, SignedTreeHead, UploadRequest, UploadResponse,
+    },
+    digest::Digest,
+    file_metadata::{FileMetadata, MetadataFields},
+    file_name::FileName,
+    merkle_tree::{MerkleLeaf, MerkleProof, MerkleTree},
+    proof_cache::{self, ProofCache, ProofCacheStats},
+    signature::SigningKey,
+    streaming::{self, StreamHeader},
+    tree_head::TreeHead,
+    utils::{self, LeafSalt},
+    versioned_tree::VersionedTree,
+};
+
+use crate::server_args::Args;
 
-mod common;
-mod merkle_tree;
 mod server_args;
-mod utils;
 
-use crate::merkle_tree::MerkleTree;
-use crate::{
-    common::{FileResponse, UploadRequest, UploadResponse},
-    server_args::Args,
-};
+/// How the server lays out the tree it generates proofs from, shared by handlers.
+#[derive(Clone, Debug)]
+struct ServerConfig {
+    directory: String,
+    arity: usize,
+    leaf_metadata: MetadataFields,
+    /// Files are stored as manifests of deduplicated chunks.
+    chunking: bool,
+    /// Bucket the store is, none for the default store.
+    bucket: Option<String>,
+    /// Key the nodes of keyed trees are hashed with, see `utils::node_key`.
+    /// Leaves are then keyed by the client and taken from uploads tagged
+    /// with this key. Without the client's secret they cannot be checked
+    /// against the contents.
+    node_key: Option<Arc<[u8]>>,
+    proof_cache: Arc<Mutex<ProofCache>>,
+    /// Cache the proofs of all files whenever a new tree is built.
+    eager_proofs: bool,
+    /// Most versions of the tree kept, so files can still be proven against
+    /// the roots clients stored before later uploads.
+    tree_versions: usize,
+    signer: Option<Arc<Mutex<TreeHeadSigner>>>,
+    /// Stored files and their trees, read from disk on first use and updated
+    /// on each upload.
+    index: Arc<Mutex<Option<StoreIndex>>>,
+}
 
-async fn upload(
+/// Named stores created through the API, each with its own directory under
+/// `<path>/.buckets`, index, proof cache and tree. The files directly under
+/// `<path>` stay the default store.
+#[derive(Clone, Debug)]
+struct Buckets {
     directory: String,
+    /// Layout every bucket's tree is built with.
+    template: ServerConfig,
+    proof_cache_size: usize,
+    buckets: Arc<Mutex<BTreeMap<String, ServerConfig>>>,
+}
+
+impl Buckets {
+    /// Picks up the buckets already stored under `template.directory`.
+    fn load(template: &ServerConfig, proof_cache_size: usize) -> io::Result<Self> {
+        let directory = std::path::Path::new(&template.directory).join(BUCKETS);
+        let buckets = Buckets {
+            directory: directory.to_string_lossy().into_owned(),
+            template: template.clone(),
+            proof_cache_size,
+            buckets: Arc::new(Mutex::new(BTreeMap::new())),
+        };
+        create_dir_all(&directory)?;
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            match entry.file_name().to_str() {
+                Some(name) if entry.path().is_dir() && valid_bucket_name(name) => {
+                    buckets.insert(name);
+                }
+                _ => eprintln!("Ignoring {:?}, not a bucket", entry.path()),
+            }
+        }
+        Ok(buckets)
+    }
+
+    /// Creates the bucket's directory, returning `false` if it already exists.
+    fn create(&self, name: &str) -> io::Result<bool> {
+        if self.buckets.lock().unwrap().contains_key(name) {
+            return Ok(false);
+        }
+        create_dir_all(std::path::Path::new(&self.directory).join(name))?;
+        Ok(self.insert(name))
+    }
+
+    fn insert(&self, name: &str) -> bool {
+        let config = ServerConfig {
+            directory: format!("{}/{}", self.directory, name),
+            bucket: Some(name.to_owned()),
+            proof_cache: Arc::new(Mutex::new(ProofCache::new(self.proof_cache_size))),
+            // Bucket heads are signed with the bucket name, see
+            // `SignedTreeHead::message`
+            signer: self
+                .template
+                .signer
+                .as_ref()
+                .map(|signer| Arc::new(Mutex::new(signer.lock().unwrap().for_store()))),
+            index: Arc::new(Mutex::new(None)),
+            ..self.template.clone()
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.contains_key(name) {
+            return false;
+        }
+        buckets.insert(name.to_owned(), config);
+        true
+    }
+
+    /// Config of an existing bucket, or 404.
+    fn config(&self, name: &str) -> Result<ServerConfig, StatusCode> {
+        match self.buckets.lock().unwrap().get(name) {
+            Some(config) => Ok(config.clone()),
+            None => {
+                eprintln!("Request for unknown bucket {:?}", name);
+                Err(StatusCode::NOT_FOUND)
+            }
+        }
+    }
+}
+
+/// Directory under the default store holding one directory per bucket.
+const BUCKETS: &str = ".buckets";
+
+/// Bucket names are used in URLs and as directory names, so they are kept to
+/// lowercase letters, digits and dashes.
+fn valid_bucket_name(name: &str) -> bool {
+    (1..=63).contains(&name.len())
+        && !name.starts_with('-')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+/// Signs each distinct tree head once, and at most one head per `interval`,
+/// since every signature uses up one of the key's one-time keys. Anyone can
+/// request tree heads, so heads changed within the interval are served with
+/// the last signature instead of exhausting the key. Each store has its own
+/// signer, all sharing the server's key.
+#[derive(Debug)]
+struct TreeHeadSigner {
+    key: Arc<Mutex<SigningKey>>,
+    interval: Duration,
+    last_signed: Option<(SignedTreeHead, Instant)>,
+}
+
+impl TreeHeadSigner {
+    /// A signer for another store, with the same key and interval.
+    fn for_store(&self) -> Self {
+        TreeHeadSigner {
+            key: Arc::clone(&self.key),
+            interval: self.interval,
+            last_signed: None,
+        }
+    }
+}
+
+async fn upload(
+    config: ServerConfig,
     Json(body): Json<UploadRequest>,
 ) -> Result<Json<UploadResponse>, StatusCode> {
+    let filename = valid_file_name(&body.filename)?;
+    valid_keyed_leaf(
+        &config,
+        &filename,
+        body.leaf_hash.as_ref(),
+        body.leaf_tag.as_ref(),
+    )?;
+    valid_metadata(&filename, body.metadata.as_ref())?;
+
     // Create the directory if it doesn't exist
-    let path = std::path::Path::new(&directory);
+    let path = std::path::Path::new(&config.directory);
     if !path.exists() {
         if let Err(e) = create_dir_all(path) {
             eprintln!("Failed to create directory: {:?}", e);
@@ -45,24 +214,582 @@ async fn upload(
     };
 
     // Save the file
-    let file_path = path.join(&body.filename);
-    if let Err(e) = File::create(file_path).and_then(|mut file| file.write_all(&content_bytes)) {
+    let file_path = path.join(&filename);
+    if let Err(e) = File::create(&file_path).and_then(|mut file| file.write_all(&content_bytes)) {
         eprintln!("Failed to save file: {:?}", e);
         return Err(StatusCode::INTERNAL_SERVER_ERROR);
     }
 
+    // Keep the client's mode and mtime so leaves committing to them match,
+    // without applying them to the stored file
+    if let Err(e) = write_metadata(&config.directory, filename.as_str(), body.metadata.as_ref()) {
+        eprintln!("Failed to save file metadata: {:?}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    if let Err(e) = write_sidecars(
+        &config.directory,
+        filename.as_str(),
+        body.leaf_hash.as_ref(),
+        body.salt.as_ref(),
+    ) {
+        eprintln!("Failed to save leaf hash or salt: {:?}", e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    if let Err(e) = update_index(&config, filename.as_str()).await {
+        eprintln!("Failed to index {}: {:?}", filename, e);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    // The root changed, so no cached proof can be served again
+    config.proof_cache.lock().unwrap().invalidate();
+
     Ok(Json(UploadResponse {
         message: "File uploaded succesfully".to_owned(),
     }))
 }
 
-async fn request_file(
+async fn missing_chunks(
     directory: String,
+    Json(body): Json<MissingChunksRequest>,
+) -> Json<MissingChunksResponse> {
+    let store = ChunkStore::new(&directory);
+    let missing = body
+        .chunks
+        .into_iter()
+        .filter(|chunk| !store.contains_chunk(chunk))
+        .collect();
+    Json(MissingChunksResponse { missing })
+}
+
+async fn upload_chunk(
+    directory: String,
+    Json(body): Json<ChunkUploadRequest>,
+) -> Result<Json<UploadResponse>, StatusCode> {
+    let content_bytes = match general_purpose::STANDARD.decode(&body.content) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Failed to decode base64 content: {:?}", e);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    match ChunkStore::new(&directory).put_chunk(&content_bytes) {
+        Ok(hash) => Ok(Json(UploadResponse {
+            message: format!("Chunk {} stored", hash),
+        })),
+        Err(e) => {
+            eprintln!("Failed to save chunk: {:?}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn upload_manifest(
+    config: ServerConfig,
+    Json(body): Json<ManifestUploadRequest>,
+) -> Result<Json<UploadResponse>, StatusCode> {
+    let filename = valid_file_name(&body.filename)?;
+    valid_keyed_leaf(
+        &config,
+        &filename,
+        body.leaf_hash.as_ref(),
+        body.leaf_tag.as_ref(),
+    )?;
+    valid_metadata(&filename, body.manifest.metadata.as_ref())?;
+
+    let stored = ChunkStore::new(&config.directory)
+        .put_manifest(filename.as_str(), &body.manifest)
+        .and_then(|_| {
+            write_sidecars(
+                &config.directory,
+                filename.as_str(),
+                body.leaf_hash.as_ref(),
+                body.salt.as_ref(),
+            )
+        });
+    let stored = match stored {
+        Ok(_) => update_index(&config, filename.as_str()).await,
+        Err(e) => Err(e),
+    };
+    match stored {
+        Ok(_) => {
+            config.proof_cache.lock().unwrap().invalidate();
+            Ok(Json(UploadResponse {
+                message: "File uploaded succesfully".to_owned(),
+            }))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            eprintln!("Rejected manifest for {}: {}", filename, e);
+            Err(StatusCode::BAD_REQUEST)
+        }
+        Err(e) => {
+            eprintln!("Failed to save manifest: {:?}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Parses a file name taken from a request, which is joined onto the storage
+/// directory, rejecting names that could escape it.
+fn valid_file_name(filename: &str) -> Result<FileName, StatusCode> {
+    FileName::new(filename).map_err(|e| {
+        eprintln!("Rejected request: {}", e);
+        StatusCode::BAD_REQUEST
+    })
+}
+
+/// Rejects metadata that could not be restored, such as setuid modes.
+/// Keyed trees only take leaf hashes tagged with their node key, see
+/// `utils::keyed_leaf_tag`, so that uploaders without the key cannot add leaves.
+fn valid_keyed_leaf(
+    config: &ServerConfig,
+    filename: &FileName,
+    leaf_hash: Option<&Digest>,
+    leaf_tag: Option<&Digest>,
+) -> Result<(), StatusCode> {
+    let Some(node_key) = &config.node_key else {
+        return Ok(());
+    };
+    match (leaf_hash, leaf_tag) {
+        (Some(leaf_hash), Some(leaf_tag))
+            if utils::keyed_leaf_tag(node_key, filename.as_str(), leaf_hash) == *leaf_tag =>
+        {
+            Ok(())
+        }
+        (Some(_), Some(_)) => {
+            eprintln!(
+                "Rejected {} with a keyed leaf hash tagged by another key",
+                filename
+            );
+            Err(StatusCode::BAD_REQUEST)
+        }
+        _ => {
+            eprintln!("Rejected {} without a tagged keyed leaf hash", filename);
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+fn valid_metadata(filename: &FileName, metadata: Option<&FileMetadata>) -> Result<(), StatusCode> {
+    match metadata.map(FileMetadata::validate) {
+        Some(Err(e)) => {
+            eprintln!("Rejected {}: {}", filename, e);
+            Err(StatusCode::BAD_REQUEST)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Like `valid_file_name`, for a name taken percent-encoded from a URL path.
+fn path_file_name(filename: &str) -> Result<FileName, StatusCode> {
+    match percent_decode_str(filename).decode_utf8() {
+        Ok(filename) => valid_file_name(&filename),
+        Err(e) => {
+            eprintln!("Rejected request for {}: {}", filename, e);
+            Err(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+/// Keyed leaf hashes sent by the client, which the server cannot compute.
+const LEAF_HASHES: &str = ".leaves";
+/// Salts committed in the leaves, returned with each file's proof.
+const SALTS: &str = ".salts";
+/// Metadata uploaded with plain files, as JSON.
+const METADATA: &str = ".metadata";
+
+/// Digests stored per file besides its contents, each kind in its own directory.
+fn sidecar_path(directory: &str, kind: &str, filename: &str) -> std::path::PathBuf {
+    std::path::Path::new(directory).join(kind).join(filename)
+}
+
+fn write_sidecar(directory: &str, kind: &str, filename: &str, digest: &Digest) -> io::Result<()> {
+    create_dir_all(std::path::Path::new(directory).join(kind))?;
+    fs::write(sidecar_path(directory, kind, filename), digest.to_hex())
+}
+
+fn read_sidecar(directory: &str, kind: &str, filename: &str) -> io::Result<Option<Digest>> {
+    match fs::read_to_string(sidecar_path(directory, kind, filename)) {
+        Ok(hex) => Digest::from_hex(hex.trim())
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Stores the keyed leaf hash and salt sent along with a file, if any.
+fn write_sidecars(
+    directory: &str,
+    filename: &str,
+    leaf_hash: Option<&Digest>,
+    salt: Option<&LeafSalt>,
+) -> io::Result<()> {
+    for (kind, digest) in [(LEAF_HASHES, leaf_hash), (SALTS, salt)] {
+        if let Some(digest) = digest {
+            write_sidecar(directory, kind, filename, digest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Stores the metadata uploaded with a plain file, or removes what an earlier
+/// upload of the file left behind.
+fn write_metadata(
+    directory: &str,
+    filename: &str,
+    metadata: Option<&FileMetadata>,
+) -> io::Result<()> {
+    let path = sidecar_path(directory, METADATA, filename);
+    match metadata {
+        Some(metadata) => {
+            create_dir_all(std::path::Path::new(directory).join(METADATA))?;
+            fs::write(path, serde_json::to_vec(metadata)?)
+        }
+        None => match fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        },
+    }
+}
+
+fn read_metadata(directory: &str, filename: &str) -> io::Result<Option<FileMetadata>> {
+    match fs::read(sidecar_path(directory, METADATA, filename)) {
+        Ok(json) => Ok(Some(serde_json::from_slice(&json)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// What the tree commits to for a stored file.
+#[derive(Clone, Debug, PartialEq)]
+struct IndexedFile {
+    leaf_hash: Digest,
+    size: u64,
+    metadata: Option<FileMetadata>,
+    salt: Option<LeafSalt>,
+}
+
+type FileIndex = BTreeMap<String, IndexedFile>;
+
+/// The stored files with the file tree and listing tree built over them,
+/// kept up to date by uploads so that requests never rebuild a tree. Trees
+/// share their nodes, so requests clone them to prove without holding the lock.
+#[derive(Debug)]
+struct StoreIndex {
+    files: FileIndex,
+    /// The file tree and its earlier versions, the latest over `files`.
+    versions: VersionedTree,
+    listing_tree: Arc<MerkleTree>,
+    /// Number of changes to the index since it was read from disk, so that a
+    /// reload can tell whether uploads were indexed while it read.
+    generation: u64,
+}
+
+impl StoreIndex {
+    fn new(config: &ServerConfig, files: FileIndex) -> Self {
+        StoreIndex {
+            versions: VersionedTree::with_max_versions(
+                index_tree(config, &files),
+                Some(config.tree_versions),
+            ),
+            listing_tree: Arc::new(utils::listing_tree(
+                &listing_entries(files.iter()),
+                config.arity,
+            )),
+            files,
+            generation: 0,
+        }
+    }
+
+    /// Indexes `file` under `filename`. Replacing a file rehashes only its
+    /// paths in both trees, and a new file is inserted as a leaf, rehashing
+    /// only the groups its insertion shifts, see `MerkleTree::with_inserted_leaf`.
+    fn insert(&mut self, filename: &str, file: IndexedFile) {
+        let index = self
+            .files
+            .range::<str, _>((Bound::Unbounded, Bound::Excluded(filename)))
+            .count();
+        let listing_leaf = utils::listing_leaf_hash(&ListingEntry {
+            filename: filename.to_owned(),
+            size: file.size,
+            leaf_hash: file.leaf_hash,
+        });
+        let latest = self.versions.latest();
+        let trees = if self.files.contains_key(filename) {
+            (
+                latest.with_leaf_hash(index, file.leaf_hash),
+                self.listing_tree.with_leaf_hash(index, listing_leaf),
+            )
+        } else {
+            let key = Some(filename.to_owned());
+            (
+                latest.with_inserted_leaf(index, key, file.leaf_hash),
+                self.listing_tree
+                    .with_inserted_leaf(index, None, listing_leaf),
+            )
+        };
+        if let (Some(tree), Some(listing_tree)) = trees {
+            self.versions.commit(tree);
+            self.listing_tree = Arc::new(listing_tree);
+            self.files.insert(filename.to_owned(), file);
+            self.generation += 1;
+        }
+    }
+
+    /// Replaces the index with `files`, keeping the earlier versions of the
+    /// tree to prove unchanged files against.
+    fn reload(&mut self, config: &ServerConfig, files: FileIndex) {
+        let reloaded = StoreIndex::new(config, files);
+        self.versions.commit(reloaded.versions.latest().clone());
+        self.listing_tree = reloaded.listing_tree;
+        self.files = reloaded.files;
+        self.generation += 1;
+    }
+}
+
+/// Runs `f` on the cached index. If it is not loaded yet, the files are read
+/// and the trees built on the blocking pool, without holding the lock.
+async fn with_index<T>(config: &ServerConfig, f: impl FnOnce(&StoreIndex) -> T) -> io::Result<T> {
+    let loaded = if config.index.lock().unwrap().is_none() {
+        Some(read_index(config).await?)
+    } else {
+        None
+    };
+
+    let mut index = config.index.lock().unwrap();
+    if let Some(loaded) = loaded {
+        index.get_or_insert(loaded);
+    }
+    match &*index {
+        Some(index) => Ok(f(index)),
+        None => Err(io::Error::other("Store index is not loaded")),
+    }
+}
+
+/// Reads every stored file and builds the index over them on the blocking pool.
+async fn read_index(config: &ServerConfig) -> io::Result<StoreIndex> {
+    let config = config.clone();
+    task::spawn_blocking(move || Ok(StoreIndex::new(&config, index_files(&config)?)))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Indexes every stored file on the blocking pool, see `index_files`.
+async fn read_files(config: &ServerConfig) -> io::Result<FileIndex> {
+    let config = config.clone();
+    task::spawn_blocking(move || index_files(&config))
+        .await
+        .map_err(io::Error::other)?
+}
+
+/// Re-reads one stored file into the cached index, if it is loaded already.
+/// Runs on the blocking pool. The file is read under the lock, so the index
+/// ends up with whichever of concurrent uploads of the file was stored last.
+async fn update_index(config: &ServerConfig, filename: &str) -> io::Result<()> {
+    let (config, filename) = (config.clone(), filename.to_owned());
+    task::spawn_blocking(move || {
+        let mut index = config.index.lock().unwrap();
+        if let Some(index) = &mut *index {
+            index.insert(&filename, index_file(&config, &filename)?);
+        }
+        Ok(())
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Indexes every stored file, in file name order.
+fn index_files(config: &ServerConfig) -> io::Result<FileIndex> {
+    if config.chunking {
+        return ChunkStore::new(&config.directory)
+            .manifests()?
+            .into_iter()
+            .map(|(filename, manifest)| {
+                let file = with_sidecars(config, &filename, manifest_entry(config, &manifest))?;
+                Ok((filename, file))
+            })
+            .collect();
+    }
+
+    let mut files = FileIndex::new();
+    for entry in fs::read_dir(&config.directory)? {
+        let entry = entry?;
+        if !entry.path().is_file() {
+            continue;
+        }
+        if let Some(filename) = entry.file_name().to_str() {
+            files.insert(filename.to_owned(), index_file(config, filename)?);
+        }
+    }
+    Ok(files)
+}
+
+/// Indexes a single stored file, like `index_files`.
+fn index_file(config: &ServerConfig, filename: &str) -> io::Result<IndexedFile> {
+    let file = if config.chunking {
+        manifest_entry(
+            config,
+            &ChunkStore::new(&config.directory).get_manifest(filename)?,
+        )
+    } else {
+        let path = std::path::Path::new(&config.directory).join(filename);
+        let content = fs::read(&path)?;
+        let mut metadata = FileMetadata::read(&path, config.leaf_metadata)?;
+        if let Some(uploaded) = read_metadata(&config.directory, filename)? {
+            metadata.mode = uploaded.mode.or(metadata.mode);
+            metadata.mtime = uploaded.mtime.or(metadata.mtime);
+        }
+        content_entry(config, &content, metadata)
+    };
+    with_sidecars(config, filename, file)
+}
+
+/// Index entry of a chunked file, whose leaf is built on its chunk root.
+fn manifest_entry(config: &ServerConfig, manifest: &ChunkManifest) -> IndexedFile {
+    let metadata = committed_metadata(config, manifest.metadata.clone());
+    IndexedFile {
+        leaf_hash: utils::leaf_hash_from_content_hash(manifest.chunk_root(), metadata.as_ref()),
+        size: manifest.size,
+        metadata,
+        salt: None,
+    }
+}
+
+fn content_entry(config: &ServerConfig, content: &[u8], metadata: FileMetadata) -> IndexedFile {
+    let metadata = committed_metadata(config, Some(metadata));
+    IndexedFile {
+        leaf_hash: utils::file_leaf_hash(content, metadata.as_ref(), false),
+        size: content.len() as u64,
+        metadata,
+        salt: None,
+    }
+}
+
+/// The metadata fields the tree commits to, if any.
+fn committed_metadata(
+    config: &ServerConfig,
+    metadata: Option<FileMetadata>,
+) -> Option<FileMetadata> {
+    metadata
+        .map(|metadata| metadata.select(config.leaf_metadata))
+        .filter(|_| !config.leaf_metadata.is_empty())
+}
+
+/// Salts the leaf with the file's stored salt, or replaces it with the keyed
+/// leaf hash sent by the client.
+fn with_sidecars(
+    config: &ServerConfig,
+    filename: &str,
+    mut file: IndexedFile,
+) -> io::Result<IndexedFile> {
+    file.salt = read_sidecar(&config.directory, SALTS, filename)?;
+    if let Some(salt) = &file.salt {
+        file.leaf_hash = utils::salted_leaf_hash(salt, &file.leaf_hash);
+    }
+    if config.node_key.is_some() {
+        file.leaf_hash =
+            read_sidecar(&config.directory, LEAF_HASHES, filename)?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No keyed leaf hash for {}", filename),
+                )
+            })?;
+    }
+    Ok(file)
+}
+
+/// Attempts at reading the files on disk while no upload is indexed, before
+/// giving up on checking the index.
+const INDEX_CHECK_ATTEMPTS: usize = 3;
+
+/// Compares the cached index with the files on disk and, if they differ,
+/// replaces it with what is on disk.
+async fn check_index(config: ServerConfig) -> Result<Json<IndexCheck>, StatusCode> {
+    let generation = |config: &ServerConfig| {
+        let index = config.index.lock().unwrap();
+        index.as_ref().map(|index| index.generation)
+    };
+
+    // The files are read on the blocking pool without the lock. An upload
+    // indexed meanwhile may be missing from what was read, so the files are
+    // read again rather than replacing the index with them
+    for _ in 0..INDEX_CHECK_ATTEMPTS {
+        let read_at = generation(&config);
+        let on_disk = match read_files(&config).await {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("Failed to index files in {}: {:?}", config.directory, e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+
+        let mut index = config.index.lock().unwrap();
+        if index.as_ref().map(|index| index.generation) == read_at {
+            return Ok(Json(replace_mismatched(&config, &mut index, on_disk)));
+        }
+    }
+
+    eprintln!(
+        "Files in {} kept changing while checking the index",
+        config.directory
+    );
+    Err(StatusCode::SERVICE_UNAVAILABLE)
+}
+
+/// Compares the index with `on_disk` and reloads it if they differ.
+fn replace_mismatched(
+    config: &ServerConfig,
+    index: &mut Option<StoreIndex>,
+    on_disk: FileIndex,
+) -> IndexCheck {
+    let index = index.get_or_insert_with(|| StoreIndex::new(config, on_disk.clone()));
+    let cached = &index.files;
+    let filenames: BTreeSet<&String> = cached.keys().chain(on_disk.keys()).collect();
+    let mismatched: Vec<String> = filenames
+        .into_iter()
+        .filter(|filename| cached.get(*filename) != on_disk.get(*filename))
+        .cloned()
+        .collect();
+
+    let files = on_disk.len() as u64;
+    if !mismatched.is_empty() {
+        eprintln!(
+            "Index of {} differs from disk for {} files, reloading it",
+            config.directory,
+            mismatched.len()
+        );
+        config.proof_cache.lock().unwrap().invalidate();
+        index.reload(config, on_disk);
+    }
+    IndexCheck { files, mismatched }
+}
+
+/// The tree over an index, with one leaf per file in file name order.
+fn index_tree(config: &ServerConfig, files: &FileIndex) -> MerkleTree {
+    let leaves = files
+        .iter()
+        .map(|(filename, file)| MerkleLeaf::from_hash(Some(filename.clone()), file.leaf_hash))
+        .collect();
+    MerkleTree::from_leaves_with_node_key(leaves, config.arity, config.node_key.as_deref())
+}
+
+async fn request_file(
+    config: ServerConfig,
     Path(filename): Path<String>,
+    Query(query): Query<ProofQuery>,
 ) -> Result<Json<FileResponse>, StatusCode> {
-    let file_path = format!("{}/{}", directory, filename);
+    let directory = &config.directory;
+    let filename = path_file_name(&filename)?;
 
-    let content = match fs::read(file_path) {
+    let content = if config.chunking {
+        ChunkStore::new(directory).read_file(filename.as_str())
+    } else {
+        fs::read(std::path::Path::new(directory).join(&filename))
+    };
+    let content = match content {
         Ok(content) => content,
         Err(e) => {
             eprintln!("Failed to read file {}/{}: {:?}", directory, filename, e);
@@ -70,11 +797,163 @@ async fn request_file(
         }
     };
 
-    let files = utils::parse_files(&directory);
-    let merkle_tree = MerkleTree::new(&files);
+    let file_response = prove_file(&config, filename.as_str(), query.root).await?;
+    Ok(Json(FileResponse {
+        content,
+        ..file_response
+    }))
+}
 
-    match merkle_tree.generate_merkle_proof(&filename, &files) {
-        Some(proof_list) => Ok(Json(FileResponse::new(filename, content, proof_list))),
+/// Streams a chunked file so the client can verify each chunk as it arrives,
+/// see `streaming::encode_stream`.
+async fn stream_file(
+    config: ServerConfig,
+    Path(filename): Path<String>,
+    Query(query): Query<ProofQuery>,
+) -> Result<Vec<u8>, StatusCode> {
+    let directory = &config.directory;
+    let filename = path_file_name(&filename)?;
+    if !config.chunking {
+        eprintln!("Cannot stream {}, files are not chunked", filename);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let chunk_store = ChunkStore::new(directory);
+    let manifest = match chunk_store.get_manifest(filename.as_str()) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            eprintln!(
+                "Failed to read manifest of {}/{}: {:?}",
+                directory, filename, e
+            );
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let header = StreamHeader {
+        file: prove_file(&config, filename.as_str(), query.root).await?,
+        chunk_root: manifest.chunk_root(),
+        chunk_count: manifest.chunks.len() as u64,
+    };
+
+    streaming::encode_stream(&header, &manifest.chunks, |hash| {
+        chunk_store.get_chunk(hash)
+    })
+    .map_err(|e| {
+        eprintln!("Failed to stream {}/{}: {:?}", directory, filename, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// The proof, committed metadata and salt of a stored file, without its
+/// contents. The proof is against `root` if given, which must be a root the
+/// file has not changed since, or against the current root.
+async fn prove_file(
+    config: &ServerConfig,
+    filename: &str,
+    root: Option<Digest>,
+) -> Result<FileResponse, StatusCode> {
+    let directory = &config.directory;
+
+    let indexed = with_index(config, |index| {
+        let (metadata, salt) = match index.files.get(filename) {
+            Some(file) => (file.metadata.clone(), file.salt),
+            None => (None, None),
+        };
+        let latest = index.versions.latest();
+        let merkle_tree = match root {
+            Some(root) => index.versions.tree_for_root(&root),
+            None => Some(latest),
+        };
+        let leaf = |merkle_tree: &MerkleTree| {
+            merkle_tree
+                .leaf_index(filename)
+                .and_then(|leaf| merkle_tree.leaf_hash(leaf))
+        };
+        merkle_tree.map(|merkle_tree| {
+            let proven = leaf(merkle_tree);
+            let changed = proven != leaf(latest);
+            let current = merkle_tree.get_root_hash() == latest.get_root_hash();
+            let proven = proven.is_some();
+            (
+                merkle_tree.clone(),
+                proven,
+                changed,
+                current,
+                metadata,
+                salt,
+            )
+        })
+    })
+    .await;
+    let (merkle_tree, proven, changed, current, metadata, salt) = match indexed {
+        Ok(Some(indexed)) => indexed,
+        Ok(None) => {
+            eprintln!(
+                "Proof of {}/{} requested against an unknown root",
+                directory, filename
+            );
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Err(e) => {
+            eprintln!("Failed to index files in {}: {:?}", directory, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    if root.is_some() && !proven {
+        eprintln!(
+            "{}/{} is not in the requested root, cannot prove it",
+            directory, filename
+        );
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if changed {
+        eprintln!(
+            "{}/{} changed since the requested root, cannot prove it",
+            directory, filename
+        );
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let root = merkle_tree.get_root_hash();
+    // Earlier trees are proven on demand, warming them would evict the proofs
+    // against the current root
+    if config.eager_proofs && current {
+        let capacity = {
+            let proof_cache = config.proof_cache.lock().unwrap();
+            (!proof_cache.is_warmed(&root)).then(|| proof_cache.capacity())
+        };
+        // Generated without the lock, so other requests are served meanwhile
+        if let Some(capacity) = capacity {
+            let proofs = proof_cache::generate_proofs(&merkle_tree, capacity);
+            config.proof_cache.lock().unwrap().warm(&root, proofs);
+        }
+    }
+    let cached = config.proof_cache.lock().unwrap().get(&root, filename);
+    let proof = cached.or_else(|| {
+        let proof = merkle_tree.generate_proof(filename)?;
+        config
+            .proof_cache
+            .lock()
+            .unwrap()
+            .insert(&root, filename, proof.clone());
+        Some(proof)
+    });
+
+    let file_response = proof.map(|proof| match proof {
+        MerkleProof::List(proof_list) => {
+            FileResponse::new(filename.to_owned(), Vec::new(), proof_list)
+        }
+        MerkleProof::Group(group_proof) => {
+            FileResponse::with_group_proof(filename.to_owned(), Vec::new(), group_proof)
+        }
+    });
+
+    match file_response {
+        Some(file_response) => Ok(FileResponse {
+            metadata,
+            salt,
+            ..file_response
+        }),
         None => {
             eprintln!(
                 "Failed to generate merkle proof for {}/{}",
@@ -85,27 +964,481 @@ async fn request_file(
     }
 }
 
+/// Name, size and leaf hash of each of `files`, in the order given.
+fn listing_entries<'a>(
+    files: impl Iterator<Item = (&'a String, &'a IndexedFile)>,
+) -> Vec<ListingEntry> {
+    files
+        .map(|(filename, file)| ListingEntry {
+            filename: filename.clone(),
+            size: file.size,
+            leaf_hash: file.leaf_hash,
+        })
+        .collect()
+}
+
+/// Most files listed per page.
+const MAX_LISTING_PAGE: u64 = 1000;
+
+/// Lists a page of the stored files in leaf order, with a range proof tying
+/// it to the current root.
+async fn list_files(
+    config: ServerConfig,
+    Query(query): Query<ListingQuery>,
+) -> Result<Json<ListingPage>, StatusCode> {
+    if query.limit == 0 || query.limit > MAX_LISTING_PAGE {
+        eprintln!("Rejected listing page of {} files", query.limit);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let page = with_index(&config, |index| {
+        let start = usize::try_from(query.offset)
+            .unwrap_or(usize::MAX)
+            .min(index.files.len());
+        let end = start
+            .saturating_add(query.limit as usize)
+            .min(index.files.len());
+        let entries = listing_entries(index.files.iter().skip(start).take(end - start));
+        (entries, start..end, Arc::clone(&index.listing_tree))
+    })
+    .await;
+
+    match page {
+        Ok((entries, range, listing_tree)) => Ok(Json(ListingPage {
+            entries,
+            proof: listing_tree.generate_range_proof(range),
+        })),
+        Err(e) => {
+            eprintln!("Failed to index files in {}: {:?}", config.directory, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn signed_tree_head(config: ServerConfig) -> Result<Json<SignedTreeHead>, StatusCode> {
+    let signer = match &config.signer {
+        Some(signer) => signer,
+        None => {
+            eprintln!("Tree head requested but the server has no signing key");
+            return Err(StatusCode::NOT_FOUND);
+        }
+    };
+
+    let indexed = with_index(&config, |index| TreeHead {
+        leaf_metadata: config.leaf_metadata,
+        chunking: config.chunking,
+        keyed: config.node_key.is_some(),
+        salted: index.files.values().any(|file| file.salt.is_some()),
+        listing_root: Some(index.listing_tree.get_root_hash()),
+        ..index.versions.latest().tree_head()
+    })
+    .await;
+    let tree_head = match indexed {
+        Ok(tree_head) => tree_head,
+        Err(e) => {
+            eprintln!("Failed to index files in {}: {:?}", config.directory, e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let mut signer = signer.lock().unwrap();
+    if let Some((signed, signed_at)) = &signer.last_signed {
+        let head = &signed.tree_head;
+        let current = head.root_hash == tree_head.root_hash
+            && head.leaf_count == tree_head.leaf_count
+            && head.listing_root == tree_head.listing_root;
+        if current || signed_at.elapsed() < signer.interval {
+            return Ok(Json(signed.clone()));
+        }
+    }
+
+    let message = SignedTreeHead::message(&tree_head, config.bucket.as_deref());
+    let signature = match signer.key.lock().unwrap().sign(&message) {
+        Ok(signature) => signature,
+        Err(e) => {
+            eprintln!("Failed to sign tree head: {:?}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let signed = SignedTreeHead {
+        tree_head,
+        bucket: config.bucket.clone(),
+        signature,
+    };
+    signer.last_signed = Some((signed.clone(), Instant::now()));
+    Ok(Json(signed))
+}
+
+async fn create_bucket(
+    buckets: Buckets,
+    Json(body): Json<CreateBucketRequest>,
+) -> Result<Json<BucketSummary>, StatusCode> {
+    if !valid_bucket_name(&body.name) {
+        eprintln!("Rejected bucket name {:?}", body.name);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    match buckets.create(&body.name) {
+        Ok(true) => Ok(Json(BucketSummary {
+            name: body.name,
+            files: 0,
+            root_hash: MerkleTree::EMPTY_ROOT,
+        })),
+        Ok(false) => {
+            eprintln!("Bucket {} already exists", body.name);
+            Err(StatusCode::CONFLICT)
+        }
+        Err(e) => {
+            eprintln!("Failed to create bucket {}: {:?}", body.name, e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn list_buckets(buckets: Buckets) -> Result<Json<Vec<BucketSummary>>, StatusCode> {
+    let configs = buckets.buckets.lock().unwrap().clone();
+    let mut summaries = Vec::new();
+    for (name, config) in configs {
+        let root = with_index(&config, |index| {
+            (
+                index.files.len() as u64,
+                index.versions.latest().get_root_hash(),
+            )
+        })
+        .await;
+        match root {
+            Ok((files, root_hash)) => summaries.push(BucketSummary {
+                name,
+                files,
+                root_hash,
+            }),
+            Err(e) => {
+                eprintln!("Failed to index bucket {}: {:?}", name, e);
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+    Ok(Json(summaries))
+}
+
+async fn bucket_upload(
+    buckets: Buckets,
+    Path(bucket): Path<String>,
+    body: Json<UploadRequest>,
+) -> Result<Json<UploadResponse>, StatusCode> {
+    upload(buckets.config(&bucket)?, body).await
+}
+
+async fn bucket_missing_chunks(
+    buckets: Buckets,
+    Path(bucket): Path<String>,
+    body: Json<MissingChunksRequest>,
+) -> Result<Json<MissingChunksResponse>, StatusCode> {
+    Ok(missing_chunks(buckets.config(&bucket)?.directory, body).await)
+}
+
+async fn bucket_upload_chunk(
+    buckets: Buckets,
+    Path(bucket): Path<String>,
+    body: Json<ChunkUploadRequest>,
+) -> Result<Json<UploadResponse>, StatusCode> {
+    upload_chunk(buckets.config(&bucket)?.directory, body).await
+}
+
+async fn bucket_upload_manifest(
+    buckets: Buckets,
+    Path(bucket): Path<String>,
+    body: Json<ManifestUploadRequest>,
+) -> Result<Json<UploadResponse>, StatusCode> {
+    upload_manifest(buckets.config(&bucket)?, body).await
+}
+
+async fn bucket_request_file(
+    buckets: Buckets,
+    Path((bucket, filename)): Path<(String, String)>,
+    query: Query<ProofQuery>,
+) -> Result<Json<FileResponse>, StatusCode> {
+    request_file(buckets.config(&bucket)?, Path(filename), query).await
+}
+
+async fn bucket_list_files(
+    buckets: Buckets,
+    Path(bucket): Path<String>,
+    query: Query<ListingQuery>,
+) -> Result<Json<ListingPage>, StatusCode> {
+    list_files(buckets.config(&bucket)?, query).await
+}
+
+async fn bucket_stream_file(
+    buckets: Buckets,
+    Path((bucket, filename)): Path<(String, String)>,
+    query: Query<ProofQuery>,
+) -> Result<Vec<u8>, StatusCode> {
+    stream_file(buckets.config(&bucket)?, Path(filename), query).await
+}
+
+async fn bucket_check_index(
+    buckets: Buckets,
+    Path(bucket): Path<String>,
+) -> Result<Json<IndexCheck>, StatusCode> {
+    check_index(buckets.config(&bucket)?).await
+}
+
+async fn bucket_signed_tree_head(
+    buckets: Buckets,
+    Path(bucket): Path<String>,
+) -> Result<Json<SignedTreeHead>, StatusCode> {
+    signed_tree_head(buckets.config(&bucket)?).await
+}
+
+async fn bucket_proof_cache_stats(
+    buckets: Buckets,
+    Path(bucket): Path<String>,
+) -> Result<Json<ProofCacheStats>, StatusCode> {
+    Ok(proof_cache_stats(buckets.config(&bucket)?).await)
+}
+
+async fn proof_cache_stats(config: ServerConfig) -> Json<ProofCacheStats> {
+    Json(config.proof_cache.lock().unwrap().stats())
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
 
     let directory = args.path;
+    let signer = args.signing_key.as_ref().map(|path| {
+        let key = SigningKey::load_or_generate(path, args.signing_key_height)
+            .unwrap_or_else(|e| panic!("Failed to load signing key {}: {}", path, e));
+        let public_key = serde_json::to_vec_pretty(&key.public_key()).unwrap();
+        fs::write(format!("{}.pub", path), public_key)
+            .unwrap_or_else(|e| panic!("Failed to write public key {}.pub: {}", path, e));
+        println!(
+            "Signing tree heads with {}, {} signatures left",
+            path,
+            key.remaining()
+        );
+        Arc::new(Mutex::new(TreeHeadSigner {
+            key: Arc::new(Mutex::new(key)),
+            interval: Duration::from_secs(args.sign_interval),
+            last_signed: None,
+        }))
+    });
+    let node_key = args.node_key_file.as_ref().map(|path| {
+        let key =
+            fs::read(path).unwrap_or_else(|e| panic!("Failed to read node key {}: {}", path, e));
+        if key.is_empty() {
+            panic!("Node key file {} is empty", path);
+        }
+        Arc::from(key)
+    });
+    let config = ServerConfig {
+        directory: directory.clone(),
+        arity: args.arity,
+        leaf_metadata: args.leaf_metadata,
+        chunking: args.chunking,
+        bucket: None,
+        node_key,
+        proof_cache: Arc::new(Mutex::new(ProofCache::new(args.proof_cache_size))),
+        eager_proofs: args.eager_proofs,
+        tree_versions: args.tree_versions,
+        signer,
+        index: Arc::new(Mutex::new(None)),
+    };
+    if let Err(e) = create_dir_all(&directory) {
+        panic!("Failed to create directory {}: {}", directory, e);
+    }
+    match with_index(&config, |index| index.files.len()).await {
+        Ok(count) => println!("Indexed {} files in {}", count, directory),
+        Err(e) => panic!("Failed to index files in {}: {}", directory, e),
+    }
+    let buckets = Buckets::load(&config, args.proof_cache_size)
+        .unwrap_or_else(|e| panic!("Failed to load buckets in {}: {}", directory, e));
 
-    let app = Router::new()
+    // Routes are boxed in small groups, as every route nests the router's type
+    let upload_routes = Router::new()
         .route(
             "/upload",
+            post({
+                let config = config.clone();
+                move |body: Json<UploadRequest>| upload(config.clone(), body)
+            }),
+        )
+        .route(
+            "/chunks/missing",
             post({
                 let directory = directory.clone();
-                move |body: Json<UploadRequest>| upload(directory.clone(), body)
+                move |body: Json<MissingChunksRequest>| missing_chunks(directory.clone(), body)
+            }),
+        )
+        .route(
+            "/chunks",
+            post({
+                let directory = directory.clone();
+                move |body: Json<ChunkUploadRequest>| upload_chunk(directory.clone(), body)
+            }),
+        )
+        .route(
+            "/manifest",
+            post({
+                let config = config.clone();
+                move |body: Json<ManifestUploadRequest>| upload_manifest(config.clone(), body)
+            }),
+        )
+        .boxed();
+    let store_routes = Router::new()
+        .route(
+            "/file/:filename",
+            get({
+                let config = config.clone();
+                move |filename: Path<String>, query: Query<ProofQuery>| {
+                    request_file(config.clone(), filename, query)
+                }
+            }),
+        )
+        .route(
+            "/files",
+            get({
+                let config = config.clone();
+                move |query: Query<ListingQuery>| list_files(config.clone(), query)
+            }),
+        )
+        .route(
+            "/stream/:filename",
+            get({
+                let config = config.clone();
+                move |filename: Path<String>, query: Query<ProofQuery>| {
+                    stream_file(config.clone(), filename, query)
+                }
+            }),
+        )
+        .route(
+            "/index/check",
+            get({
+                let config = config.clone();
+                move || check_index(config.clone())
+            }),
+        )
+        .route(
+            "/tree-head",
+            get({
+                let config = config.clone();
+                move || signed_tree_head(config.clone())
+            }),
+        )
+        .route(
+            "/stats/proof-cache",
+            get({
+                let config = config.clone();
+                move || proof_cache_stats(config.clone())
+            }),
+        )
+        .boxed();
+    let bucket_upload_routes = Router::new()
+        .route(
+            "/buckets",
+            get({
+                let buckets = buckets.clone();
+                move || list_buckets(buckets.clone())
+            })
+            .post({
+                let buckets = buckets.clone();
+                move |body: Json<CreateBucketRequest>| create_bucket(buckets.clone(), body)
+            }),
+        )
+        .route(
+            "/buckets/:bucket/upload",
+            post({
+                let buckets = buckets.clone();
+                move |bucket: Path<String>, body: Json<UploadRequest>| {
+                    bucket_upload(buckets.clone(), bucket, body)
+                }
+            }),
+        )
+        .route(
+            "/buckets/:bucket/chunks/missing",
+            post({
+                let buckets = buckets.clone();
+                move |bucket: Path<String>, body: Json<MissingChunksRequest>| {
+                    bucket_missing_chunks(buckets.clone(), bucket, body)
+                }
+            }),
+        )
+        .route(
+            "/buckets/:bucket/chunks",
+            post({
+                let buckets = buckets.clone();
+                move |bucket: Path<String>, body: Json<ChunkUploadRequest>| {
+                    bucket_upload_chunk(buckets.clone(), bucket, body)
+                }
+            }),
+        )
+        .route(
+            "/buckets/:bucket/manifest",
+            post({
+                let buckets = buckets.clone();
+                move |bucket: Path<String>, body: Json<ManifestUploadRequest>| {
+                    bucket_upload_manifest(buckets.clone(), bucket, body)
+                }
+            }),
+        )
+        .boxed();
+    let bucket_file_routes = Router::new()
+        .route(
+            "/buckets/:bucket/file/:filename",
+            get({
+                let buckets = buckets.clone();
+                move |path: Path<(String, String)>, query: Query<ProofQuery>| {
+                    bucket_request_file(buckets.clone(), path, query)
+                }
+            }),
+        )
+        .route(
+            "/buckets/:bucket/files",
+            get({
+                let buckets = buckets.clone();
+                move |bucket: Path<String>, query: Query<ListingQuery>| {
+                    bucket_list_files(buckets.clone(), bucket, query)
+                }
+            }),
+        )
+        .route(
+            "/buckets/:bucket/stream/:filename",
+            get({
+                let buckets = buckets.clone();
+                move |path: Path<(String, String)>, query: Query<ProofQuery>| {
+                    bucket_stream_file(buckets.clone(), path, query)
+                }
+            }),
+        )
+        .boxed();
+    let bucket_store_routes = Router::new()
+        .route(
+            "/buckets/:bucket/index/check",
+            get({
+                let buckets = buckets.clone();
+                move |bucket: Path<String>| bucket_check_index(buckets.clone(), bucket)
+            }),
+        )
+        .route(
+            "/buckets/:bucket/tree-head",
+            get({
+                let buckets = buckets.clone();
+                move |bucket: Path<String>| bucket_signed_tree_head(buckets.clone(), bucket)
             }),
         )
         .route(
-            "/file/:filename",
+            "/buckets/:bucket/stats/proof-cache",
             get({
-                let directory = directory.clone();
-                move |filename: Path<String>| request_file(directory.clone(), filename)
+                let buckets = buckets.clone();
+                move |bucket: Path<String>| bucket_proof_cache_stats(buckets.clone(), bucket)
             }),
-        );
+        )
+        .boxed();
+    let app = upload_routes
+        .or(store_routes)
+        .or(bucket_upload_routes)
+        .or(bucket_file_routes)
+        .or(bucket_store_routes);
 
     let addr = std::net::SocketAddr::from(([0, 0, 0, 0], args.port));
 
@@ -122,14 +1455,30 @@ async fn main() {
 mod tests {
     use super::*;
     use hyper::{Body, Request};
+    use merkle_rs::chunking::{self, ChunkManifest};
+    use merkle_rs::streaming::StreamDecoder;
     use tempfile::tempdir;
     use tokio_test::block_on;
 
-    impl UploadRequest {
-        pub fn from_req(req: Request<Body>) -> Result<Self, StatusCode> {
-            let bytes = block_on(hyper::body::to_bytes(req.into_body())).unwrap();
-            let body_str = String::from_utf8(bytes.to_vec()).unwrap();
-            serde_json::from_str(&body_str).map_err(|_| StatusCode::BAD_REQUEST)
+    fn upload_request_from_req(req: Request<Body>) -> Result<UploadRequest, StatusCode> {
+        let bytes = block_on(hyper::body::to_bytes(req.into_body())).unwrap();
+        let body_str = String::from_utf8(bytes.to_vec()).unwrap();
+        serde_json::from_str(&body_str).map_err(|_| StatusCode::BAD_REQUEST)
+    }
+
+    fn config(directory: String, arity: usize) -> ServerConfig {
+        ServerConfig {
+            directory,
+            arity,
+            leaf_metadata: MetadataFields::default(),
+            chunking: false,
+            bucket: None,
+            node_key: None,
+            proof_cache: Arc::new(Mutex::new(ProofCache::new(16))),
+            eager_proofs: false,
+            tree_versions: 8,
+            signer: None,
+            index: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -137,6 +1486,10 @@ mod tests {
         let body = UploadRequest {
             content: content.to_string(),
             filename: filename.to_string(),
+            metadata: None,
+            leaf_hash: None,
+            leaf_tag: None,
+            salt: None,
         };
         let body = serde_json::to_string(&body).unwrap();
         Request::builder()
@@ -150,14 +1503,14 @@ mod tests {
     fn upload_two_files(directory: String) {
         let req = mock_upload_request("SGVsbG8gV29ybGQ=", "hello1.txt");
         let _ = block_on(upload(
-            directory.clone(),
-            Json(UploadRequest::from_req(req).unwrap()),
+            config(directory.clone(), 2),
+            Json(upload_request_from_req(req).unwrap()),
         ));
 
         let req = mock_upload_request("SGVsbG8gV29ybGQ=", "hello2.txt");
         let _: Result<Json<UploadResponse>, StatusCode> = block_on(upload(
-            directory,
-            Json(UploadRequest::from_req(req).unwrap()),
+            config(directory, 2),
+            Json(upload_request_from_req(req).unwrap()),
         ));
     }
 
@@ -167,8 +1520,8 @@ mod tests {
         let directory = dir.path().to_str().unwrap().to_string();
         let req = mock_upload_request("SGVsbG8gV29ybGQ=", "hello.txt");
         let resp = block_on(upload(
-            directory.clone(),
-            Json(UploadRequest::from_req(req).unwrap()),
+            config(directory.clone(), 2),
+            Json(upload_request_from_req(req).unwrap()),
         ));
 
         assert!(resp.is_ok());
@@ -178,6 +1531,35 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_reject_file_names_escaping_the_store() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().join("store").to_str().unwrap().to_string();
+        for filename in ["../escaped.txt", "/tmp/absolute.txt", ".salts", "nul\0.txt"] {
+            let req = mock_upload_request("SGVsbG8gV29ybGQ=", filename);
+            let resp = block_on(upload(
+                config(directory.clone(), 2),
+                Json(upload_request_from_req(req).unwrap()),
+            ));
+            assert_eq!(resp.err(), Some(StatusCode::BAD_REQUEST));
+        }
+        assert!(!dir.path().join("escaped.txt").exists());
+
+        upload_two_files(directory.clone());
+        let resp = block_on(request_file(
+            config(directory.clone(), 2),
+            Path("..%2Fstore%2Fhello1.txt".to_string()),
+            Query(ProofQuery::default()),
+        ));
+        assert_eq!(resp.err(), Some(StatusCode::BAD_REQUEST));
+        let resp = block_on(request_file(
+            config(directory, 2),
+            Path("hello%31.txt".to_string()),
+            Query(ProofQuery::default()),
+        ));
+        assert_eq!(resp.unwrap().0.filename, "hello1.txt");
+    }
+
     #[test]
     fn test_request_file() {
         let dir = tempdir().unwrap();
@@ -185,7 +1567,11 @@ mod tests {
         upload_two_files(directory.clone());
 
         let filename = "hello1.txt".to_string();
-        let resp = block_on(request_file(directory, Path(filename)));
+        let resp = block_on(request_file(
+            config(directory, 2),
+            Path(filename),
+            Query(ProofQuery::default()),
+        ));
 
         assert!(resp.is_ok());
         let file_response = resp.unwrap().0;
@@ -195,4 +1581,854 @@ mod tests {
             vec![72, 101, 108, 108, 111, 32, 87, 111, 114, 108, 100]
         ); // "Hello World" in bytes
     }
+
+    #[test]
+    fn test_list_files_in_verified_pages() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        for i in 0..5 {
+            let content = general_purpose::STANDARD.encode(format!("file {}", i));
+            let req = mock_upload_request(&content, &format!("{}.txt", i));
+            let body = Json(upload_request_from_req(req).unwrap());
+            assert!(block_on(upload(config(directory.clone(), 4), body)).is_ok());
+        }
+        let mut tree_head = MerkleTree::new_with_arity(
+            (0..5).map(|i| (format!("{}.txt", i), format!("file {}", i))),
+            4,
+        )
+        .tree_head();
+        let entries: Vec<ListingEntry> = (0..5)
+            .map(|i| ListingEntry {
+                filename: format!("{}.txt", i),
+                size: 6,
+                leaf_hash: Digest::of(format!("file {}", i)),
+            })
+            .collect();
+        tree_head.listing_root = Some(utils::listing_tree(&entries, 4).get_root_hash());
+
+        let page = |offset| {
+            let query = ListingQuery { offset, limit: 2 };
+            block_on(list_files(config(directory.clone(), 4), Query(query)))
+                .unwrap()
+                .0
+        };
+        let mut listed = Vec::new();
+        for offset in [0, 2, 4, 6] {
+            let page = page(offset);
+            assert!(utils::verify_listing_page(&page, &tree_head, offset));
+            listed.extend(page.entries.into_iter().map(|entry| entry.filename));
+        }
+        assert_eq!(listed, ["0.txt", "1.txt", "2.txt", "3.txt", "4.txt"]);
+
+        let mut truncated = page(2);
+        truncated.entries.pop();
+        assert!(!utils::verify_listing_page(&truncated, &tree_head, 2));
+        let mut renamed = page(2);
+        renamed.entries[0].filename = "2-renamed.txt".to_string();
+        assert!(!utils::verify_listing_page(&renamed, &tree_head, 2));
+        let mut resized = page(2);
+        resized.entries[1].size = 7;
+        assert!(!utils::verify_listing_page(&resized, &tree_head, 2));
+        let mut without_listing_root = tree_head.clone();
+        without_listing_root.listing_root = None;
+        assert!(!utils::verify_listing_page(
+            &page(0),
+            &without_listing_root,
+            0
+        ));
+        let empty = ListingPage {
+            entries: Vec::new(),
+            proof: None,
+        };
+        assert!(!utils::verify_listing_page(&empty, &tree_head, 4));
+        assert!(!utils::verify_listing_page(&page(0), &tree_head, 2));
+
+        let too_large = ListingQuery {
+            offset: 0,
+            limit: MAX_LISTING_PAGE + 1,
+        };
+        let resp = block_on(list_files(config(directory, 4), Query(too_large)));
+        assert_eq!(resp.err(), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn test_request_file_from_single_file_store() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        let req = mock_upload_request("SGVsbG8gV29ybGQ=", "hello.txt");
+        let _ = block_on(upload(
+            config(directory.clone(), 2),
+            Json(upload_request_from_req(req).unwrap()),
+        ));
+
+        let resp = block_on(request_file(
+            config(directory, 2),
+            Path("hello.txt".to_string()),
+            Query(ProofQuery::default()),
+        ));
+
+        assert!(resp.is_ok());
+        let file_response = resp.unwrap().0;
+        assert_eq!(file_response.merkle_proof.len(), 1);
+        assert!(utils::verify_merkle_proof(
+            file_response.merkle_proof,
+            MerkleTree::from_items([b"Hello World"]).get_root_hash(),
+            file_response.content,
+        ));
+    }
+
+    #[test]
+    fn test_request_file_with_group_proof() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        for i in 0..5 {
+            let req = mock_upload_request("SGVsbG8gV29ybGQ=", &format!("hello{}.txt", i));
+            let _ = block_on(upload(
+                config(directory.clone(), 2),
+                Json(upload_request_from_req(req).unwrap()),
+            ));
+        }
+
+        let resp = block_on(request_file(
+            config(directory.clone(), 4),
+            Path("hello3.txt".to_string()),
+            Query(ProofQuery::default()),
+        ));
+
+        let file_response = resp.unwrap().0;
+        assert!(file_response.merkle_proof.is_empty());
+        let tree_head = MerkleTree::new_with_arity(utils::parse_files(&directory), 4).tree_head();
+        assert!(utils::verify_file_response(&file_response, &tree_head));
+    }
+
+    #[test]
+    fn test_request_file_with_committed_metadata() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        let metadata = FileMetadata {
+            size: Some(11),
+            mode: Some(0o640),
+            mtime: Some(1_600_000_000),
+        };
+        for filename in ["hello1.txt", "hello2.txt"] {
+            let body = UploadRequest {
+                content: "SGVsbG8gV29ybGQ=".to_string(),
+                filename: filename.to_string(),
+                metadata: Some(metadata.clone()),
+                leaf_hash: None,
+                leaf_tag: None,
+                salt: None,
+            };
+            assert!(block_on(upload(config(directory.clone(), 2), Json(body))).is_ok());
+        }
+
+        let config = ServerConfig {
+            leaf_metadata: MetadataFields::ALL,
+            ..config(directory, 2)
+        };
+        let resp = block_on(request_file(
+            config,
+            Path("hello2.txt".to_string()),
+            Query(ProofQuery::default()),
+        ));
+
+        let file_response = resp.unwrap().0;
+        assert_eq!(file_response.metadata, Some(metadata.clone()));
+
+        let leaf_data = metadata.leaf_data(b"Hello World");
+        let mut tree_head =
+            MerkleTree::new([("hello1.txt", &leaf_data), ("hello2.txt", &leaf_data)]).tree_head();
+        tree_head.leaf_metadata = MetadataFields::ALL;
+        assert!(utils::verify_file_response(&file_response, &tree_head));
+
+        let mut tampered = file_response;
+        tampered.metadata.as_mut().unwrap().mode = Some(0o777);
+        assert!(!utils::verify_file_response(&tampered, &tree_head));
+    }
+
+    #[test]
+    fn test_keep_uploaded_modes_beside_stored_files() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        let upload_with_mode = |filename: &str, mode: u32| {
+            let body = UploadRequest {
+                content: "SGVsbG8gV29ybGQ=".to_string(),
+                filename: filename.to_string(),
+                metadata: Some(FileMetadata {
+                    size: None,
+                    mode: Some(mode),
+                    mtime: None,
+                }),
+                leaf_hash: None,
+                leaf_tag: None,
+                salt: None,
+            };
+            block_on(upload(config(directory.clone(), 2), Json(body)))
+        };
+
+        assert_eq!(
+            upload_with_mode("setuid.txt", 0o4755).err(),
+            Some(StatusCode::BAD_REQUEST)
+        );
+        assert!(upload_with_mode("locked.txt", 0o000).is_ok());
+
+        let config = ServerConfig {
+            leaf_metadata: MetadataFields {
+                mode: true,
+                ..MetadataFields::default()
+            },
+            ..config(directory, 2)
+        };
+        let file_response = block_on(request_file(
+            config,
+            Path("locked.txt".to_string()),
+            Query(ProofQuery::default()),
+        ))
+        .unwrap()
+        .0;
+        assert_eq!(file_response.content, b"Hello World");
+        assert_eq!(file_response.metadata.unwrap().mode, Some(0o000));
+    }
+
+    #[test]
+    fn test_request_chunked_file() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        let shared: Vec<u8> = (0..100_000u32).map(|i| (i * 7919 % 251) as u8).collect();
+        let files = [
+            ("a.bin", shared.clone()),
+            ("b.bin", [&shared[..], b"tail"].concat()),
+        ];
+
+        for (filename, content) in &files {
+            let chunks: Vec<&[u8]> = chunking::split_chunks(content);
+            let hashes: Vec<Digest> = chunks.iter().map(Digest::of).collect();
+            let missing = block_on(missing_chunks(
+                directory.clone(),
+                Json(MissingChunksRequest {
+                    chunks: hashes.clone(),
+                }),
+            ))
+            .0
+            .missing;
+            if *filename == "b.bin" {
+                assert!(missing.len() < hashes.len());
+            }
+            for chunk in chunks {
+                if missing.contains(&Digest::of(chunk)) {
+                    let body = ChunkUploadRequest {
+                        content: general_purpose::STANDARD.encode(chunk),
+                    };
+                    assert!(block_on(upload_chunk(directory.clone(), Json(body))).is_ok());
+                }
+            }
+            let body = ManifestUploadRequest {
+                filename: filename.to_string(),
+                manifest: ChunkManifest {
+                    size: content.len() as u64,
+                    chunks: hashes,
+                    metadata: None,
+                },
+                leaf_hash: None,
+                leaf_tag: None,
+                salt: None,
+            };
+            assert!(block_on(upload_manifest(config(directory.clone(), 2), Json(body))).is_ok());
+        }
+
+        let config = ServerConfig {
+            chunking: true,
+            ..config(directory, 2)
+        };
+        let resp = block_on(request_file(
+            config.clone(),
+            Path("b.bin".to_string()),
+            Query(ProofQuery::default()),
+        ));
+
+        let file_response = resp.unwrap().0;
+        assert_eq!(file_response.content, files[1].1);
+        let leaves = files
+            .iter()
+            .map(|(filename, content)| {
+                let leaf_hash = utils::file_leaf_hash(content, None, true);
+                MerkleLeaf::from_hash(Some(filename.to_string()), leaf_hash)
+            })
+            .collect();
+        let mut tree_head = MerkleTree::from_leaves(leaves).tree_head();
+        tree_head.chunking = true;
+        assert!(utils::verify_file_response(&file_response, &tree_head));
+
+        let stream = block_on(stream_file(
+            config,
+            Path("b.bin".to_string()),
+            Query(ProofQuery::default()),
+        ))
+        .unwrap();
+        let (header, header_len) = streaming::decode_header(&stream).unwrap().unwrap();
+        assert!(utils::verify_file_proof_for_content_hash(
+            &header.file,
+            header.chunk_root,
+            &tree_head,
+            None
+        ));
+        let mut decoder = StreamDecoder::new(header.chunk_root, header.chunk_count).unwrap();
+        let mut content = Vec::new();
+        decoder.update(&stream[header_len..], &mut content).unwrap();
+        assert_eq!(decoder.finish().unwrap(), files[1].1.len() as u64);
+        assert_eq!(content, files[1].1);
+    }
+
+    #[test]
+    fn test_reject_manifest_with_missing_chunks() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        let body = ManifestUploadRequest {
+            filename: "a.bin".to_string(),
+            manifest: ChunkManifest {
+                size: 5,
+                chunks: vec![Digest::of(b"never uploaded")],
+                metadata: None,
+            },
+            leaf_hash: None,
+            leaf_tag: None,
+            salt: None,
+        };
+
+        let resp = block_on(upload_manifest(config(directory, 2), Json(body)));
+
+        assert_eq!(resp.err(), Some(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn test_serve_cached_proofs_until_upload() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        let config = config(directory, 2);
+        for filename in ["hello1.txt", "hello2.txt", "hello3.txt"] {
+            let req = mock_upload_request("SGVsbG8gV29ybGQ=", filename);
+            let _ = block_on(upload(
+                config.clone(),
+                Json(upload_request_from_req(req).unwrap()),
+            ));
+        }
+
+        let first = block_on(request_file(
+            config.clone(),
+            Path("hello1.txt".to_string()),
+            Query(ProofQuery::default()),
+        ));
+        let second = block_on(request_file(
+            config.clone(),
+            Path("hello1.txt".to_string()),
+            Query(ProofQuery::default()),
+        ));
+
+        assert_eq!(
+            format!("{:?}", first.unwrap().0.merkle_proof),
+            format!("{:?}", second.unwrap().0.merkle_proof)
+        );
+        let stats = block_on(proof_cache_stats(config.clone())).0;
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+        let req = mock_upload_request("SGVsbG8gV29ybGQ=", "hello4.txt");
+        let _ = block_on(upload(
+            config.clone(),
+            Json(upload_request_from_req(req).unwrap()),
+        ));
+        let stats = block_on(proof_cache_stats(config.clone())).0;
+        assert_eq!((stats.entries, stats.invalidations), (0, 1));
+    }
+
+    #[test]
+    fn test_serve_from_index_updated_on_upload() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        upload_two_files(directory.clone());
+        let config = config(directory.clone(), 2);
+        assert_eq!(
+            block_on(with_index(&config, |index| index.files.len())).unwrap(),
+            2
+        );
+
+        let req = mock_upload_request("SGVsbG8gV29ybGQ=", "hello3.txt");
+        let body = Json(upload_request_from_req(req).unwrap());
+        assert!(block_on(upload(config.clone(), body)).is_ok());
+        let resp = block_on(request_file(
+            config.clone(),
+            Path("hello3.txt".to_string()),
+            Query(ProofQuery::default()),
+        ));
+        let tree_head = MerkleTree::new(
+            ["hello1.txt", "hello2.txt", "hello3.txt"].map(|filename| (filename, "Hello World")),
+        )
+        .tree_head();
+        assert!(utils::verify_file_response(&resp.unwrap().0, &tree_head));
+
+        // Changed behind the server's back, so only the check notices
+        fs::write(format!("{}/hello2.txt", directory), "changed").unwrap();
+        let stale = block_on(request_file(
+            config.clone(),
+            Path("hello2.txt".to_string()),
+            Query(ProofQuery::default()),
+        ));
+        assert_eq!(stale.unwrap().0.content, b"changed");
+        assert_eq!(cached_root(&config), tree_head.root_hash);
+
+        let check = block_on(check_index(config.clone())).unwrap().0;
+        assert_eq!(
+            (check.files, check.mismatched),
+            (3, vec!["hello2.txt".to_owned()])
+        );
+        assert_ne!(cached_root(&config), tree_head.root_hash);
+        assert!(block_on(check_index(config))
+            .unwrap()
+            .0
+            .mismatched
+            .is_empty());
+    }
+
+    #[test]
+    fn test_prove_files_against_earlier_roots() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        let config = config(directory.clone(), 2);
+        upload_two_files(directory.clone());
+        let first_head = MerkleTree::new(utils::parse_files(&directory)).tree_head();
+        assert_eq!(cached_root(&config), first_head.root_hash);
+        let prove = |filename: &str, root: Option<Digest>| {
+            block_on(request_file(
+                config.clone(),
+                Path(filename.to_string()),
+                Query(ProofQuery { root }),
+            ))
+        };
+
+        for (content, filename) in [("SGk=", "hello0.txt"), ("Y2hhbmdlZA==", "hello2.txt")] {
+            let req = mock_upload_request(content, filename);
+            let body = Json(upload_request_from_req(req).unwrap());
+            assert!(block_on(upload(config.clone(), body)).is_ok());
+        }
+        assert_ne!(cached_root(&config), first_head.root_hash);
+
+        let file_response = prove("hello1.txt", Some(first_head.root_hash)).unwrap().0;
+        assert!(utils::verify_file_response(&file_response, &first_head));
+        assert_eq!(
+            prove("hello2.txt", Some(first_head.root_hash)).unwrap_err(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            prove("hello0.txt", Some(first_head.root_hash)).unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            prove("hello1.txt", Some(Digest::of(b"unknown root"))).unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+
+        // Reloading the index keeps the earlier versions
+        fs::write(format!("{}/hello0.txt", directory), "changed").unwrap();
+        let check = block_on(check_index(config.clone())).unwrap().0;
+        assert_eq!(check.mismatched, vec!["hello0.txt".to_owned()]);
+        let file_response = prove("hello1.txt", Some(first_head.root_hash)).unwrap().0;
+        assert!(utils::verify_file_response(&file_response, &first_head));
+        let current = MerkleTree::new(utils::parse_files(&directory)).tree_head();
+        let file_response = prove("hello0.txt", None).unwrap().0;
+        assert!(utils::verify_file_response(&file_response, &current));
+    }
+
+    #[test]
+    fn test_update_cached_trees_on_upload() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        upload_two_files(directory.clone());
+        let config = config(directory, 2);
+        let latest = |config: &ServerConfig| {
+            block_on(with_index(config, |index| {
+                let latest = index.versions.latest();
+                (index.versions.version_count(), latest.root.children.clone())
+            }))
+            .unwrap()
+        };
+        let (versions, children) = latest(&config);
+        let _ = block_on(request_file(
+            config.clone(),
+            Path("hello1.txt".to_string()),
+            Query(ProofQuery::default()),
+        ));
+        let _ = block_on(signed_tree_head(config.clone()));
+        let (versions_after, children_after) = latest(&config);
+        assert_eq!(versions, versions_after);
+        assert!(children
+            .iter()
+            .zip(&children_after)
+            .all(|(before, after)| Arc::ptr_eq(before, after)));
+
+        for (content, filename) in [("Y2hhbmdlZA==", "hello1.txt"), ("SGk=", "hello0.txt")] {
+            let req = mock_upload_request(content, filename);
+            let body = Json(upload_request_from_req(req).unwrap());
+            assert!(block_on(upload(config.clone(), body)).is_ok());
+            let (files, tree, listing_tree) = block_on(with_index(&config, |index| {
+                (
+                    index.files.clone(),
+                    index.versions.latest().clone(),
+                    Arc::clone(&index.listing_tree),
+                )
+            }))
+            .unwrap();
+            let rebuilt = index_tree(&config, &files);
+            assert_eq!(
+                (tree.tree_head(), tree.leaves()),
+                (rebuilt.tree_head(), rebuilt.leaves())
+            );
+            let rebuilt = utils::listing_tree(&listing_entries(files.iter()), config.arity);
+            assert_eq!(listing_tree.get_root_hash(), rebuilt.get_root_hash());
+        }
+    }
+
+    #[test]
+    fn test_buckets_keep_independent_trees() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        upload_two_files(directory.clone());
+        let config = config(directory.clone(), 2);
+        let default_root = cached_root(&config);
+        let buckets = Buckets::load(&config, 4).unwrap();
+
+        for name in ["photos", "docs"] {
+            let body = Json(CreateBucketRequest {
+                name: name.to_owned(),
+            });
+            let created = block_on(create_bucket(buckets.clone(), body)).unwrap().0;
+            assert_eq!(created.root_hash, MerkleTree::EMPTY_ROOT);
+        }
+        for (name, status) in [
+            ("photos", StatusCode::CONFLICT),
+            ("../x", StatusCode::BAD_REQUEST),
+        ] {
+            let body = Json(CreateBucketRequest {
+                name: name.to_owned(),
+            });
+            assert_eq!(
+                block_on(create_bucket(buckets.clone(), body)).unwrap_err(),
+                status
+            );
+        }
+
+        for (bucket, content) in [("photos", "SGVsbG8gV29ybGQ="), ("docs", "Y2hhbmdlZA==")] {
+            let req = mock_upload_request(content, "hello1.txt");
+            let body = Json(upload_request_from_req(req).unwrap());
+            assert!(block_on(bucket_upload(
+                buckets.clone(),
+                Path(bucket.to_owned()),
+                body
+            ))
+            .is_ok());
+        }
+
+        let resp = block_on(bucket_request_file(
+            buckets.clone(),
+            Path(("docs".to_owned(), "hello1.txt".to_owned())),
+            Query(ProofQuery::default()),
+        ))
+        .unwrap()
+        .0;
+        let docs_head = MerkleTree::new([("hello1.txt", "changed")]).tree_head();
+        assert!(utils::verify_file_response(&resp, &docs_head));
+
+        let summaries = block_on(list_buckets(buckets.clone())).unwrap().0;
+        let photos_root = MerkleTree::new([("hello1.txt", "Hello World")]).get_root_hash();
+        assert_eq!(
+            summaries
+                .iter()
+                .map(|bucket| (bucket.name.as_str(), bucket.files, bucket.root_hash))
+                .collect::<Vec<_>>(),
+            vec![("docs", 1, docs_head.root_hash), ("photos", 1, photos_root)]
+        );
+        assert_eq!(cached_root(&config), default_root);
+        assert_eq!(
+            block_on(with_index(&config, |index| index.files.len())).unwrap(),
+            2
+        );
+
+        let unknown = block_on(bucket_request_file(
+            buckets.clone(),
+            Path(("music".to_owned(), "hello1.txt".to_owned())),
+            Query(ProofQuery::default()),
+        ));
+        assert_eq!(unknown.unwrap_err(), StatusCode::NOT_FOUND);
+
+        // Picked up again after a restart
+        let reloaded = Buckets::load(&config, 4).unwrap();
+        assert_eq!(block_on(list_buckets(reloaded)).unwrap().0, summaries);
+    }
+
+    fn cached_root(config: &ServerConfig) -> Digest {
+        block_on(with_index(config, |index| {
+            index.versions.latest().get_root_hash()
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_warm_proof_cache_eagerly() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        upload_two_files(directory.clone());
+        let lazy = config(directory, 2);
+        let config = ServerConfig {
+            eager_proofs: true,
+            ..lazy.clone()
+        };
+
+        // A proof cached on demand does not stop the root from being warmed
+        let resp = block_on(request_file(
+            lazy,
+            Path("hello2.txt".to_string()),
+            Query(ProofQuery::default()),
+        ));
+        assert!(resp.is_ok());
+        let resp = block_on(request_file(
+            config.clone(),
+            Path("hello1.txt".to_string()),
+            Query(ProofQuery::default()),
+        ));
+
+        assert!(resp.is_ok());
+        let stats = block_on(proof_cache_stats(config)).0;
+        assert_eq!((stats.entries, stats.hits), (2, 1));
+    }
+
+    #[test]
+    fn test_request_file_with_keyed_leaves() {
+        for arity in [2, 4] {
+            let dir = tempdir().unwrap();
+            let directory = dir.path().to_str().unwrap().to_string();
+            let key = b"client secret";
+            let node_key = utils::node_key(key);
+            let config = ServerConfig {
+                node_key: Some(Arc::from(node_key.as_slice())),
+                ..config(directory, arity)
+            };
+            let leaf_hash = utils::keyed_leaf_hash(key, &Digest::of(b"Hello World"));
+
+            let req = mock_upload_request("SGVsbG8gV29ybGQ=", "hello0.txt");
+            let resp = block_on(upload(
+                config.clone(),
+                Json(upload_request_from_req(req).unwrap()),
+            ));
+            assert_eq!(resp.err(), Some(StatusCode::BAD_REQUEST));
+
+            // Tags made with another key, or for another file, are rejected too
+            let other_node_key = utils::node_key(b"guessed secret");
+            for (tag_key, tag_name) in [(&other_node_key, "hello0.txt"), (&node_key, "hello1.txt")]
+            {
+                let req = mock_upload_request("SGVsbG8gV29ybGQ=", "hello0.txt");
+                let body = UploadRequest {
+                    leaf_hash: Some(leaf_hash),
+                    leaf_tag: Some(utils::keyed_leaf_tag(tag_key, tag_name, &leaf_hash)),
+                    ..upload_request_from_req(req).unwrap()
+                };
+                let resp = block_on(upload(config.clone(), Json(body)));
+                assert_eq!(resp.err(), Some(StatusCode::BAD_REQUEST));
+            }
+
+            for filename in ["hello1.txt", "hello2.txt"] {
+                let req = mock_upload_request("SGVsbG8gV29ybGQ=", filename);
+                let body = UploadRequest {
+                    leaf_hash: Some(leaf_hash),
+                    leaf_tag: Some(utils::keyed_leaf_tag(&node_key, filename, &leaf_hash)),
+                    ..upload_request_from_req(req).unwrap()
+                };
+                assert!(block_on(upload(config.clone(), Json(body))).is_ok());
+            }
+
+            let resp = block_on(request_file(
+                config,
+                Path("hello2.txt".to_string()),
+                Query(ProofQuery::default()),
+            ));
+
+            let file_response = resp.unwrap().0;
+            let leaves = ["hello1.txt", "hello2.txt"]
+                .map(|filename| MerkleLeaf::from_hash(Some(filename.to_owned()), leaf_hash));
+            let mut tree_head =
+                MerkleTree::from_leaves_with_node_key(leaves.into(), arity, Some(&node_key))
+                    .tree_head();
+            tree_head.keyed = true;
+            assert!(utils::verify_file_response_with_key(
+                &file_response,
+                &tree_head,
+                Some(key)
+            ));
+            assert!(!utils::verify_file_response(&file_response, &tree_head));
+            assert!(!utils::verify_file_response_with_key(
+                &file_response,
+                &tree_head,
+                Some(b"guessed secret")
+            ));
+        }
+    }
+
+    #[test]
+    fn test_disclose_only_requested_salt() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        let config = config(directory, 2);
+        let salts = [utils::random_salt(), utils::random_salt()];
+        for (filename, salt) in ["hello1.txt", "hello2.txt"].iter().zip(salts) {
+            let req = mock_upload_request("SGVsbG8gV29ybGQ=", filename);
+            let body = UploadRequest {
+                salt: Some(salt),
+                ..upload_request_from_req(req).unwrap()
+            };
+            assert!(block_on(upload(config.clone(), Json(body))).is_ok());
+        }
+
+        let resp = block_on(request_file(
+            config,
+            Path("hello1.txt".to_string()),
+            Query(ProofQuery::default()),
+        ));
+
+        let file_response = resp.unwrap().0;
+        assert_eq!(file_response.salt, Some(salts[0]));
+        let leaf_hashes =
+            ["hello1.txt", "hello2.txt"]
+                .iter()
+                .zip(&salts)
+                .map(|(filename, salt)| {
+                    let leaf_hash = utils::salted_leaf_hash(salt, &Digest::of(b"Hello World"));
+                    (filename, leaf_hash)
+                });
+        let mut tree_head = MerkleTree::from_leaf_hashes(leaf_hashes).tree_head();
+        tree_head.salted = true;
+        assert!(utils::verify_file_response(&file_response, &tree_head));
+        assert_ne!(
+            file_response.merkle_proof[0].hash,
+            Digest::of(b"Hello World")
+        );
+
+        let unsalted = FileResponse {
+            salt: None,
+            ..file_response
+        };
+        assert!(!utils::verify_file_response(&unsalted, &tree_head));
+    }
+
+    #[test]
+    fn test_sign_tree_heads_once_per_interval() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        assert_eq!(
+            block_on(signed_tree_head(config(directory.clone(), 2))).err(),
+            Some(StatusCode::NOT_FOUND)
+        );
+
+        let key_dir = tempdir().unwrap();
+        let key = SigningKey::generate(key_dir.path().join("signing.key"), 2).unwrap();
+        let public_key = key.public_key();
+        let config = ServerConfig {
+            signer: Some(Arc::new(Mutex::new(TreeHeadSigner {
+                key: Arc::new(Mutex::new(key)),
+                interval: Duration::from_secs(3600),
+                last_signed: None,
+            }))),
+            ..config(directory.clone(), 2)
+        };
+        upload_two_files(directory);
+
+        let first = block_on(signed_tree_head(config.clone())).unwrap().0;
+        let again = block_on(signed_tree_head(config.clone())).unwrap().0;
+
+        assert!(first.verify(&public_key));
+        assert_eq!(again, first);
+        let tree_head = MerkleTree::new([
+            ("hello1.txt", b"Hello World"),
+            ("hello2.txt", b"Hello World"),
+        ])
+        .tree_head();
+        assert_eq!(first.tree_head.root_hash, tree_head.root_hash);
+
+        let req = mock_upload_request("SGVsbG8gV29ybGQ=", "hello3.txt");
+        let body = upload_request_from_req(req).unwrap();
+        assert!(block_on(upload(config.clone(), Json(body))).is_ok());
+
+        let stale = block_on(signed_tree_head(config.clone())).unwrap().0;
+        assert_eq!(stale, first);
+
+        config.signer.as_ref().unwrap().lock().unwrap().interval = Duration::ZERO;
+        let updated = block_on(signed_tree_head(config.clone())).unwrap().0;
+        assert!(updated.verify(&public_key));
+        assert_eq!(updated.signature.leaf_index, 1);
+        assert_eq!(updated.tree_head.leaf_count, 3);
+        let signer = config.signer.unwrap();
+        let remaining = signer.lock().unwrap().key.lock().unwrap().remaining();
+        assert_eq!(remaining, 2);
+    }
+
+    #[test]
+    fn test_sign_bucket_tree_heads_with_the_bucket_name() {
+        let dir = tempdir().unwrap();
+        let directory = dir.path().to_str().unwrap().to_string();
+        let key_dir = tempdir().unwrap();
+        let key = SigningKey::generate(key_dir.path().join("signing.key"), 2).unwrap();
+        let public_key = key.public_key();
+        let config = ServerConfig {
+            signer: Some(Arc::new(Mutex::new(TreeHeadSigner {
+                key: Arc::new(Mutex::new(key)),
+                interval: Duration::from_secs(3600),
+                last_signed: None,
+            }))),
+            ..config(directory.clone(), 2)
+        };
+        upload_two_files(directory);
+        let buckets = Buckets::load(&config, 4).unwrap();
+        for name in ["docs", "photos"] {
+            let body = Json(CreateBucketRequest {
+                name: name.to_owned(),
+            });
+            assert!(block_on(create_bucket(buckets.clone(), body)).is_ok());
+        }
+        let req = mock_upload_request("SGVsbG8gV29ybGQ=", "hello1.txt");
+        let body = Json(upload_request_from_req(req).unwrap());
+        assert!(block_on(bucket_upload(
+            buckets.clone(),
+            Path("docs".to_owned()),
+            body
+        ))
+        .is_ok());
+
+        let default = block_on(signed_tree_head(config)).unwrap().0;
+        let head = |name: &str| {
+            block_on(bucket_signed_tree_head(
+                buckets.clone(),
+                Path(name.to_owned()),
+            ))
+        };
+        let docs = head("docs").unwrap().0;
+        let photos = head("photos").unwrap().0;
+        assert_eq!(head("docs").unwrap().0, docs);
+        assert_eq!(head("music").unwrap_err(), StatusCode::NOT_FOUND);
+
+        assert!(default.verify(&public_key) && default.bucket.is_none());
+        assert!(docs.verify(&public_key));
+        assert_eq!(docs.bucket.as_deref(), Some("docs"));
+        assert_eq!(
+            docs.tree_head.root_hash,
+            MerkleTree::new([("hello1.txt", b"Hello World")]).get_root_hash()
+        );
+        assert_eq!(photos.tree_head.root_hash, MerkleTree::EMPTY_ROOT);
+        // The buckets share the server's key, each head using up a one-time key
+        let leaf_indexes = [&default, &docs, &photos].map(|signed| signed.signature.leaf_index);
+        assert_eq!(leaf_indexes, [0, 1, 2]);
+
+        for bucket in [None, Some("photos".to_owned())] {
+            let relabeled = SignedTreeHead {
+                bucket,
+                ..docs.clone()
+            };
+            assert!(!relabeled.verify(&public_key));
+        }
+    }
 }
@@ -1,4 +1,8 @@
 use clap::Parser;
+use merkle_rs::{
+    file_metadata::MetadataFields,
+    utils::{parse_arity, parse_metadata_fields},
+};
 
 /// A Merkle Tree implementation for proving file integrity.
 #[derive(Parser, Debug)]
@@ -11,4 +15,44 @@ pub struct Args {
     /// Port to listen to
     #[arg(long, default_value_t = 3000)]
     pub port: u16,
+
+    /// Number of children per tree node (2, 4, 8 or 16), must match the client
+    #[arg(long, default_value_t = 2, value_parser = parse_arity)]
+    pub arity: usize,
+
+    /// Comma separated metadata fields (size, mode, mtime) committed to in each leaf, must match the client
+    #[arg(long, default_value = "", value_parser = parse_metadata_fields)]
+    pub leaf_metadata: MetadataFields,
+
+    /// Store files as content-defined chunks, deduplicated across files, must match the client
+    #[arg(long)]
+    pub chunking: bool,
+
+    /// Build a keyed tree from the client's keyed leaf hashes, hashing nodes with the key in this file, written by the client's node-key command
+    #[arg(long)]
+    pub node_key_file: Option<String>,
+
+    /// Maximum number of proofs kept in memory, 0 disables the proof cache
+    #[arg(long, default_value_t = 10_000)]
+    pub proof_cache_size: usize,
+
+    /// Precompute the proofs of all files after each tree build instead of on first request
+    #[arg(long)]
+    pub eager_proofs: bool,
+
+    /// Versions of each store's tree kept, so files can be proven against roots clients stored before later uploads
+    #[arg(long, default_value_t = 64)]
+    pub tree_versions: usize,
+
+    /// Sign tree heads with the Merkle signature key stored in this file, created if missing
+    #[arg(long)]
+    pub signing_key: Option<String>,
+
+    /// Height of a newly created signing key, which can sign 2^height tree heads
+    #[arg(long, default_value_t = 10)]
+    pub signing_key_height: u8,
+
+    /// Seconds to keep serving the last signed tree head after the tree changes, so requests cannot use up the signing key
+    #[arg(long, default_value_t = 3600)]
+    pub sign_interval: u64,
 }
@@ -0,0 +1,486 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::digest::Digest;
+use crate::merkle_tree::{self, MerkleLeaf, MerkleTree, ProofGroup};
+use crate::utils;
+
+/// Winternitz parameter: each chain signs one base-16 digit.
+const WOTS_W: u8 = 16;
+/// Digits of a 256-bit message digest, plus the digits of their checksum.
+const WOTS_MESSAGE_LEN: usize = 64;
+const WOTS_CHECKSUM_LEN: usize = 3;
+pub const WOTS_LEN: usize = WOTS_MESSAGE_LEN + WOTS_CHECKSUM_LEN;
+
+/// Trees of `2^height` one-time keys can be generated up to this height.
+pub const MAX_HEIGHT: u8 = 20;
+
+/// Identifies a signer: the root of its tree of one-time public keys and the
+/// public seed its hash chains are masked with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicKey {
+    pub root: Digest,
+    pub public_seed: Digest,
+    pub height: u8,
+}
+
+/// A WOTS+ signature by the one-time key at `leaf_index`, with the
+/// authentication path of that key's leaf to the public key root.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Signature {
+    pub leaf_index: u64,
+    pub wots: Vec<Digest>,
+    pub auth_path: Vec<ProofGroup>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct KeyState {
+    secret_seed: Digest,
+    public_seed: Digest,
+    height: u8,
+    /// First one-time key that has not signed anything yet.
+    next_index: u64,
+    /// Root of the one-time public keys, to check the cached keys against.
+    /// Missing in key files written before the keys were cached.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    root: Option<Digest>,
+}
+
+/// A stateful Merkle signature key, persisted at `path`.
+///
+/// Each of the `2^height` WOTS+ one-time keys must sign at most once, so the
+/// state on disk is advanced before a signature is returned. Copying the key
+/// file and signing with both copies breaks that guarantee. The one-time
+/// public keys are cached next to it with a `.keys` extension, since deriving
+/// them takes one WOTS+ key generation per leaf.
+#[derive(Debug)]
+pub struct SigningKey {
+    state: KeyState,
+    path: PathBuf,
+    /// Tree over the public keys of all one-time keys, built once so that
+    /// signing only walks an authentication path.
+    key_tree: MerkleTree,
+}
+
+impl SigningKey {
+    /// Creates a key with `2^height` one-time keys and stores it at `path`.
+    pub fn generate<P: AsRef<Path>>(path: P, height: u8) -> io::Result<Self> {
+        if height == 0 || height > MAX_HEIGHT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Signing key height must be between 1 and {}", MAX_HEIGHT),
+            ));
+        }
+        let state = KeyState {
+            secret_seed: utils::random_salt(),
+            public_seed: utils::random_salt(),
+            height,
+            next_index: 0,
+            root: None,
+        };
+        SigningKey::from_state(state, path.as_ref())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let state: KeyState = serde_json::from_slice(&fs::read(path.as_ref())?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if state.height == 0 || state.height > MAX_HEIGHT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid signing key height",
+            ));
+        }
+        SigningKey::from_state(state, path.as_ref())
+    }
+
+    pub fn load_or_generate<P: AsRef<Path>>(path: P, height: u8) -> io::Result<Self> {
+        if path.as_ref().exists() {
+            SigningKey::load(path)
+        } else {
+            SigningKey::generate(path, height)
+        }
+    }
+
+    /// Reads the cached one-time public keys, or derives them and writes the
+    /// cache and the state if the cache is missing or does not match.
+    fn from_state(state: KeyState, path: &Path) -> io::Result<Self> {
+        let keys_path = path.with_extension("keys");
+        let cached = state
+            .root
+            .and_then(|root| read_key_tree(&keys_path, state.height, &root));
+        if let Some(key_tree) = cached {
+            return Ok(SigningKey {
+                state,
+                path: path.to_path_buf(),
+                key_tree,
+            });
+        }
+
+        let one_time_keys: Vec<Digest> = (0..1u64 << state.height)
+            .map(|index| wots_public_key(&state.secret_seed, &state.public_seed, index))
+            .collect();
+        let key_tree = key_tree(&one_time_keys);
+        let bytes: Vec<u8> = one_time_keys.iter().flat_map(Digest::to_vec).collect();
+        write_synced(&keys_path, &bytes)?;
+        let key = SigningKey {
+            state: KeyState {
+                root: Some(key_tree.get_root_hash()),
+                ..state
+            },
+            path: path.to_path_buf(),
+            key_tree,
+        };
+        key.write_state(&key.state)?;
+        Ok(key)
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            root: self.key_tree.get_root_hash(),
+            public_seed: self.state.public_seed,
+            height: self.state.height,
+        }
+    }
+
+    /// Number of signatures left before the key is exhausted.
+    pub fn remaining(&self) -> u64 {
+        (1u64 << self.state.height) - self.state.next_index
+    }
+
+    /// Signs `message` with the next unused one-time key, after recording on
+    /// disk that the key is used.
+    pub fn sign(&mut self, message: &[u8]) -> io::Result<Signature> {
+        if self.remaining() == 0 {
+            return Err(io::Error::other("Signing key is exhausted"));
+        }
+
+        let leaf_index = self.state.next_index;
+        let next = KeyState {
+            next_index: leaf_index + 1,
+            ..self.state.clone()
+        };
+        self.write_state(&next)?;
+        self.state = next;
+
+        let seeds = (&self.state.secret_seed, &self.state.public_seed);
+        let digits = message_digits(seeds.1, leaf_index, message);
+        let wots = digits
+            .iter()
+            .enumerate()
+            .map(|(chain_index, digit)| {
+                let secret = chain_secret(seeds.0, leaf_index, chain_index);
+                chain(secret, seeds.1, leaf_index, chain_index, 0, *digit)
+            })
+            .collect();
+        let auth_path = self
+            .key_tree
+            .generate_group_proof_for_index(leaf_index as usize)
+            .ok_or_else(|| io::Error::other("No authentication path for one-time key"))?;
+
+        Ok(Signature {
+            leaf_index,
+            wots,
+            auth_path,
+        })
+    }
+
+    /// Replaces the key file in one step and syncs it to disk before
+    /// returning, so a crash cannot roll the index back.
+    fn write_state(&self, state: &KeyState) -> io::Result<()> {
+        write_synced(&self.path, &serde_json::to_vec_pretty(state)?)
+    }
+}
+
+/// Writes `data` to a temporary file, syncs it, renames it over `path` and
+/// syncs the directory, so that `path` holds either the old or the new data
+/// after a crash.
+fn write_synced(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let mut file = fs::File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temp_path, path)?;
+    sync_parent(path)
+}
+
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs::File::open(parent)?.sync_all(),
+        _ => fs::File::open(".")?.sync_all(),
+    }
+}
+
+/// Directories cannot be opened for syncing outside unix, the rename is all
+/// that can be done there.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// The tree over the cached one-time public keys, if the file holds
+/// `2^height` of them and they hash to `root`.
+fn read_key_tree(path: &Path, height: u8, root: &Digest) -> Option<MerkleTree> {
+    let bytes = fs::read(path).ok()?;
+    if bytes.len() != Digest::LEN << height {
+        return None;
+    }
+    let one_time_keys: Vec<Digest> = bytes
+        .chunks(Digest::LEN)
+        .map(|chunk| Digest::from_slice(chunk).ok())
+        .collect::<Option<_>>()?;
+    let key_tree = key_tree(&one_time_keys);
+    (key_tree.get_root_hash() == *root).then_some(key_tree)
+}
+
+impl PublicKey {
+    /// Keys with a height no signing key can have are rejected, rather than
+    /// sizing a tree of `2^height` leaves from them.
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> bool {
+        if self.height == 0 || self.height > MAX_HEIGHT {
+            return false;
+        }
+        if signature.leaf_index >= 1u64 << self.height
+            || signature.wots.len() != WOTS_LEN
+            || signature.auth_path.len() != self.height as usize
+        {
+            return false;
+        }
+
+        // The path must have the shape of the claimed leaf's path, or a
+        // signature by one key could be presented as another's
+        let groups = merkle_tree::path_groups(1 << self.height, 2, signature.leaf_index as usize);
+        let bound = signature
+            .auth_path
+            .iter()
+            .zip(&groups)
+            .all(|(group, (position, size))| {
+                group.position == *position && group.hashes.len() == *size
+            });
+        if !bound {
+            return false;
+        }
+
+        let leaf_index = signature.leaf_index;
+        let digits = message_digits(&self.public_seed, leaf_index, message);
+        let chain_ends: Vec<Digest> = signature
+            .wots
+            .iter()
+            .zip(digits)
+            .enumerate()
+            .map(|(chain_index, (value, digit))| {
+                let steps = WOTS_W - 1 - digit;
+                chain(
+                    *value,
+                    &self.public_seed,
+                    leaf_index,
+                    chain_index,
+                    digit,
+                    steps,
+                )
+            })
+            .collect();
+        let leaf_hash = Digest::of_concatenated(&chain_ends);
+
+        utils::verify_group_proof_for_leaf(&signature.auth_path, &self.root, 2, leaf_hash)
+    }
+}
+
+fn key_tree(one_time_keys: &[Digest]) -> MerkleTree {
+    let leaves = one_time_keys
+        .iter()
+        .map(|public_key| MerkleLeaf::from_hash(None, *public_key))
+        .collect();
+    MerkleTree::from_leaves(leaves)
+}
+
+/// Public key of the one-time key at `leaf_index`: the hash of all chain ends.
+fn wots_public_key(secret_seed: &Digest, public_seed: &Digest, leaf_index: u64) -> Digest {
+    let chain_ends: Vec<Digest> = (0..WOTS_LEN)
+        .map(|chain_index| {
+            let secret = chain_secret(secret_seed, leaf_index, chain_index);
+            chain(secret, public_seed, leaf_index, chain_index, 0, WOTS_W - 1)
+        })
+        .collect();
+    Digest::of_concatenated(&chain_ends)
+}
+
+fn chain_secret(secret_seed: &Digest, leaf_index: u64, chain_index: usize) -> Digest {
+    Digest::of(
+        [
+            b"merkle-rs:wots-secret".as_slice(),
+            secret_seed.as_bytes(),
+            &leaf_index.to_be_bytes(),
+            &(chain_index as u16).to_be_bytes(),
+        ]
+        .concat(),
+    )
+}
+
+/// Applies `steps` steps of a hash chain to `value`, starting at step `start`.
+/// Each step XORs a mask and hashes with a key, both derived from the public
+/// seed and the step's address, as in WOTS+.
+fn chain(
+    mut value: Digest,
+    public_seed: &Digest,
+    leaf_index: u64,
+    chain_index: usize,
+    start: u8,
+    steps: u8,
+) -> Digest {
+    for step in start..start + steps {
+        let address = [
+            public_seed.as_bytes().as_slice(),
+            &leaf_index.to_be_bytes(),
+            &(chain_index as u16).to_be_bytes(),
+            &[step],
+        ]
+        .concat();
+        let key = Digest::of([b"merkle-rs:wots-key".as_slice(), &address].concat());
+        let mask = Digest::of([b"merkle-rs:wots-mask".as_slice(), &address].concat());
+        let masked: Vec<u8> = value
+            .as_bytes()
+            .iter()
+            .zip(mask.as_bytes())
+            .map(|(v, m)| v ^ m)
+            .collect();
+        value = Digest::of([key.as_bytes().as_slice(), &masked].concat());
+    }
+    value
+}
+
+/// Base-16 digits of the message digest followed by those of their checksum,
+/// so that raising any digit lowers another.
+fn message_digits(public_seed: &Digest, leaf_index: u64, message: &[u8]) -> [u8; WOTS_LEN] {
+    let digest = Digest::of(
+        [
+            b"merkle-rs:wots-message".as_slice(),
+            public_seed.as_bytes(),
+            &leaf_index.to_be_bytes(),
+            message,
+        ]
+        .concat(),
+    );
+
+    let mut digits = [0u8; WOTS_LEN];
+    for (i, byte) in digest.as_bytes().iter().enumerate() {
+        digits[2 * i] = byte >> 4;
+        digits[2 * i + 1] = byte & 0x0f;
+    }
+    let checksum: u16 = digits[..WOTS_MESSAGE_LEN]
+        .iter()
+        .map(|digit| (WOTS_W - 1 - digit) as u16)
+        .sum();
+    for i in 0..WOTS_CHECKSUM_LEN {
+        let shift = 4 * (WOTS_CHECKSUM_LEN - 1 - i);
+        digits[WOTS_MESSAGE_LEN + i] = ((checksum >> shift) & 0x0f) as u8;
+    }
+    digits
+}
+
+#[cfg(test)]
+mod test {
+    use crate::digest::Digest;
+    use crate::signature::{PublicKey, SigningKey, MAX_HEIGHT, WOTS_LEN};
+    use std::fs;
+
+    #[test]
+    fn should_verify_signatures_of_every_one_time_key() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut key = SigningKey::generate(temp_dir.path().join("key.json"), 2).unwrap();
+        let public_key = key.public_key();
+
+        for i in 0..4u64 {
+            let message = format!("tree head {}", i);
+            let signature = key.sign(message.as_bytes()).unwrap();
+
+            assert_eq!(signature.leaf_index, i);
+            assert_eq!(signature.wots.len(), WOTS_LEN);
+            assert!(public_key.verify(message.as_bytes(), &signature));
+            assert!(!public_key.verify(b"another head", &signature));
+        }
+        assert_eq!(key.remaining(), 0);
+        assert!(key.sign(b"one too many").is_err());
+    }
+
+    #[test]
+    fn should_reject_tampered_signatures() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let mut key = SigningKey::generate(temp_dir.path().join("key.json"), 3).unwrap();
+        let public_key = key.public_key();
+        let signature = key.sign(b"tree head").unwrap();
+
+        let mut wrong_index = signature.clone();
+        wrong_index.leaf_index = 1;
+        assert!(!public_key.verify(b"tree head", &wrong_index));
+
+        let mut wrong_chain = signature.clone();
+        wrong_chain.wots[5] = wrong_chain.wots[6];
+        assert!(!public_key.verify(b"tree head", &wrong_chain));
+
+        let mut moved = signature.clone();
+        let group = &mut moved.auth_path[0];
+        group.position = 1 - group.position;
+        group.hashes.reverse();
+        assert!(!public_key.verify(b"tree head", &moved));
+
+        let mut short_path = signature.clone();
+        short_path.auth_path.pop();
+        assert!(!public_key.verify(b"tree head", &short_path));
+
+        // Heights past what a key can be generated with are rejected
+        // before they size anything
+        for height in [0, MAX_HEIGHT + 1, 64, u8::MAX] {
+            let oversized = PublicKey {
+                height,
+                ..public_key
+            };
+            assert!(!oversized.verify(b"tree head", &signature));
+        }
+    }
+
+    #[test]
+    fn should_load_cached_one_time_keys() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("key.json");
+        let public_key = SigningKey::generate(&path, 3).unwrap().public_key();
+        let keys_path = temp_dir.path().join("key.keys");
+        assert_eq!(fs::read(&keys_path).unwrap().len(), 8 * Digest::LEN);
+
+        let mut reloaded = SigningKey::load(&path).unwrap();
+        assert_eq!(reloaded.public_key(), public_key);
+        let signature = reloaded.sign(b"tree head").unwrap();
+        assert!(public_key.verify(b"tree head", &signature));
+
+        // A damaged cache is derived again rather than trusted
+        let mut damaged = fs::read(&keys_path).unwrap();
+        damaged[0] ^= 1;
+        fs::write(&keys_path, damaged).unwrap();
+        let mut rederived = SigningKey::load(&path).unwrap();
+        assert_eq!(rederived.public_key(), public_key);
+        let signature = rederived.sign(b"another head").unwrap();
+        assert!(public_key.verify(b"another head", &signature));
+        assert_eq!(signature.leaf_index, 1);
+    }
+
+    #[test]
+    fn should_not_reuse_one_time_keys_after_reload() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("key.json");
+        let mut key = SigningKey::generate(&path, 2).unwrap();
+        let first = key.sign(b"first").unwrap();
+
+        let mut reloaded = SigningKey::load_or_generate(&path, 2).unwrap();
+        let second = reloaded.sign(b"second").unwrap();
+
+        assert_eq!(reloaded.public_key(), key.public_key());
+        assert_eq!((first.leaf_index, second.leaf_index), (0, 1));
+        assert_eq!(reloaded.remaining(), 2);
+        assert!(SigningKey::generate(temp_dir.path().join("big.json"), 21).is_err());
+    }
+}
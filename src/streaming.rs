@@ -0,0 +1,397 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::{self, Write},
+};
+
+use crate::chunking::{self, MAX_CHUNK_SIZE};
+use crate::common::FileResponse;
+use crate::digest::Digest;
+use crate::merkle_tree::{MerkleLeaf, MerkleTree};
+
+/// Arity of chunk trees, see `chunking::chunk_root`.
+const ARITY: usize = 2;
+
+/// Bound on the header, so a server cannot make clients buffer without limit.
+const MAX_HEADER_LEN: usize = 1 << 20;
+
+/// Sent ahead of a streamed file: the file's proof, with empty contents, and
+/// the chunk root it proves, which every streamed chunk is checked against.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamHeader {
+    pub file: FileResponse,
+    pub chunk_root: Digest,
+    pub chunk_count: u64,
+}
+
+/// Something the stream carries next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Item {
+    /// Hashes of the children of the node at `index` of `level`.
+    Group {
+        level: usize,
+        index: usize,
+        size: usize,
+    },
+    /// Length prefixed contents of a chunk.
+    Chunk(usize),
+}
+
+/// Order of a chunk tree's nodes in a stream. Chunks come in file order, each
+/// preceded by the children of every node on its path that no earlier chunk
+/// went through, so each item can be checked against hashes already verified.
+///
+/// Nodes are addressed like `MerkleTree::node_at`.
+#[derive(Debug)]
+struct StreamLayout {
+    level_sizes: Vec<usize>,
+    expanded: HashSet<(usize, usize)>,
+}
+
+impl StreamLayout {
+    fn new(chunk_count: usize) -> Self {
+        let mut level_sizes = vec![chunk_count];
+        while let Some(&size) = level_sizes.last().filter(|&&size| size > 1) {
+            level_sizes.push(size.div_ceil(ARITY));
+        }
+        StreamLayout {
+            level_sizes,
+            expanded: HashSet::new(),
+        }
+    }
+
+    fn top(&self) -> (usize, usize) {
+        (self.level_sizes.len() - 1, 0)
+    }
+
+    /// Group a node is part of on the level above, and the group's size. A
+    /// group of size 1 is the node itself, promoted.
+    fn parent(&self, level: usize, index: usize) -> Option<((usize, usize), usize)> {
+        let size = *self.level_sizes.get(level).filter(|&&size| size > 1)?;
+        let group = (size - 1 - index) / ARITY;
+        let group_size = (size - group * ARITY).min(ARITY);
+        Some(((level + 1, group), group_size))
+    }
+
+    fn children(&self, level: usize, index: usize, size: usize) -> Vec<(usize, usize)> {
+        let below = self.level_sizes[level - 1];
+        (0..size)
+            .map(|j| (level - 1, below - 1 - (index * ARITY + j)))
+            .collect()
+    }
+
+    /// Items to send for chunk `index`, which must follow the chunk before it.
+    fn items_for_chunk(&mut self, index: usize) -> Vec<Item> {
+        let mut items = vec![Item::Chunk(index)];
+        let mut node = (0, index);
+        while let Some((parent, size)) = self.parent(node.0, node.1) {
+            if size > 1 && self.expanded.insert(parent) {
+                items.push(Item::Group {
+                    level: parent.0,
+                    index: parent.1,
+                    size,
+                });
+            }
+            node = parent;
+        }
+        items.reverse();
+        items
+    }
+}
+
+/// Encodes a file's chunks for streaming, fetching each chunk's contents by
+/// hash. The encoding starts with a big-endian `u32` length and the JSON of
+/// `header`.
+pub fn encode_stream<F>(
+    header: &StreamHeader,
+    chunk_hashes: &[Digest],
+    mut read_chunk: F,
+) -> io::Result<Vec<u8>>
+where
+    F: FnMut(&Digest) -> io::Result<Vec<u8>>,
+{
+    let header_json = serde_json::to_vec(header)?;
+    let mut encoded = (header_json.len() as u32).to_be_bytes().to_vec();
+    encoded.extend_from_slice(&header_json);
+
+    let leaves = chunk_hashes
+        .iter()
+        .map(|hash| MerkleLeaf::from_hash(None, *hash))
+        .collect();
+    let chunk_tree = MerkleTree::from_leaves(leaves);
+    let mut layout = StreamLayout::new(chunk_hashes.len());
+
+    for chunk_index in 0..chunk_hashes.len() {
+        for item in layout.items_for_chunk(chunk_index) {
+            match item {
+                Item::Group { level, index, size } => {
+                    for (child_level, child_index) in layout.children(level, index, size) {
+                        let node = chunk_tree
+                            .node_at(child_level, child_index)
+                            .ok_or_else(|| io::Error::other("Chunk tree node out of range"))?;
+                        encoded.extend_from_slice(node.hash.as_bytes());
+                    }
+                }
+                Item::Chunk(index) => {
+                    let content = read_chunk(&chunk_hashes[index])?;
+                    encoded.extend_from_slice(&(content.len() as u32).to_be_bytes());
+                    encoded.extend_from_slice(&content);
+                }
+            }
+        }
+    }
+    Ok(encoded)
+}
+
+/// Splits the header off the front of an encoded stream, returning `None`
+/// until all of it has arrived.
+pub fn decode_header(data: &[u8]) -> io::Result<Option<(StreamHeader, usize)>> {
+    let Some(len) = data.get(..4) else {
+        return Ok(None);
+    };
+    let end = 4 + u32::from_be_bytes(len.try_into().unwrap()) as usize;
+    if end - 4 > MAX_HEADER_LEN {
+        return Err(invalid("Stream header is too long"));
+    }
+    match data.get(4..end) {
+        Some(json) => {
+            let header = serde_json::from_slice(json)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            Ok(Some((header, end)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Checks a streamed file against its chunk root as it arrives, passing on
+/// only chunks whose path to the root has been verified.
+#[derive(Debug)]
+pub struct StreamDecoder {
+    chunk_root: Digest,
+    chunk_count: usize,
+    layout: StreamLayout,
+    /// Verified hashes of nodes whose children have not been received yet.
+    known: HashMap<(usize, usize), Digest>,
+    items: VecDeque<Item>,
+    next_chunk: usize,
+    buffer: Vec<u8>,
+    written: u64,
+}
+
+impl StreamDecoder {
+    pub fn new(chunk_root: Digest, chunk_count: u64) -> io::Result<Self> {
+        let chunk_count = usize::try_from(chunk_count).map_err(|_| invalid("Too many chunks"))?;
+        let layout = StreamLayout::new(chunk_count);
+        let known = HashMap::from([(layout.top(), chunk_root)]);
+        Ok(StreamDecoder {
+            chunk_root,
+            chunk_count,
+            layout,
+            known,
+            items: VecDeque::new(),
+            next_chunk: 0,
+            buffer: Vec::new(),
+            written: 0,
+        })
+    }
+
+    /// Consumes the next piece of the stream, writing every chunk it completes
+    /// to `out`. Fails at the first node or chunk that does not match.
+    pub fn update<W: Write>(&mut self, data: &[u8], out: &mut W) -> io::Result<()> {
+        self.buffer.extend_from_slice(data);
+        let mut consumed = 0;
+
+        loop {
+            if self.items.is_empty() {
+                if self.next_chunk == self.chunk_count {
+                    break;
+                }
+                self.items = self.layout.items_for_chunk(self.next_chunk).into();
+                self.next_chunk += 1;
+            }
+            let rest = &self.buffer[consumed..];
+
+            match self.items[0] {
+                Item::Group { level, index, size } => {
+                    let Some(hashes) = rest.get(..size * Digest::LEN) else {
+                        break;
+                    };
+                    let expected = take_known(&mut self.known, &self.layout, level, index)?;
+                    let children: Vec<Digest> = hashes
+                        .chunks(Digest::LEN)
+                        .map(|hash| Digest::from_slice(hash).unwrap())
+                        .collect();
+                    if Digest::of_children(&children) != expected {
+                        return Err(invalid("Streamed tree node does not match its parent"));
+                    }
+                    let positions = self.layout.children(level, index, size);
+                    self.known.extend(positions.into_iter().zip(children));
+                    consumed += hashes.len();
+                }
+                Item::Chunk(index) => {
+                    let Some(len) = rest.get(..4) else {
+                        break;
+                    };
+                    let len = u32::from_be_bytes(len.try_into().unwrap()) as usize;
+                    if len == 0 || len > MAX_CHUNK_SIZE {
+                        return Err(invalid("Streamed chunk has an invalid length"));
+                    }
+                    let Some(content) = rest.get(4..4 + len) else {
+                        break;
+                    };
+                    let leaf = Digest::of_leaf(&Digest::of(content));
+                    if leaf != take_known(&mut self.known, &self.layout, 0, index)? {
+                        return Err(invalid(&format!(
+                            "Streamed chunk {} does not match its hash",
+                            index
+                        )));
+                    }
+                    out.write_all(content)?;
+                    self.written += len as u64;
+                    consumed += 4 + len;
+                }
+            }
+            self.items.pop_front();
+        }
+
+        self.buffer.drain(..consumed);
+        if self.next_chunk == self.chunk_count && self.items.is_empty() && !self.buffer.is_empty() {
+            return Err(invalid("Unexpected data after the last chunk"));
+        }
+        Ok(())
+    }
+
+    /// Checks that the whole file arrived and returns its length.
+    pub fn finish(self) -> io::Result<u64> {
+        if self.next_chunk < self.chunk_count || !self.items.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Stream ended before the last chunk",
+            ));
+        }
+        if self.chunk_count == 0 && self.chunk_root != chunking::chunk_root(&[]) {
+            return Err(invalid("Empty stream for a non-empty file"));
+        }
+        Ok(self.written)
+    }
+}
+
+/// Removes and returns the verified hash of a node, following promoted nodes
+/// up to the group they were verified in.
+fn take_known(
+    known: &mut HashMap<(usize, usize), Digest>,
+    layout: &StreamLayout,
+    level: usize,
+    index: usize,
+) -> io::Result<Digest> {
+    let mut node = (level, index);
+    loop {
+        if let Some(hash) = known.remove(&node) {
+            return Ok(hash);
+        }
+        match layout.parent(node.0, node.1) {
+            Some((parent, 1)) => node = parent,
+            _ => return Err(invalid("Streamed node arrived before its parent")),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        chunking::{self, split_chunks},
+        common::FileResponse,
+        digest::Digest,
+        streaming::{decode_header, encode_stream, StreamDecoder, StreamHeader},
+    };
+    use std::collections::HashMap;
+
+    fn pseudo_random_bytes(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 33) as u8
+            })
+            .collect()
+    }
+
+    fn encode(data: &[u8]) -> (Digest, Vec<u8>) {
+        let chunks: HashMap<Digest, Vec<u8>> = split_chunks(data)
+            .into_iter()
+            .map(|chunk| (Digest::of(chunk), chunk.to_vec()))
+            .collect();
+        let chunk_hashes = chunking::chunk_hashes(data);
+        let header = StreamHeader {
+            file: FileResponse::new("big.bin".to_owned(), Vec::new(), Vec::new()),
+            chunk_root: chunking::chunk_root(&chunk_hashes),
+            chunk_count: chunk_hashes.len() as u64,
+        };
+        let encoded = encode_stream(&header, &chunk_hashes, |hash| Ok(chunks[hash].clone()));
+        (header.chunk_root, encoded.unwrap())
+    }
+
+    fn decode(encoded: &[u8], piece_size: usize) -> Result<Vec<u8>, (std::io::Error, Vec<u8>)> {
+        let (header, header_len) = decode_header(encoded).unwrap().unwrap();
+        let mut decoder = StreamDecoder::new(header.chunk_root, header.chunk_count).unwrap();
+        let mut out = Vec::new();
+        for piece in encoded[header_len..].chunks(piece_size) {
+            if let Err(e) = decoder.update(piece, &mut out) {
+                return Err((e, out));
+            }
+        }
+        decoder.finish().map(|_| out.clone()).map_err(|e| (e, out))
+    }
+
+    #[test]
+    fn should_stream_files_of_any_chunk_count() {
+        for (len, seed) in [(0, 1), (100, 2), (20_000, 3), (300_000, 4)] {
+            let data = pseudo_random_bytes(len, seed);
+            let (root, encoded) = encode(&data);
+
+            assert_eq!(decode(&encoded, 1000).unwrap(), data);
+            assert_eq!(decode(&encoded, 7).unwrap(), data);
+            assert_eq!(root, chunking::chunk_root(&chunking::chunk_hashes(&data)));
+        }
+    }
+
+    #[test]
+    fn should_stop_before_writing_a_tampered_chunk() {
+        let data = pseudo_random_bytes(300_000, 5);
+        let chunks = split_chunks(&data);
+        assert!(chunks.len() > 4);
+        let (_, mut encoded) = encode(&data);
+
+        let tampered_offset = chunks[..3].iter().map(|c| c.len()).sum::<usize>() + 10;
+        let position = encoded
+            .windows(32)
+            .position(|window| window == &data[tampered_offset..tampered_offset + 32])
+            .unwrap();
+        encoded[position] ^= 1;
+
+        let (error, written) = decode(&encoded, 4096).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(written, data[..written.len()]);
+        assert!(written.len() < tampered_offset);
+    }
+
+    #[test]
+    fn should_reject_truncated_or_extended_streams() {
+        let data = pseudo_random_bytes(50_000, 6);
+        let (_, encoded) = encode(&data);
+
+        let truncated = &encoded[..encoded.len() - 1];
+        assert_eq!(
+            decode(truncated, 4096).unwrap_err().0.kind(),
+            std::io::ErrorKind::UnexpectedEof
+        );
+
+        let mut extended = encoded.clone();
+        extended.push(0);
+        assert!(decode(&extended, 4096).is_err());
+    }
+}
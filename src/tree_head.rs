@@ -0,0 +1,369 @@
+use serde::{Deserialize, Serialize};
+
+use crate::digest::Digest;
+use crate::file_metadata::MetadataFields;
+use crate::merkle_tree::MerkleTree;
+use std::{
+    fmt, io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Magic bytes at the start of a serialized tree head.
+const MAGIC: &[u8; 4] = b"MRKL";
+
+/// Hash function used for leaves and internal nodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    Sha256,
+}
+
+impl HashAlgorithm {
+    fn id(&self) -> u8 {
+        match self {
+            HashAlgorithm::Sha256 => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+
+    pub fn output_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+}
+
+/// Summary of a tree that a client keeps after deleting its files.
+///
+/// The serialized form is `MRKL`, layout version, hash algorithm id, arity,
+/// leaf metadata field bits, leaf count and creation time (big-endian `u64`s),
+/// chunking flag, keyed leaves flag, salted leaves flag, root length, the
+/// root hash, listing root length (0 without one) and the listing root.
+/// Legacy heads are serialized as the bare root, see `TreeHead::legacy`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeHead {
+    pub layout_version: u8,
+    pub hash_algorithm: HashAlgorithm,
+    pub arity: u8,
+    /// Metadata fields committed to in each leaf besides the contents.
+    pub leaf_metadata: MetadataFields,
+    /// Whether file leaves are roots of content-defined chunk trees.
+    pub chunking: bool,
+    /// Whether leaves are keyed with a secret only the client holds.
+    pub keyed: bool,
+    /// Whether leaves commit to a random per-file salt.
+    pub salted: bool,
+    pub leaf_count: u64,
+    /// Seconds since the unix epoch.
+    pub created_at: u64,
+    pub root_hash: Digest,
+    /// Root of the tree over every file's name, size and leaf hash in leaf
+    /// order, see `utils::listing_tree`, which listing pages are proven
+    /// against. Unknown for heads built without the files' names and sizes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listing_root: Option<Digest>,
+}
+
+impl TreeHead {
+    /// Layout produced by `MerkleTree`, bumped whenever roots would change.
+    /// Version 3 hashes leaves and internal nodes with different prefixes.
+    pub const LAYOUT_VERSION: u8 = 3;
+
+    /// Layout of a bare root written before tree heads existed.
+    pub const LEGACY_LAYOUT_VERSION: u8 = 1;
+
+    /// Leaf count of heads that do not record one.
+    pub const UNKNOWN_LEAF_COUNT: u64 = u64::MAX;
+
+    pub fn new(root_hash: Digest, leaf_count: u64, arity: usize) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+
+        TreeHead {
+            layout_version: TreeHead::LAYOUT_VERSION,
+            hash_algorithm: HashAlgorithm::Sha256,
+            arity: arity as u8,
+            leaf_metadata: MetadataFields::default(),
+            chunking: false,
+            keyed: false,
+            salted: false,
+            leaf_count,
+            created_at,
+            root_hash,
+            listing_root: None,
+        }
+    }
+
+    /// Head of a bare root as written by the first client: a binary tree over
+    /// plain content hashes, whose leaf count and creation time were not kept.
+    /// Its nodes hash like its leaves, so `validate` rejects it.
+    pub fn legacy(root_hash: Digest) -> Self {
+        TreeHead {
+            layout_version: TreeHead::LEGACY_LAYOUT_VERSION,
+            leaf_count: TreeHead::UNKNOWN_LEAF_COUNT,
+            created_at: 0,
+            ..TreeHead::new(root_hash, 0, 2)
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        if self.layout_version == TreeHead::LEGACY_LAYOUT_VERSION {
+            return self.root_hash.as_bytes().to_vec();
+        }
+
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 24 + Digest::LEN);
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(self.layout_version);
+        bytes.push(self.hash_algorithm.id());
+        bytes.push(self.arity);
+        bytes.push(self.leaf_metadata.bits());
+        bytes.push(self.chunking as u8);
+        bytes.push(self.keyed as u8);
+        bytes.push(self.salted as u8);
+        bytes.extend_from_slice(&self.leaf_count.to_be_bytes());
+        bytes.extend_from_slice(&self.created_at.to_be_bytes());
+        bytes.push(Digest::LEN as u8);
+        bytes.extend_from_slice(self.root_hash.as_bytes());
+        match &self.listing_root {
+            Some(listing_root) => {
+                bytes.push(Digest::LEN as u8);
+                bytes.extend_from_slice(listing_root.as_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() == Digest::LEN {
+            return Ok(TreeHead::legacy(Digest::from_slice(bytes).unwrap()));
+        }
+        let rest = bytes
+            .strip_prefix(MAGIC.as_slice())
+            .ok_or_else(|| invalid("Not a merkle tree head"))?;
+        let mut reader = Reader(rest);
+
+        let layout_version = reader.u8()?;
+        if layout_version != TreeHead::LAYOUT_VERSION {
+            return Err(invalid(&format!(
+                "Unsupported tree head version {}",
+                layout_version
+            )));
+        }
+        let hash_algorithm = HashAlgorithm::from_id(reader.u8()?)
+            .ok_or_else(|| invalid("Unknown hash algorithm"))?;
+        let arity = reader.u8()?;
+        let leaf_metadata = MetadataFields::from_bits(reader.u8()?)
+            .ok_or_else(|| invalid("Unknown leaf metadata fields"))?;
+        let chunking = reader.u8()? != 0;
+        let keyed = reader.u8()? != 0;
+        let salted = reader.u8()? != 0;
+        let leaf_count = reader.u64()?;
+        let created_at = reader.u64()?;
+        let root_len = reader.u8()? as usize;
+        if root_len != hash_algorithm.output_len() {
+            return Err(invalid("Merkle root length does not match header"));
+        }
+        let root_hash =
+            Digest::from_slice(reader.take(root_len)?).map_err(|e| invalid(&e.to_string()))?;
+        let listing_root = match reader.u8()? as usize {
+            0 => None,
+            len if len == hash_algorithm.output_len() => {
+                Some(Digest::from_slice(reader.take(len)?).map_err(|e| invalid(&e.to_string()))?)
+            }
+            _ => return Err(invalid("Listing root length does not match header")),
+        };
+        if !reader.0.is_empty() {
+            return Err(invalid("Trailing bytes after merkle tree head"));
+        }
+
+        Ok(TreeHead {
+            layout_version,
+            hash_algorithm,
+            arity,
+            leaf_metadata,
+            chunking,
+            keyed,
+            salted,
+            leaf_count,
+            created_at,
+            root_hash,
+            listing_root,
+        })
+    }
+
+    /// Checks that this head describes a tree that proofs can be verified against.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.layout_version != TreeHead::LAYOUT_VERSION {
+            return Err(format!(
+                "Unsupported tree layout version {}, files must be uploaded again to get a version {} root",
+                self.layout_version,
+                TreeHead::LAYOUT_VERSION
+            ));
+        }
+        if !MerkleTree::SUPPORTED_ARITIES.contains(&(self.arity as usize)) {
+            return Err(format!("Unsupported tree arity {}", self.arity));
+        }
+        Ok(())
+    }
+
+    /// Number of levels above the leaves in a tree with this many leaves.
+    pub fn max_depth(&self) -> usize {
+        let mut depth = 0;
+        let mut level_size = self.leaf_count;
+        while level_size > 1 {
+            level_size = level_size.div_ceil(self.arity.max(2) as u64);
+            depth += 1;
+        }
+        depth
+    }
+
+    /// Largest number of items a binary proof for this tree can contain: the
+    /// leaf itself plus one sibling per level.
+    pub fn max_proof_len(&self) -> usize {
+        self.max_depth() + 1
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Reads big-endian fields from the front of a byte slice.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> io::Result<&[u8]> {
+        if self.0.len() < len {
+            return Err(invalid("Truncated merkle tree head"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+impl fmt::Display for TreeHead {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.leaf_count == TreeHead::UNKNOWN_LEAF_COUNT {
+            return write!(
+                f,
+                "root {} (legacy, unknown leaves, {:?}, arity {})",
+                self.root_hash, self.hash_algorithm, self.arity
+            );
+        }
+        write!(
+            f,
+            "root {} ({} leaves, {:?}, arity {}, layout v{}, created at {})",
+            self.root_hash,
+            self.leaf_count,
+            self.hash_algorithm,
+            self.arity,
+            self.layout_version,
+            self.created_at
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::digest::Digest;
+    use crate::merkle_tree::MerkleTree;
+    use crate::tree_head::{HashAlgorithm, TreeHead};
+    use crate::utils;
+    use std::fs;
+
+    #[test]
+    fn should_round_trip_serialized_tree_head() {
+        let mut tree_head = TreeHead::new(Digest::new([7; 32]), 5, 2);
+        tree_head.leaf_metadata.mtime = true;
+        tree_head.chunking = true;
+        tree_head.keyed = true;
+        tree_head.salted = true;
+
+        let decoded = TreeHead::from_bytes(&tree_head.to_bytes()).unwrap();
+
+        assert_eq!(decoded, tree_head);
+        assert_eq!(decoded.hash_algorithm, HashAlgorithm::Sha256);
+        assert!(decoded.validate().is_ok());
+
+        tree_head.listing_root = Some(Digest::new([9; 32]));
+        let decoded = TreeHead::from_bytes(&tree_head.to_bytes()).unwrap();
+        assert_eq!(decoded, tree_head);
+    }
+
+    #[test]
+    fn should_reject_malformed_tree_head() {
+        let bytes = TreeHead::new(Digest::new([7; 32]), 5, 2).to_bytes();
+
+        assert!(TreeHead::from_bytes(&[7; 31]).is_err());
+        assert!(TreeHead::from_bytes(&[7; 33]).is_err());
+        assert!(TreeHead::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(TreeHead::from_bytes(&bytes[..10]).is_err());
+        let mut older = bytes.clone();
+        older[4] = 1;
+        assert!(TreeHead::from_bytes(&older).is_err());
+        let mut short_listing_root = bytes.clone();
+        *short_listing_root.last_mut().unwrap() = 1;
+        short_listing_root.push(9);
+        assert!(TreeHead::from_bytes(&short_listing_root).is_err());
+        assert!(TreeHead::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+    }
+
+    #[test]
+    fn should_bound_proof_length_by_depth() {
+        assert_eq!(TreeHead::new(Digest::new([7; 32]), 1, 2).max_proof_len(), 1);
+        assert_eq!(TreeHead::new(Digest::new([7; 32]), 2, 2).max_proof_len(), 2);
+        assert_eq!(TreeHead::new(Digest::new([7; 32]), 5, 2).max_proof_len(), 4);
+        assert_eq!(TreeHead::new(Digest::new([7; 32]), 8, 2).max_proof_len(), 4);
+        assert_eq!(TreeHead::new(Digest::new([7; 32]), 17, 4).max_depth(), 3);
+        assert_eq!(TreeHead::new(Digest::new([7; 32]), 16, 16).max_depth(), 1);
+    }
+
+    #[test]
+    fn should_read_but_reject_bare_root_written_by_first_client() {
+        let files: Vec<(String, String)> = (1..=5)
+            .map(|i| (format!("file{}.txt", i), format!("File {} contents", i)))
+            .collect();
+        let merkle_tree = MerkleTree::new(files.clone());
+        // The first client stored the root's raw bytes in merkle.bin
+        let root =
+            Digest::from_hex("681554e74a23e23d84248b01faa65307876b9c0c4a701968066b3c3222c598da")
+                .unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("merkle.bin");
+        fs::write(&path, root.as_bytes()).unwrap();
+
+        let tree_head = TreeHead::from_bytes(&fs::read(&path).unwrap()).unwrap();
+
+        assert_eq!(tree_head.root_hash, root);
+        assert_eq!(tree_head.arity, 2);
+        assert!(tree_head.leaf_metadata.is_empty());
+        assert_eq!(tree_head.leaf_count, TreeHead::UNKNOWN_LEAF_COUNT);
+        assert_eq!(tree_head.to_bytes(), root.as_bytes());
+        // Roots are no longer computed the way the first client did
+        assert_ne!(merkle_tree.get_root_hash(), root);
+        assert!(tree_head.validate().is_err());
+        let proof_list = merkle_tree.generate_merkle_proof("file3.txt").unwrap();
+        assert!(!utils::verify_merkle_proof_against_head(
+            proof_list,
+            &tree_head,
+            files[2].1.clone().into_bytes(),
+        ));
+    }
+}
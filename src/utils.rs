@@ -1,9 +1,15 @@
-use sha2::{Digest, Sha256};
 use std::collections::BTreeMap;
 use std::fs;
-use std::io::Read;
+use std::io::{self, Read};
 
-use crate::merkle_tree::{NodeOrder, ProofListItem};
+use crate::chunking;
+use crate::common::{FileResponse, ListingEntry, ListingPage};
+use crate::digest::Digest;
+use crate::file_metadata::{FileMetadata, MetadataFields};
+use crate::merkle_tree::{
+    self, IndexedProof, MerkleLeaf, MerkleTree, NodeOrder, ProofGroup, ProofListItem, RangeProof,
+};
+use crate::tree_head::TreeHead;
 
 pub fn parse_files(path: &str) -> BTreeMap<String, Vec<u8>> {
     let mut files_map = BTreeMap::new();
@@ -31,51 +37,672 @@ pub fn parse_files(path: &str) -> BTreeMap<String, Vec<u8>> {
     files_map
 }
 
-fn contains_hash(proof_list: &Vec<ProofListItem>, target_hash: &Vec<u8>) -> bool {
-    for item in proof_list {
-        if &item.hash == target_hash {
-            return true;
+/// Like `parse_files`, also reading the selected metadata fields of each file.
+pub fn parse_files_with_metadata(
+    path: &str,
+    fields: MetadataFields,
+) -> BTreeMap<String, (Vec<u8>, FileMetadata)> {
+    parse_files(path)
+        .into_iter()
+        .map(|(file_name, content)| {
+            let file_path = std::path::Path::new(path).join(&file_name);
+            let metadata =
+                FileMetadata::read(&file_path, fields).expect("Failed to read file metadata");
+            (file_name, (content, metadata))
+        })
+        .collect()
+}
+
+/// Leaf hash of each file, see `file_leaf_hash`.
+pub fn parse_leaf_hashes(
+    path: &str,
+    fields: MetadataFields,
+    chunking: bool,
+) -> BTreeMap<String, Digest> {
+    parse_listing_entries(path, fields, chunking)
+        .into_iter()
+        .map(|entry| (entry.filename, entry.leaf_hash))
+        .collect()
+}
+
+/// Name, size and leaf hash of each file, in file name order.
+pub fn parse_listing_entries(
+    path: &str,
+    fields: MetadataFields,
+    chunking: bool,
+) -> Vec<ListingEntry> {
+    parse_files_with_metadata(path, fields)
+        .into_iter()
+        .map(|(filename, (content, metadata))| {
+            let metadata = Some(&metadata).filter(|_| !fields.is_empty());
+            ListingEntry {
+                size: content.len() as u64,
+                leaf_hash: file_leaf_hash(&content, metadata, chunking),
+                filename,
+            }
+        })
+        .collect()
+}
+
+/// Hash of a file's leaf. The contents are hashed whole, or as the root of
+/// their chunk tree when chunking, and wrapped in the metadata leaf encoding
+/// when the tree commits to metadata.
+pub fn file_leaf_hash(content: &[u8], metadata: Option<&FileMetadata>, chunking: bool) -> Digest {
+    let content_hash = if chunking {
+        chunking::chunk_root(&chunking::chunk_hashes(content))
+    } else {
+        Digest::of(content)
+    };
+
+    leaf_hash_from_content_hash(content_hash, metadata)
+}
+
+/// Wraps a content hash (or chunk root) in the metadata leaf encoding, if any.
+pub fn leaf_hash_from_content_hash(
+    content_hash: Digest,
+    metadata: Option<&FileMetadata>,
+) -> Digest {
+    match metadata {
+        Some(metadata) => Digest::of(metadata.leaf_data_for_hash(&content_hash)),
+        None => content_hash,
+    }
+}
+
+/// Parses a checksum manifest as written by `sha256sum` (`<hex>  <name>`, or
+/// `<hex> *<name>` in binary mode) or `sha256sum --tag` / BSD `sha256`
+/// (`SHA256 (<name>) = <hex>`). Blank lines and `#` comments are skipped.
+///
+/// Entries are ordered by file name, like `parse_files`, so the tree built
+/// from a manifest matches the one built from the files it lists.
+pub fn parse_checksum_manifest(manifest: &str) -> io::Result<BTreeMap<String, Digest>> {
+    let mut hashes = BTreeMap::new();
+
+    for (number, line) in manifest.lines().enumerate() {
+        let invalid = |msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Line {} of checksum manifest: {}", number + 1, msg),
+            )
+        };
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // GNU tools prefix lines whose file name needed escaping with a backslash
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+
+        let (file_name, hex) = if let Some(rest) = line.strip_prefix("SHA256 (") {
+            rest.rsplit_once(") = ")
+                .ok_or_else(|| invalid("expected `SHA256 (<name>) = <hex>`"))?
+        } else {
+            let (hex, rest) = line
+                .split_once(' ')
+                .ok_or_else(|| invalid("expected `<hex>  <name>`"))?;
+            let file_name = rest
+                .strip_prefix(' ')
+                .or_else(|| rest.strip_prefix('*'))
+                .ok_or_else(|| invalid("expected `<hex>  <name>`"))?;
+            (file_name, hex)
+        };
+
+        let file_name = if escaped {
+            unescape_file_name(file_name)
+        } else {
+            file_name.to_owned()
+        };
+        if file_name.is_empty() {
+            return Err(invalid("missing file name"));
+        }
+        let hash = Digest::from_hex(hex).map_err(|e| invalid(&e.to_string()))?;
+
+        match hashes.insert(file_name.clone(), hash) {
+            Some(previous) if previous != hash => {
+                return Err(invalid(&format!("conflicting hashes for {}", file_name)));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(hashes)
+}
+
+/// Undoes the `\\` and `\n` escapes of `sha256sum` file names.
+fn unescape_file_name(file_name: &str) -> String {
+    let mut unescaped = String::with_capacity(file_name.len());
+    let mut chars = file_name.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                unescaped.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                unescaped.push('\\');
+                chars.next();
+            }
+            _ => unescaped.push(c),
         }
     }
-    false
+    unescaped
+}
+
+/// Random value committed in a leaf. Salts have the size and encoding of a digest.
+pub type LeafSalt = Digest;
+
+/// Prefix of salted leaves, so they cannot collide with unsalted ones.
+const SALTED_LEAF_PREFIX: &[u8] = b"merkle-rs:salted-leaf:v1";
+
+pub fn random_salt() -> LeafSalt {
+    Digest::new(rand::random())
+}
+
+/// Leaf hash mixed with a random salt. Sibling hashes in a proof then reveal
+/// nothing about other files' contents to anyone who lacks their salts.
+pub fn salted_leaf_hash(salt: &LeafSalt, leaf_hash: &Digest) -> Digest {
+    Digest::of([SALTED_LEAF_PREFIX, salt.as_bytes(), leaf_hash.as_bytes()].concat())
+}
+
+/// Prefix of keyed leaves, so a key used elsewhere cannot produce them.
+const KEYED_LEAF_PREFIX: &[u8] = b"merkle-rs:keyed-leaf:v1";
+
+/// Leaf hash keyed with a client-held secret. Without the key, the published
+/// leaves and proofs cannot be used to confirm guesses of a file's contents.
+pub fn keyed_leaf_hash(key: &[u8], leaf_hash: &Digest) -> Digest {
+    Digest::hmac(key, [KEYED_LEAF_PREFIX, leaf_hash.as_bytes()].concat())
+}
+
+/// Label the node key is derived under, so it never equals the leaf key.
+const NODE_KEY_LABEL: &[u8] = b"merkle-rs:node-key:v1";
+
+/// Key the internal nodes of a keyed tree are hashed with, derived from the
+/// client's secret. The server is given this key to build the tree and serve
+/// proofs, but cannot compute keyed leaves with it.
+pub fn node_key(leaf_key: &[u8]) -> Vec<u8> {
+    Digest::hmac(leaf_key, NODE_KEY_LABEL).to_vec()
+}
+
+/// Prefix of the tags sent along with keyed leaves.
+const KEYED_LEAF_TAG_PREFIX: &[u8] = b"merkle-rs:keyed-leaf-tag:v1";
+
+/// Tag binding an uploaded keyed leaf hash to the file's name under the node
+/// key, so the server only takes keyed leaves from holders of the key.
+pub fn keyed_leaf_tag(node_key: &[u8], filename: &str, leaf_hash: &Digest) -> Digest {
+    let mut data = KEYED_LEAF_TAG_PREFIX.to_vec();
+    data.extend_from_slice(&(filename.len() as u64).to_be_bytes());
+    data.extend_from_slice(filename.as_bytes());
+    data.extend_from_slice(leaf_hash.as_bytes());
+    Digest::hmac(node_key, data)
 }
 
-#[allow(dead_code)]
 pub fn verify_merkle_proof(
-    mut proof_list: Vec<ProofListItem>,
-    markle_root: Vec<u8>,
+    proof_list: Vec<ProofListItem>,
+    markle_root: Digest,
     file_contents: Vec<u8>,
 ) -> bool {
-    if proof_list.len() < 2 {
+    let hashed_file_contents = Digest::of(&file_contents);
+    verify_merkle_proof_for_leaf(proof_list, markle_root, hashed_file_contents)
+}
+
+/// Like `verify_merkle_proof`, for a leaf whose hash is not the plain hash of
+/// the file contents.
+pub fn verify_merkle_proof_for_leaf(
+    proof_list: Vec<ProofListItem>,
+    markle_root: Digest,
+    hashed_file_contents: Digest,
+) -> bool {
+    fold_merkle_proof(&proof_list, hashed_file_contents, None)
+        .is_some_and(|(root, _)| root == markle_root)
+}
+
+/// Verifies a proof against a stored tree head, rejecting heads with an
+/// unsupported layout and proofs whose path does not fit a tree with the
+/// head's leaf count.
+pub fn verify_merkle_proof_against_head(
+    proof_list: Vec<ProofListItem>,
+    tree_head: &TreeHead,
+    file_contents: Vec<u8>,
+) -> bool {
+    if tree_head.validate().is_err() {
         return false;
     }
 
-    let hashed_file_contents = Sha256::digest(&file_contents).to_vec();
+    verify_merkle_proof_for_head(&proof_list, tree_head, Digest::of(file_contents), None)
+}
 
-    if !contains_hash(&proof_list, &hashed_file_contents) {
-        return false;
+fn verify_merkle_proof_for_head(
+    proof_list: &[ProofListItem],
+    tree_head: &TreeHead,
+    leaf_hash: Digest,
+    node_key: Option<&[u8]>,
+) -> bool {
+    fold_merkle_proof(proof_list, leaf_hash, node_key)
+        .is_some_and(|(root, path)| root == tree_head.root_hash && fits_tree(&path, tree_head))
+}
+
+/// Hashes a list proof up from the leaf, returning the root it leads to and
+/// the proof's path as (position, group size) pairs, bottom up. List proofs
+/// go from the root down and must end with the leaf's own pair, whose second
+/// to last item carries the pair's order; a single item is the leaf's node
+/// of a one-leaf tree. Keyed trees hash their nodes under `node_key`.
+pub(crate) fn fold_merkle_proof(
+    proof_list: &[ProofListItem],
+    leaf_hash: Digest,
+    node_key: Option<&[u8]>,
+) -> Option<(Digest, Vec<(usize, usize)>)> {
+    let mut current = Digest::of_leaf(&leaf_hash);
+    let mut path = Vec::new();
+    for (sibling, is_left) in list_proof_siblings(proof_list, &current)? {
+        current = if is_left {
+            Digest::of_keyed_children(node_key, [sibling, &current])
+        } else {
+            Digest::of_keyed_children(node_key, [&current, sibling])
+        };
+        path.push((is_left as usize, 2));
     }
+    Some((current, path))
+}
 
-    while proof_list.len() != 1 {
-        let h1 = proof_list.pop().unwrap();
-        let h2 = proof_list.pop().unwrap();
+/// Siblings on the path from the leaf whose node hashes as `leaf_node` up to
+/// the root, each with whether it is on the left, or `None` if the proof does
+/// not end with the leaf's own pair.
+pub(crate) fn list_proof_siblings<'a>(
+    proof_list: &'a [ProofListItem],
+    leaf_node: &Digest,
+) -> Option<Vec<(&'a Digest, bool)>> {
+    let is_left = |item: &ProofListItem| match item.order {
+        Some(NodeOrder::Left) => Some(true),
+        Some(NodeOrder::Right) => Some(false),
+        None => None,
+    };
 
-        match h2.order {
-            Some(NodeOrder::Left) => {
-                let combined = [&h2.hash[..], &h1.hash[..]].concat();
-                let hash = Sha256::digest(&combined).to_vec();
-                proof_list.push(ProofListItem::new(hash, None));
-            }
-            Some(NodeOrder::Right) => {
-                let combined = [&h1.hash[..], &h2.hash[..]].concat();
-                let hash = Sha256::digest(&combined).to_vec();
-                proof_list.push(ProofListItem::new(hash, None));
+    match proof_list {
+        [] => None,
+        [single] => (&single.hash == leaf_node).then(Vec::new),
+        [upper @ .., second, last] => {
+            let second_is_left = is_left(second)?;
+            let bottom = if &last.hash == leaf_node {
+                (&second.hash, second_is_left)
+            } else if &second.hash == leaf_node {
+                (&last.hash, !second_is_left)
+            } else {
+                return None;
+            };
+
+            let mut siblings = vec![bottom];
+            for item in upper.iter().rev() {
+                siblings.push((&item.hash, is_left(item)?));
             }
-            None => {
-                panic!("Should not be None");
+            Some(siblings)
+        }
+    }
+}
+
+/// Whether a proof's path, as (position, group size) pairs from the leaf up,
+/// is the path of some leaf in a tree with the head's leaf count and arity.
+/// Only a one-leaf tree has an empty path.
+fn fits_tree(path: &[(usize, usize)], tree_head: &TreeHead) -> bool {
+    usize::try_from(tree_head.leaf_count).is_ok_and(|leaf_count| {
+        merkle_tree::path_index(leaf_count, tree_head.arity as usize, path).is_some()
+    })
+}
+
+/// Verifies a proof made of sibling groups, as produced for trees of any arity.
+/// The hash at each group's position must be the one computed from the level below.
+pub fn verify_group_proof(
+    proof: &[ProofGroup],
+    merkle_root: &Digest,
+    arity: usize,
+    file_contents: &[u8],
+) -> bool {
+    let leaf_hash = Digest::of(file_contents);
+    verify_group_proof_for_leaf(proof, merkle_root, arity, leaf_hash)
+}
+
+pub fn verify_group_proof_for_leaf(
+    proof: &[ProofGroup],
+    merkle_root: &Digest,
+    arity: usize,
+    leaf_hash: Digest,
+) -> bool {
+    verify_keyed_group_proof(proof, merkle_root, arity, leaf_hash, None)
+}
+
+/// Like `verify_group_proof_for_leaf`, for trees whose nodes are hashed under
+/// `node_key`.
+fn verify_keyed_group_proof(
+    proof: &[ProofGroup],
+    merkle_root: &Digest,
+    arity: usize,
+    leaf_hash: Digest,
+    node_key: Option<&[u8]>,
+) -> bool {
+    let mut current = Digest::of_leaf(&leaf_hash);
+
+    for group in proof {
+        if group.hashes.len() < 2 || group.hashes.len() > arity {
+            return false;
+        }
+        if group.hashes.get(group.position) != Some(&current) {
+            return false;
+        }
+        current = Digest::of_keyed_children(node_key, &group.hashes);
+    }
+
+    *merkle_root == current
+}
+
+/// Verifies a position-binding proof, rebuilding each group from the claimed
+/// leaf index and tree size. Fails if the proof has siblings left over.
+pub fn verify_indexed_proof_for_leaf(
+    proof: &IndexedProof,
+    merkle_root: &Digest,
+    arity: usize,
+    leaf_hash: Digest,
+) -> bool {
+    if !MerkleTree::SUPPORTED_ARITIES.contains(&arity) || proof.leaf_index >= proof.tree_size {
+        return false;
+    }
+    let (Ok(tree_size), Ok(leaf_index)) = (
+        usize::try_from(proof.tree_size),
+        usize::try_from(proof.leaf_index),
+    ) else {
+        return false;
+    };
+
+    let mut siblings = proof.siblings.iter();
+    let mut current = Digest::of_leaf(&leaf_hash);
+    for (position, group_size) in merkle_tree::path_groups(tree_size, arity, leaf_index) {
+        let mut group: Vec<&Digest> = siblings.by_ref().take(group_size - 1).collect();
+        if group.len() != group_size - 1 {
+            return false;
+        }
+        group.insert(position, &current);
+        current = Digest::of_children(group);
+    }
+
+    siblings.next().is_none() && *merkle_root == current
+}
+
+/// Like `verify_indexed_proof_for_leaf`, against a stored tree head whose leaf
+/// count must match the proof's tree size.
+pub fn verify_indexed_proof_against_head(
+    proof: &IndexedProof,
+    tree_head: &TreeHead,
+    leaf_hash: Digest,
+) -> bool {
+    if tree_head.validate().is_err() {
+        return false;
+    }
+
+    proof.tree_size == tree_head.leaf_count
+        && verify_indexed_proof_for_leaf(
+            proof,
+            &tree_head.root_hash,
+            tree_head.arity as usize,
+            leaf_hash,
+        )
+}
+
+/// Checks that `leaf_hashes` are exactly the leaves from `proof.start` of the
+/// tree with root `merkle_root`, in order.
+pub fn verify_range_proof(
+    proof: &RangeProof,
+    merkle_root: &Digest,
+    arity: usize,
+    leaf_hashes: &[Digest],
+) -> bool {
+    if !MerkleTree::SUPPORTED_ARITIES.contains(&arity) {
+        return false;
+    }
+    let (Ok(tree_size), Ok(start)) = (
+        usize::try_from(proof.tree_size),
+        usize::try_from(proof.start),
+    ) else {
+        return false;
+    };
+
+    let mut siblings = proof.siblings.iter();
+    let span = leaf_hashes.iter().map(Digest::of_leaf).collect();
+    let root = merkle_tree::fold_range(tree_size, arity, start, span, |_, _| {
+        siblings.next().copied()
+    });
+    siblings.next().is_none() && root == Some(*merkle_root)
+}
+
+/// Like `verify_range_proof`, against a stored tree head whose leaf count
+/// must match the proof's tree size.
+pub fn verify_range_proof_against_head(
+    proof: &RangeProof,
+    tree_head: &TreeHead,
+    leaf_hashes: &[Digest],
+) -> bool {
+    if tree_head.validate().is_err() {
+        return false;
+    }
+
+    proof.tree_size == tree_head.leaf_count
+        && verify_range_proof(
+            proof,
+            &tree_head.root_hash,
+            tree_head.arity as usize,
+            leaf_hashes,
+        )
+}
+
+/// Prefix of listing leaves, so they cannot collide with file leaves.
+const LISTING_LEAF_PREFIX: &[u8] = b"merkle-rs:listing-leaf:v1";
+
+/// Leaf of a listing tree: the file's length-prefixed name, its size and its
+/// leaf hash in the file tree.
+pub fn listing_leaf_hash(entry: &ListingEntry) -> Digest {
+    let mut data = LISTING_LEAF_PREFIX.to_vec();
+    data.extend_from_slice(&(entry.filename.len() as u64).to_be_bytes());
+    data.extend_from_slice(entry.filename.as_bytes());
+    data.extend_from_slice(&entry.size.to_be_bytes());
+    data.extend_from_slice(entry.leaf_hash.as_bytes());
+    Digest::of(data)
+}
+
+/// Tree over the listing leaves of `entries`, which must be in the file
+/// tree's leaf order. A tree head records its root as `listing_root`, so that
+/// listing pages commit to names and sizes the file tree's leaves leave out.
+pub fn listing_tree<'a, I>(entries: I, arity: usize) -> MerkleTree
+where
+    I: IntoIterator<Item = &'a ListingEntry>,
+{
+    let leaves = entries
+        .into_iter()
+        .map(|entry| MerkleLeaf::from_hash(None, listing_leaf_hash(entry)))
+        .collect();
+    MerkleTree::from_leaves_with_arity(leaves, arity)
+}
+
+/// Checks that a listing page holds exactly the files from `offset` of the
+/// tree, with the names, sizes and leaf hashes the tree head's listing root
+/// commits to. An empty page is only accepted past the last leaf, so paging
+/// until `tree_head.leaf_count` lists every file. Heads without a listing
+/// root cannot bind a page's names, so every page is rejected against them.
+pub fn verify_listing_page(page: &ListingPage, tree_head: &TreeHead, offset: u64) -> bool {
+    let Some(listing_root) = &tree_head.listing_root else {
+        return false;
+    };
+    if tree_head.validate().is_err() {
+        return false;
+    }
+    match &page.proof {
+        None => page.entries.is_empty() && offset >= tree_head.leaf_count,
+        Some(proof) => {
+            let leaf_hashes: Vec<Digest> = page.entries.iter().map(listing_leaf_hash).collect();
+            proof.start == offset
+                && proof.tree_size == tree_head.leaf_count
+                && verify_range_proof(proof, listing_root, tree_head.arity as usize, &leaf_hashes)
+        }
+    }
+}
+
+/// Verifies a file returned by the server against the client's tree head,
+/// using the proof format matching the tree's arity and the leaf encoding the
+/// head records.
+///
+/// When the tree commits to metadata, the response must carry exactly the
+/// committed fields.
+pub fn verify_file_response(file_response: &FileResponse, tree_head: &TreeHead) -> bool {
+    verify_file_response_with_key(file_response, tree_head, None)
+}
+
+/// Like `verify_file_response`, with the secret that keyed trees' leaves and
+/// nodes were computed with. Keyed trees never verify without it.
+///
+/// Salted trees require the response to disclose the file's salt.
+pub fn verify_file_response_with_key(
+    file_response: &FileResponse,
+    tree_head: &TreeHead,
+    leaf_key: Option<&[u8]>,
+) -> bool {
+    let content_hash = if tree_head.chunking {
+        chunking::chunk_root(&chunking::chunk_hashes(&file_response.content))
+    } else {
+        Digest::of(&file_response.content)
+    };
+    verify_file_proof_for_content_hash(file_response, content_hash, tree_head, leaf_key)
+}
+
+/// Verifies the proof, metadata and salt of a file response for contents with
+/// the given hash (or chunk root), ignoring `content`. Lets a client check a
+/// streamed file's proof before its contents arrive.
+pub fn verify_file_proof_for_content_hash(
+    file_response: &FileResponse,
+    content_hash: Digest,
+    tree_head: &TreeHead,
+    leaf_key: Option<&[u8]>,
+) -> bool {
+    if tree_head.validate().is_err() {
+        return false;
+    }
+
+    let metadata = if tree_head.leaf_metadata.is_empty() {
+        None
+    } else {
+        match &file_response.metadata {
+            Some(metadata) if metadata.fields() == tree_head.leaf_metadata => Some(metadata),
+            _ => return false,
+        }
+    };
+    let mut leaf_hash = leaf_hash_from_content_hash(content_hash, metadata);
+    if tree_head.salted {
+        match &file_response.salt {
+            Some(salt) => leaf_hash = salted_leaf_hash(salt, &leaf_hash),
+            None => return false,
+        }
+    }
+    let mut tree_node_key = None;
+    if tree_head.keyed {
+        match leaf_key {
+            Some(key) => {
+                leaf_hash = keyed_leaf_hash(key, &leaf_hash);
+                tree_node_key = Some(node_key(key));
             }
+            None => return false,
         }
     }
+    let tree_node_key = tree_node_key.as_deref();
+
+    if tree_head.arity == 2 && !file_response.merkle_proof.is_empty() {
+        return verify_merkle_proof_for_head(
+            &file_response.merkle_proof,
+            tree_head,
+            leaf_hash,
+            tree_node_key,
+        );
+    }
 
-    markle_root == proof_list.pop().unwrap().hash
+    match &file_response.group_proof {
+        Some(group_proof) => {
+            let path: Vec<(usize, usize)> = group_proof
+                .iter()
+                .map(|group| (group.position, group.hashes.len()))
+                .collect();
+            fits_tree(&path, tree_head)
+                && verify_keyed_group_proof(
+                    group_proof,
+                    &tree_head.root_hash,
+                    tree_head.arity as usize,
+                    leaf_hash,
+                    tree_node_key,
+                )
+        }
+        None => false,
+    }
+}
+
+/// Parses a tree arity given on the command line.
+pub fn parse_arity(arity: &str) -> Result<usize, String> {
+    let arity: usize = arity
+        .parse()
+        .map_err(|_| format!("`{}` is not a number", arity))?;
+    if MerkleTree::SUPPORTED_ARITIES.contains(&arity) {
+        Ok(arity)
+    } else {
+        Err(format!(
+            "arity must be one of {:?}",
+            MerkleTree::SUPPORTED_ARITIES
+        ))
+    }
+}
+
+/// Parses a comma separated list of metadata fields (`size`, `mode`, `mtime`).
+pub fn parse_metadata_fields(fields: &str) -> Result<MetadataFields, String> {
+    let mut selected = MetadataFields::default();
+    for field in fields.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        match field {
+            "size" => selected.size = true,
+            "mode" => selected.mode = true,
+            "mtime" => selected.mtime = true,
+            _ => return Err(format!("unknown metadata field `{}`", field)),
+        }
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::digest::Digest;
+    use crate::utils::parse_checksum_manifest;
+
+    #[test]
+    fn should_parse_gnu_and_bsd_checksum_manifests() {
+        let a = Digest::of(b"a contents");
+        let b = Digest::of(b"b contents");
+        let c = Digest::of(b"c contents");
+        let manifest = format!(
+            "# exported dataset\n{}  data/b.csv\n{} *a.bin\r\n\nSHA256 (c (1).txt) = {}\n",
+            b, a, c
+        );
+
+        let hashes = parse_checksum_manifest(&manifest).unwrap();
+
+        let names: Vec<&String> = hashes.keys().collect();
+        assert_eq!(names, ["a.bin", "c (1).txt", "data/b.csv"]);
+        assert_eq!(hashes["a.bin"], a);
+        assert_eq!(hashes["c (1).txt"], c);
+        assert_eq!(
+            parse_checksum_manifest(&format!("\\{}  new\\nline", a)).unwrap()["new\nline"],
+            a
+        );
+    }
+
+    #[test]
+    fn should_reject_malformed_checksum_manifests() {
+        let a = Digest::of(b"a contents");
+        let b = Digest::of(b"b contents");
+
+        assert!(parse_checksum_manifest("e3b0c442  short.txt").is_err());
+        assert!(parse_checksum_manifest(&format!("{}", a)).is_err());
+        assert!(parse_checksum_manifest(&format!("{}  a\n{}  a", a, b)).is_err());
+        assert!(parse_checksum_manifest(&format!("{}  a\n{}  a", a, a)).is_ok());
+    }
 }
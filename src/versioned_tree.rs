@@ -0,0 +1,250 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::digest::Digest;
+use crate::merkle_tree::{MerkleTree, ProofGroup, ProofListItem};
+
+/// Versions of a collection's tree, so proofs can still be served against
+/// roots that clients stored before later updates.
+///
+/// Leaf updates copy only the path from the changed leaf to the root, the rest
+/// of each version shares its nodes and keys with the version before it.
+/// Inserted leaves rehash the groups the insertion shifts and copy the keys.
+///
+/// Versions are numbered from 0 in commit order. With `max_versions`, the
+/// oldest versions are dropped once more are kept, and their roots are no
+/// longer found.
+#[derive(Clone, Debug)]
+pub struct VersionedTree {
+    versions: VecDeque<MerkleTree>,
+    /// Number of the oldest version kept.
+    first_version: usize,
+    max_versions: Option<usize>,
+    by_root: HashMap<Digest, usize>,
+}
+
+impl VersionedTree {
+    pub fn new(merkle_tree: MerkleTree) -> Self {
+        VersionedTree::with_max_versions(merkle_tree, None)
+    }
+
+    /// Keeps at most `max_versions` versions, at least the latest one.
+    pub fn with_max_versions(merkle_tree: MerkleTree, max_versions: Option<usize>) -> Self {
+        let mut versioned = VersionedTree {
+            versions: VecDeque::new(),
+            first_version: 0,
+            max_versions: max_versions.map(|max_versions| max_versions.max(1)),
+            by_root: HashMap::new(),
+        };
+        versioned.commit(merkle_tree);
+        versioned
+    }
+
+    /// Adds `merkle_tree` as the latest version and returns its version number.
+    pub fn commit(&mut self, merkle_tree: MerkleTree) -> usize {
+        let version = self.first_version + self.versions.len();
+        self.by_root
+            .entry(merkle_tree.get_root_hash())
+            .or_insert(version);
+        self.versions.push_back(merkle_tree);
+        while self.versions.len() > self.max_versions.unwrap_or(usize::MAX) {
+            self.drop_oldest();
+        }
+        version
+    }
+
+    /// Drops the oldest version, pointing its root at the next version with
+    /// the same root, if any is kept.
+    fn drop_oldest(&mut self) {
+        let dropped = match self.versions.pop_front() {
+            Some(dropped) => dropped.get_root_hash(),
+            None => return,
+        };
+        let version = self.first_version;
+        self.first_version += 1;
+        if self.by_root.get(&dropped) != Some(&version) {
+            return;
+        }
+        let first_version = self.first_version;
+        match self
+            .versions
+            .iter()
+            .position(|merkle_tree| merkle_tree.get_root_hash() == dropped)
+        {
+            Some(position) => self.by_root.insert(dropped, first_version + position),
+            None => self.by_root.remove(&dropped),
+        };
+    }
+
+    /// Sets the hash of the leaf stored under `key` in a new version.
+    pub fn update_leaf(&mut self, key: &str, hash: Digest) -> Option<usize> {
+        let merkle_tree = self.latest().with_leaf_hash_for_key(key, hash)?;
+        Some(self.commit(merkle_tree))
+    }
+
+    /// Sets the hash of the leaf at `index` in a new version.
+    pub fn update_leaf_at(&mut self, index: usize, hash: Digest) -> Option<usize> {
+        let merkle_tree = self.latest().with_leaf_hash(index, hash)?;
+        Some(self.commit(merkle_tree))
+    }
+
+    /// Inserts a leaf at `index` in a new version, see
+    /// `MerkleTree::with_inserted_leaf`.
+    pub fn insert_leaf(
+        &mut self,
+        index: usize,
+        key: Option<String>,
+        hash: Digest,
+    ) -> Option<usize> {
+        let merkle_tree = self.latest().with_inserted_leaf(index, key, hash)?;
+        Some(self.commit(merkle_tree))
+    }
+
+    pub fn latest(&self) -> &MerkleTree {
+        self.versions
+            .back()
+            .expect("A versioned tree has a version")
+    }
+
+    pub fn version(&self, version: usize) -> Option<&MerkleTree> {
+        self.versions.get(version.checked_sub(self.first_version)?)
+    }
+
+    /// Number of versions kept.
+    pub fn version_count(&self) -> usize {
+        self.versions.len()
+    }
+
+    /// First kept version whose root is `root`.
+    pub fn version_of_root(&self, root: &Digest) -> Option<usize> {
+        self.by_root.get(root).copied()
+    }
+
+    pub fn tree_for_root(&self, root: &Digest) -> Option<&MerkleTree> {
+        self.version(self.version_of_root(root)?)
+    }
+
+    /// Proves the leaf stored under `key` against a past or current `root`.
+    pub fn generate_merkle_proof(&self, root: &Digest, key: &str) -> Option<Vec<ProofListItem>> {
+        self.tree_for_root(root)?.generate_merkle_proof(key)
+    }
+
+    pub fn generate_group_proof(&self, root: &Digest, key: &str) -> Option<Vec<ProofGroup>> {
+        self.tree_for_root(root)?.generate_group_proof(key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{digest::Digest, merkle_tree::MerkleTree, utils, versioned_tree::VersionedTree};
+    use std::sync::Arc;
+
+    fn files(version: usize) -> Vec<(String, String)> {
+        (0..6)
+            .map(|i| {
+                let content = if i == 2 && version > 0 {
+                    format!("file {} v{}", i, version)
+                } else {
+                    format!("file {}", i)
+                };
+                (format!("file{}.txt", i), content)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_serve_proofs_against_every_version() {
+        let mut versioned = VersionedTree::new(MerkleTree::new(files(0)));
+        let first_root = versioned.latest().get_root_hash();
+
+        for version in 1..=2 {
+            let content = &files(version)[2].1;
+            let updated = versioned.update_leaf("file2.txt", Digest::of(content));
+            assert_eq!(updated, Some(version));
+            assert_eq!(
+                versioned.latest().get_root_hash(),
+                MerkleTree::new(files(version)).get_root_hash()
+            );
+        }
+
+        assert_eq!(versioned.version_count(), 3);
+        assert_eq!(versioned.version_of_root(&first_root), Some(0));
+        let proof = versioned
+            .generate_merkle_proof(&first_root, "file2.txt")
+            .unwrap();
+        assert!(utils::verify_merkle_proof(
+            proof,
+            first_root,
+            b"file 2".to_vec()
+        ));
+        assert!(versioned
+            .generate_group_proof(&Digest::of(b"unknown root"), "file2.txt")
+            .is_none());
+        assert!(versioned.update_leaf("missing.txt", first_root).is_none());
+    }
+
+    #[test]
+    fn should_share_unchanged_subtrees_between_versions() {
+        let mut versioned = VersionedTree::new(MerkleTree::new(files(0)));
+        versioned.update_leaf_at(5, Digest::of(b"new contents"));
+
+        let (before, after) = (versioned.version(0).unwrap(), versioned.latest());
+        let shared = before
+            .root
+            .children
+            .iter()
+            .zip(&after.root.children)
+            .filter(|(old, new)| Arc::ptr_eq(old, new))
+            .count();
+
+        assert_eq!(shared, before.root.children.len() - 1);
+        assert_ne!(before.get_root_hash(), after.get_root_hash());
+    }
+
+    #[test]
+    fn should_drop_the_oldest_versions() {
+        let tree = MerkleTree::new(files(0));
+        let first_root = tree.get_root_hash();
+        let mut versioned = VersionedTree::with_max_versions(tree, Some(2));
+
+        let v1 = Digest::of(&files(1)[2].1);
+        assert_eq!(versioned.update_leaf("file2.txt", v1), Some(1));
+        assert_eq!(versioned.version_of_root(&first_root), Some(0));
+        // Back to the first contents, so version 2 has the first root again
+        assert_eq!(
+            versioned.update_leaf("file2.txt", Digest::of(b"file 2")),
+            Some(2)
+        );
+        assert_eq!(versioned.version_count(), 2);
+        assert!(versioned.version(0).is_none());
+        assert_eq!(versioned.version_of_root(&first_root), Some(2));
+
+        let second_root = versioned.version(1).unwrap().get_root_hash();
+        versioned.update_leaf("file2.txt", Digest::of(b"other"));
+        assert!(versioned.tree_for_root(&second_root).is_none());
+        assert!(versioned.tree_for_root(&first_root).is_some());
+    }
+
+    #[test]
+    fn should_prove_against_roots_before_an_inserted_leaf() {
+        let mut versioned = VersionedTree::new(MerkleTree::new(files(0)));
+        let first_root = versioned.latest().get_root_hash();
+
+        assert_eq!(
+            versioned.insert_leaf(0, Some("added.txt".to_owned()), Digest::of(b"added")),
+            Some(1)
+        );
+        assert_eq!(versioned.latest().leaf_count(), 7);
+        assert_ne!(versioned.latest().get_root_hash(), first_root);
+        assert!(versioned
+            .generate_merkle_proof(&first_root, "added.txt")
+            .is_none());
+        let proof = versioned
+            .generate_merkle_proof(&first_root, "file5.txt")
+            .unwrap();
+        assert!(utils::verify_merkle_proof(
+            proof,
+            first_root,
+            b"file 5".to_vec()
+        ));
+    }
+}